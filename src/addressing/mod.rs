@@ -0,0 +1,102 @@
+use crate::db;
+use crate::error::{self, Error};
+use serde::{Deserialize, Serialize};
+use mongodb::bson::doc;
+
+const QUIET_ROOMS_KEY : &str = "quiet_rooms";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct QuietRooms {
+    quiet_rooms: Vec<String>,
+}
+
+// How the bot recognizes messages meant for it
+#[derive(Clone, Debug)]
+pub struct Addressing {
+    prefix: Option<String>,
+    names: Vec<String>,
+}
+
+impl Addressing {
+    // The names are what the bot may be addressed with: the full user ID,
+    // the localpart and the display name
+    pub fn new(prefix: Option<String>, names: Vec<String>) -> Addressing {
+        let prefix = prefix.filter(|p| p.trim() != "").map(|p| p.trim().to_string());
+        let mut lowered : Vec<String> = vec![];
+        for n in names {
+            let n = n.trim().to_lowercase();
+            if n != "" && !lowered.contains(&n) {
+                lowered.push(n);
+            }
+        }
+        // Longest first, so "@bot:example.org" wins over "bot"
+        lowered.sort_by(|a, b| b.len().cmp(&a.len()));
+        return Addressing{prefix: prefix, names: lowered};
+    }
+
+    // Returns the command if the message is addressed to the bot. Without a
+    // configured prefix every message is a command, like it always was; with
+    // one, messages need the prefix or a mention unless the room is a DM.
+    pub fn extract_command(&self, body: &str, is_dm: bool) -> Option<String> {
+        let body = body.trim();
+        let prefix = match &self.prefix {
+            Some(p) => p,
+            None => return Some(body.to_string()),
+        };
+        if body.starts_with(prefix.as_str()) {
+            return Some(body[prefix.len()..].trim_start().to_string());
+        }
+        let lower = body.to_lowercase();
+        for n in self.names.iter() {
+            if !lower.starts_with(n.as_str()) || !body.is_char_boundary(n.len()) {
+                continue;
+            }
+            let rest = &body[n.len()..];
+            let rest = rest.strip_prefix(':').or(rest.strip_prefix(',')).unwrap_or(rest);
+            // "bot list" is a mention, "botany" is not
+            if rest == "" || rest.starts_with(char::is_whitespace) {
+                let rest = rest.trim_start();
+                return Some(rest.strip_prefix(prefix.as_str()).unwrap_or(rest).trim_start().to_string());
+            }
+        }
+        if is_dm {
+            return Some(body.to_string());
+        }
+        return None;
+    }
+}
+
+async fn get_quiet_rooms(db: Box<db::Homechatbotdb>) -> Result<Vec<String>, Error> {
+    let items = db.get_generic_data_collection::<QuietRooms>(db::CONFIG_COLLECTION_NAME, doc!{QUIET_ROOMS_KEY: {"$exists": true}}, doc!{}).await?;
+    let mut rooms : Vec<String> = vec![];
+    for i in items {
+        rooms.extend(i.quiet_rooms);
+    }
+    return Ok(rooms);
+}
+
+pub async fn is_quiet_room(room: &str, db: Box<db::Homechatbotdb>) -> Result<bool, Error> {
+    return Ok(get_quiet_rooms(db).await?.iter().any(|r| r == room));
+}
+
+pub async fn set_quiet_room(room: &str, quiet: bool, db: Box<db::Homechatbotdb>) -> Result<(), Error> {
+    let mut rooms = get_quiet_rooms(db.clone()).await?;
+    rooms.retain(|r| r != room);
+    if quiet {
+        rooms.push(room.to_string());
+    }
+    db.remove_data(db::CONFIG_COLLECTION_NAME, doc!{QUIET_ROOMS_KEY: {"$exists": true}}).await?;
+    return db.insert_data_to_collection(db::CONFIG_COLLECTION_NAME, vec![doc!{QUIET_ROOMS_KEY: rooms}]).await;
+}
+
+pub async fn handle_quiet_command(cmd: &str, room: &str, db: Box<db::Homechatbotdb>) -> String {
+    let quiet = match cmd.trim().to_lowercase().as_str() {
+        "on" => true,
+        "off" => false,
+        _ => return String::from("Usage: quiet on|off"),
+    };
+    match set_quiet_room(room, quiet, db).await {
+        Ok(_) => return String::from(if quiet { "Quiet mode enabled for this room" } else { "Quiet mode disabled for this room" }),
+        Err(e) => return error::report(&e),
+    };
+}
@@ -0,0 +1,42 @@
+use crate::backup;
+use crate::db;
+use crate::error::{self, Error};
+use crate::reply::Reply;
+use crate::webhook;
+use serde::{Deserialize, Serialize};
+use mongodb::bson::doc;
+
+const ADMIN_HELP : &str = "Admin allowed commands:
+    webhook list
+    webhook add {name} [{room_id}]
+    webhook rem {name}
+    backup (sent in a direct chat with the bot, or written to the backup directory)
+    restore [file] (without a file, the next file you send is restored)";
+
+// {admin_users: [...]} in the config collection. Being allowed to invite
+// the bot is not enough to administer it.
+#[derive(Debug, Serialize, Deserialize)]
+struct AdminUsers {
+    admin_users: Vec<String>,
+}
+
+pub async fn is_admin(user: &str, db: Box<db::Homechatbotdb>) -> Result<bool, Error> {
+    let items = db.get_generic_data_collection::<AdminUsers>(db::CONFIG_COLLECTION_NAME, doc!{"admin_users": {"$exists": true}}, doc!{}).await?;
+    return Ok(items.iter().any(|a| a.admin_users.iter().any(|u| u == user)));
+}
+
+pub async fn handle_admin_command(cmd: String, sender: &str, room: &str, is_dm: bool, db: Box<db::Homechatbotdb>) -> Reply {
+    match is_admin(sender, db.clone()).await {
+        Ok(true) => {},
+        Ok(false) => return String::from("Admin commands are only for the users listed in \"admin_users\"").into(),
+        Err(e) => return error::report(&e).into(),
+    };
+    let cmd = cmd.trim();
+    let (first, rest) = cmd.split_once(char::is_whitespace).unwrap_or((cmd, ""));
+    match first.to_lowercase().as_str() {
+        "webhook" => return webhook::handle_webhook_command(rest, room, is_dm, db).await.into(),
+        "backup" => return backup::handle_backup_request(is_dm, db).await,
+        "restore" => return backup::handle_restore_request(rest, sender, db).await.into(),
+        _ => return String::from(ADMIN_HELP).into(),
+    };
+}
@@ -0,0 +1,325 @@
+use crate::db;
+use crate::error::{self, Error};
+use crate::migrate;
+use crate::reply::Reply;
+use chrono::{Local, NaiveDate, NaiveTime, Utc};
+use mongodb::bson::{doc, Bson, Document};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+use std::path::Path;
+use tracing::{error, info, warn};
+
+const ARCHIVE_FORMAT : &str = "home-chatbot-backup";
+// Raised when the layout of the archive itself changes, not for schema
+// migrations; those are handled by restoring and then migrating
+const ARCHIVE_VERSION : u32 = 1;
+const FILE_PREFIX : &str = "home-chatbot-backup-";
+const PENDING_RESTORE_COLLECTION_NAME : &str = "backup_pending_restores";
+// How long to wait for the archive after "admin restore" was sent
+const PENDING_RESTORE_TIMEOUT_SECS : i64 = 600;
+pub const COLLECTIONS : &[migrate::Collection] = &[
+    migrate::Collection{name: PENDING_RESTORE_COLLECTION_NAME, index: None},
+];
+
+// {backup: {dir, at, keep}} in the config collection. With dir, "admin
+// backup" writes the archive there instead of uploading it. With at
+// ("HH:MM", local time) an archive is written to dir every night, and only
+// the newest keep archives are kept.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Settings {
+    pub dir: Option<String>,
+    pub at: Option<String>,
+    pub keep: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupSettings {
+    backup: Settings,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingRestore {
+    sender: String,
+    requested: i64,
+}
+
+pub async fn get_settings(db: Box<db::Homechatbotdb>) -> Result<Option<Settings>, Error> {
+    let items = db.get_generic_data_collection::<BackupSettings>(db::CONFIG_COLLECTION_NAME, doc!{"backup": {"$exists": true}}, doc!{}).await?;
+    return Ok(items.into_iter().next().map(|b| b.backup));
+}
+
+// Everything the bot stores, as relaxed extended JSON so that the types
+// MongoDB knows but JSON does not (ObjectIds, dates) survive a restore
+pub async fn create_archive(db: Box<db::Homechatbotdb>) -> Result<Vec<u8>, Error> {
+    let names = migrate::collection_names().into_iter().filter(|n| *n != PENDING_RESTORE_COLLECTION_NAME);
+    let mut collections = serde_json::Map::new();
+    for name in names {
+        let docs = db.get_generic_data_collection::<Document>(name, doc!{}, doc!{}).await?;
+        let docs : Vec<Value> = docs.into_iter().map(|d| Bson::Document(d).into_relaxed_extjson()).collect();
+        collections.insert(name.to_string(), Value::Array(docs));
+    }
+    let archive = json!({
+        "format": ARCHIVE_FORMAT,
+        "version": ARCHIVE_VERSION,
+        "schema_version": migrate::get_schema_version(db).await?,
+        "created": Utc::now().to_rfc3339(),
+        "collections": collections,
+    });
+    match serde_json::to_vec_pretty(&archive) {
+        Ok(d) => return Ok(d),
+        Err(e) => return Err(Error::Internal(format!("Unable to create JSON: {}", e))),
+    };
+}
+
+fn archive_name() -> String {
+    return format!("{}{}.json", FILE_PREFIX, Local::now().format("%Y-%m-%d-%H%M"));
+}
+
+// Writes an archive into dir, returning its path
+fn write_file(dir: &str, name: &str, data: &[u8]) -> Result<String, Error> {
+    let path = Path::new(dir).join(name);
+    match std::fs::write(&path, data) {
+        Ok(_) => return Ok(path.display().to_string()),
+        Err(e) => return Err(Error::Internal(format!("Unable to write {}: {}", path.display(), e))),
+    };
+}
+
+// Reads and checks a whole archive before anything in the database changes
+fn parse_archive(data: &[u8]) -> Result<(String, Vec<(String, Vec<Document>)>), Error> {
+    let archive : Value = match serde_json::from_slice(data) {
+        Ok(a) => a,
+        Err(e) => return Err(Error::InvalidInput(format!("The file is not valid JSON: {}", e))),
+    };
+    if archive.get("format").and_then(|f| f.as_str()) != Some(ARCHIVE_FORMAT) {
+        return Err(Error::InvalidInput("The file is not a backup of this bot".to_string()));
+    }
+    match archive.get("version").and_then(|v| v.as_u64()) {
+        Some(v) if v <= ARCHIVE_VERSION as u64 => {},
+        _ => return Err(Error::InvalidInput("The backup was made by a newer version of the bot".to_string())),
+    };
+    let schema = archive.get("schema_version").and_then(|v| v.as_u64()).unwrap_or(0);
+    if schema > migrate::latest_version() as u64 {
+        return Err(Error::InvalidInput(format!("The backup has schema version {}, newer than this bot knows ({})", schema, migrate::latest_version())));
+    }
+    let created = archive.get("created").and_then(|c| c.as_str()).unwrap_or("an unknown date").to_string();
+    let collections = match archive.get("collections").and_then(|c| c.as_object()) {
+        Some(c) => c,
+        None => return Err(Error::InvalidInput("The backup has no collections".to_string())),
+    };
+    let known = migrate::collection_names();
+    let mut parsed = vec![];
+    for (name, docs) in collections {
+        if !known.contains(&name.as_str()) {
+            warn!(collection = %name, "Skipping unknown collection in backup");
+            continue;
+        }
+        let docs = match docs.as_array() {
+            Some(d) => d,
+            None => return Err(Error::InvalidInput(format!("The collection {} is not a list", name))),
+        };
+        let mut out = vec![];
+        for d in docs {
+            match Bson::try_from(d.clone()) {
+                Ok(Bson::Document(d)) => out.push(d),
+                Ok(_) => return Err(Error::InvalidInput(format!("The collection {} holds something which is not a document", name))),
+                Err(e) => return Err(Error::InvalidInput(format!("Invalid document in {}: {}", name, e))),
+            };
+        }
+        parsed.push((name.clone(), out));
+    }
+    return Ok((created, parsed));
+}
+
+async fn replace_collection(name: &str, docs: &[Document], db: Box<db::Homechatbotdb>) -> Result<(), Error> {
+    if !db.check_collection_exists(name).await? {
+        db.create_collection(name).await?;
+    }
+    db.remove_data(name, doc!{}).await?;
+    if docs.len() > 0 {
+        db.insert_data_to_collection(name, docs.to_vec()).await?;
+    }
+    return Ok(());
+}
+
+// Replaces the collections in the archive with its documents, then brings
+// the restored data up to the current schema. Collections the archive does
+// not have are left alone. The current data is archived first, into the
+// backup directory if there is one, and put back if a collection can not be
+// restored. Returns what to tell the admin; an error means nothing changed.
+pub async fn restore_archive(data: &[u8], db: Box<db::Homechatbotdb>) -> Result<String, Error> {
+    let (created, collections) = parse_archive(data)?;
+    let safety_data = create_archive(db.clone()).await?;
+    let (_, safety) = parse_archive(&safety_data)?;
+    let mut safety_path = None;
+    if let Some(dir) = get_settings(db.clone()).await?.and_then(|s| s.dir) {
+        let name = format!("{}{}-before-restore.json", FILE_PREFIX, Local::now().format("%Y-%m-%d-%H%M"));
+        safety_path = Some(write_file(dir.as_str(), name.as_str(), &safety_data)?);
+    }
+    let mut count = 0;
+    for (i, (name, docs)) in collections.iter().enumerate() {
+        let e = match replace_collection(name.as_str(), docs, db.clone()).await {
+            Ok(_) => {
+                count += docs.len();
+                continue;
+            },
+            Err(e) => e,
+        };
+        error!(collection = %name, "Unable to restore: {}", e);
+        let mut lost = vec![];
+        for (name, _) in collections.iter().take(i + 1) {
+            let docs = safety.iter().find(|(n, _)| n == name).map_or(vec![], |(_, d)| d.clone());
+            if let Err(e) = replace_collection(name.as_str(), &docs, db.clone()).await {
+                error!(collection = %name, "Unable to put back the data from before the restore: {}", e);
+                lost.push(name.clone());
+            }
+        }
+        let mut msg = format!("Restoring {} failed: {}\nEverything else was left as it was", name, e.user_message());
+        if lost.len() > 0 {
+            msg = format!("Restoring {} failed: {}\nThese collections could not be put back: {}", name, e.user_message(), lost.join(", "));
+            if let Some(p) = &safety_path {
+                msg = format!("{}\nThe data from before the restore is in {}", msg, p);
+            }
+        }
+        return Ok(msg);
+    }
+    let steps = migrate::run(db, false).await?;
+    for s in &steps {
+        info!("Schema: {}", s);
+    }
+    info!(collections = collections.len(), documents = count, "Backup restored");
+    return Ok(format!("Restored {} documents in {} collections from the backup of {}", count, collections.len(), created));
+}
+
+// The archive holds the whole config collection with every token and
+// password in it, so it is only ever uploaded into a direct chat
+pub async fn handle_backup_request(is_dm: bool, db: Box<db::Homechatbotdb>) -> Reply {
+    let settings = match get_settings(db.clone()).await {
+        Ok(s) => s,
+        Err(e) => return error::report(&e).into(),
+    };
+    if let Some(dir) = settings.and_then(|s| s.dir) {
+        let data = match create_archive(db).await {
+            Ok(d) => d,
+            Err(e) => return error::report(&e).into(),
+        };
+        match write_file(dir.as_str(), archive_name().as_str(), &data) {
+            Ok(p) => return format!("Backup written to {}", p).into(),
+            Err(e) => return error::report(&e).into(),
+        };
+    }
+    if !is_dm {
+        return String::from("The backup holds every password and token the bot knows. Send \"admin backup\" in a direct chat with the bot, or set {backup: {dir}} in the config collection to have it written to a directory.").into();
+    }
+    match create_archive(db).await {
+        Ok(d) => return Reply::File{name: archive_name(), content_type: mime::APPLICATION_JSON, data: d},
+        Err(e) => return error::report(&e).into(),
+    };
+}
+
+// Without a file name the next file the sender uploads is restored,
+// otherwise the archive of that name in the backup directory
+pub async fn handle_restore_request(file: &str, sender: &str, db: Box<db::Homechatbotdb>) -> String {
+    let file = file.trim();
+    if file == "" {
+        match db.remove_data(PENDING_RESTORE_COLLECTION_NAME, doc!{"sender": sender}).await {
+            Ok(_) => {},
+            Err(e) => return error::report(&e),
+        };
+        match db.insert_data_to_collection(PENDING_RESTORE_COLLECTION_NAME, vec![doc!{"sender": sender, "requested": Utc::now().timestamp()}]).await {
+            Ok(_) => return "Send the backup file to restore. Everything the bot stores is replaced by it!".to_string(),
+            Err(e) => return error::report(&e),
+        };
+    }
+    if file.contains('/') || file.contains('\\') || file.starts_with('.') {
+        return "Only the name of a file in the backup directory is allowed".to_string();
+    }
+    let dir = match get_settings(db.clone()).await {
+        Ok(Some(Settings{dir: Some(d), ..})) => d,
+        Ok(_) => return "No backup directory is configured, send \"admin restore\" and then the file".to_string(),
+        Err(e) => return error::report(&e),
+    };
+    let data = match std::fs::read(Path::new(dir.as_str()).join(file)) {
+        Ok(d) => d,
+        Err(e) => return format!("Unable to read {}: {}", file, e),
+    };
+    return handle_restore_file(data, db).await;
+}
+
+// Returns true if the sender asked to restore a file and is still within
+// the time window; the request is consumed either way
+pub async fn take_pending_restore(sender: &str, db: Box<db::Homechatbotdb>) -> Result<bool, Error> {
+    let pending = db.get_generic_data_collection::<PendingRestore>(PENDING_RESTORE_COLLECTION_NAME, doc!{"sender": sender}, doc!{}).await?;
+    if pending.len() == 0 {
+        return Ok(false);
+    }
+    db.remove_data(PENDING_RESTORE_COLLECTION_NAME, doc!{"sender": sender}).await?;
+    let now = Utc::now().timestamp();
+    return Ok(pending.iter().any(|p| now - p.requested <= PENDING_RESTORE_TIMEOUT_SECS));
+}
+
+pub async fn handle_restore_file(data: Vec<u8>, db: Box<db::Homechatbotdb>) -> String {
+    match restore_archive(&data, db).await {
+        Ok(msg) => return msg,
+        Err(e) => return error::report(&e),
+    };
+}
+
+// Deletes all but the newest keep archives in dir
+fn prune(dir: &str, keep: usize) -> Result<(), Error> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => return Err(Error::Internal(format!("Unable to list {}: {}", dir, e))),
+    };
+    let mut names : Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .filter(|n| n.starts_with(FILE_PREFIX) && n.ends_with(".json"))
+        .collect();
+    // The date in the name sorts oldest first
+    names.sort();
+    let remove = names.len().saturating_sub(keep);
+    for n in names.iter().take(remove) {
+        match std::fs::remove_file(Path::new(dir).join(n)) {
+            Ok(_) => info!(file = %n, "Removed old backup"),
+            Err(e) => warn!(file = %n, "Unable to remove old backup: {}", e),
+        };
+    }
+    return Ok(());
+}
+
+// Writes the nightly archive once the configured time has passed on a day
+// without one yet, last being the day of the previous one. Returns the path
+// written, if any.
+pub async fn run_scheduled(last: &mut Option<NaiveDate>, db: Box<db::Homechatbotdb>) -> Result<Option<String>, Error> {
+    let settings = match get_settings(db.clone()).await? {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    let at = match &settings.at {
+        Some(a) => match NaiveTime::parse_from_str(a.as_str(), "%H:%M") {
+            Ok(t) => t,
+            Err(e) => return Err(Error::InvalidInput(format!("Invalid backup time {}: {}", a, e))),
+        },
+        None => return Ok(None),
+    };
+    let now = Local::now().naive_local();
+    // Started after the time today; restarts must not each make a backup
+    if last.is_none() {
+        *last = Some(if now.time() >= at { now.date() } else { now.date().pred() });
+    }
+    if now.time() < at || *last == Some(now.date()) {
+        return Ok(None);
+    }
+    *last = Some(now.date());
+    let dir = match settings.dir {
+        Some(d) => d,
+        None => return Err(Error::InvalidInput("The nightly backup needs a backup directory".to_string())),
+    };
+    let data = create_archive(db).await?;
+    let path = write_file(dir.as_str(), archive_name().as_str(), &data)?;
+    if let Some(k) = settings.keep.filter(|k| *k > 0) {
+        prune(dir.as_str(), k as usize)?;
+    }
+    return Ok(Some(path));
+}
@@ -0,0 +1,210 @@
+use crate::admin;
+use crate::bgchan::{lineup, schedule, HttpBackend, TvBackend};
+use crate::db;
+use crate::error::{self, Error};
+use serde::{Deserialize, Serialize};
+use mongodb::bson::{doc, to_document};
+use tracing::info;
+
+// Name of the TV configured before devices had names
+pub const DEFAULT_TV : &str = "default";
+const ROOM_DEVICES_KEY : &str = "tv_room_devices";
+pub const DEVICE_HELP : &str = "TV device commands (for the users in \"admin_users\"):
+    device add {name} {url} [{token}] (a token only in a direct chat with the bot)
+    device rem {name}
+    device default {name}
+Anybody can list them with \"devices\"";
+
+// One {tv_device, tv_url, tv_token} document in the config collection. A
+// document without tv_device is the TV configured before devices had names.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TvDevice {
+    pub tv_device: Option<String>,
+    pub tv_url: String,
+    pub tv_token: Option<String>,
+}
+
+impl TvDevice {
+    pub fn name(&self) -> String {
+        return self.tv_device.clone().unwrap_or(DEFAULT_TV.to_string());
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct RoomDevice {
+    room: String,
+    device: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RoomDevices {
+    tv_room_devices: Vec<RoomDevice>,
+}
+
+pub async fn get_devices(db: Box<db::Homechatbotdb>) -> Result<Vec<TvDevice>, Error> {
+    let mut items = db.get_generic_data_collection::<TvDevice>(db::CONFIG_COLLECTION_NAME, doc!{"tv_url": {"$exists": true}}, doc!{}).await?;
+    items.sort_by(|a, b| a.name().cmp(&b.name()));
+    return Ok(items);
+}
+
+async fn get_room_devices(db: Box<db::Homechatbotdb>) -> Result<Vec<RoomDevice>, Error> {
+    let items = db.get_generic_data_collection::<RoomDevices>(db::CONFIG_COLLECTION_NAME, doc!{ROOM_DEVICES_KEY: {"$exists": true}}, doc!{}).await?;
+    return Ok(items.into_iter().flat_map(|i| i.tv_room_devices).collect());
+}
+
+async fn set_room_device(room: &str, device: &str, db: Box<db::Homechatbotdb>) -> Result<(), Error> {
+    let mut rooms = get_room_devices(db.clone()).await?;
+    rooms.retain(|r| r.room != room);
+    rooms.push(RoomDevice{room: room.to_string(), device: device.to_string()});
+    let mut docs = vec![];
+    for r in rooms.iter() {
+        match to_document(r) {
+            Ok(d) => docs.push(d),
+            Err(e) => return Err(Error::Internal(format!("Unable to serialize room device: {}", e))),
+        };
+    }
+    db.remove_data(db::CONFIG_COLLECTION_NAME, doc!{ROOM_DEVICES_KEY: {"$exists": true}}).await?;
+    return db.insert_data_to_collection(db::CONFIG_COLLECTION_NAME, vec![doc!{ROOM_DEVICES_KEY: docs}]).await;
+}
+
+// Picks the TV a command is meant for: the one named with @, else the
+// room's default, else the only one there is. Without any device the old
+// unnamed one is assumed, so the lineup can be set up before the TV.
+pub async fn resolve_device(explicit: Option<&str>, room: &str, db: Box<db::Homechatbotdb>) -> Result<String, Error> {
+    let devices = get_devices(db.clone()).await?;
+    let names : Vec<String> = devices.iter().map(|d| d.name()).collect();
+    if let Some(name) = explicit {
+        let name = name.to_lowercase();
+        if names.contains(&name) {
+            return Ok(name);
+        }
+        return Err(Error::NotFound(format!("No TV called \"{}\", known TVs: {}", name, names.join(", "))));
+    }
+    let room_devices = get_room_devices(db).await?;
+    if let Some(rd) = room_devices.iter().find(|r| r.room == room && names.contains(&r.device)) {
+        return Ok(rd.device.clone());
+    }
+    if names.len() == 0 {
+        return Ok(DEFAULT_TV.to_string());
+    } else if names.len() == 1 {
+        return Ok(names[0].clone());
+    }
+    return Err(Error::InvalidInput(format!("There are several TVs ({}), use \"tv @{{name}} ...\" or set one with \"tv device default {{name}}\"", names.join(", "))));
+}
+
+// The lineup and schedules set up before any TV had a name are kept under
+// the unnamed TV. Once there are named TVs but no unnamed one it is never
+// picked again, so they go to the first named TV instead.
+pub async fn adopt_default_lineup(db: Box<db::Homechatbotdb>) -> Result<(), Error> {
+    let devices = get_devices(db.clone()).await?;
+    if devices.iter().any(|d| d.tv_device.is_none()) {
+        return Ok(());
+    }
+    let target = match devices.first() {
+        Some(d) => d.name(),
+        None => return Ok(()),
+    };
+    let moved = lineup::move_lineup(DEFAULT_TV, target.as_str(), db.clone()).await?;
+    if moved > 0 {
+        info!("Moved {} channels of the unnamed TV to \"{}\"", moved, target);
+    }
+    return schedule::move_schedules(DEFAULT_TV, target.as_str(), db).await;
+}
+
+pub async fn get_backend(name: &str, db: Box<db::Homechatbotdb>) -> Result<Option<Box<dyn TvBackend>>, Error> {
+    let devices = get_devices(db).await?;
+    let dev = match devices.into_iter().find(|d| d.name() == name) {
+        Some(d) => d,
+        None => return Ok(None),
+    };
+    return Ok(Some(Box::new(HttpBackend::new(dev.tv_url.as_str(), dev.tv_token)?)));
+}
+
+pub async fn handle_devices_request(room: &str, db: Box<db::Homechatbotdb>) -> String {
+    let devices = match get_devices(db.clone()).await {
+        Ok(d) => d,
+        Err(e) => return error::report(&e),
+    };
+    if devices.len() == 0 {
+        return String::from("No TVs configured");
+    }
+    let current = resolve_device(None, room, db).await.ok();
+    let mut msg = "".to_string();
+    for d in devices {
+        let here = if current.as_ref() == Some(&d.name()) { " (used here)" } else { "" };
+        msg = format!("{}{} {}{}\n", msg, d.name(), d.tv_url, here);
+    }
+    return msg;
+}
+
+// Changing the devices decides where the bot sends requests and with which
+// token, so it is only for admins, and a token is only taken in a direct chat
+pub async fn handle_device_command(cmd: &str, sender: &str, room: &str, is_dm: bool, db: Box<db::Homechatbotdb>) -> String {
+    let parts : Vec<&str> = cmd.split_whitespace().collect();
+    if parts.len() < 2 {
+        return String::from(DEVICE_HELP);
+    }
+    match admin::is_admin(sender, db.clone()).await {
+        Ok(true) => {},
+        Ok(false) => return String::from("TV devices can only be changed by the users listed in \"admin_users\""),
+        Err(e) => return error::report(&e),
+    };
+    let name = parts[1].trim_start_matches('@').to_lowercase();
+    let action = parts[0].to_lowercase();
+    if action == "add" {
+        if parts.len() < 3 || parts.len() > 4 {
+            return String::from(DEVICE_HELP);
+        }
+        if parts.len() == 4 && !is_dm {
+            return String::from("A TV token is only accepted in a direct chat. Send \"tv device add {name} {url} {token}\" there.");
+        }
+        let dev = TvDevice{
+            tv_device: if name == DEFAULT_TV { None } else { Some(name.clone()) },
+            tv_url: parts[2].to_string(),
+            tv_token: parts.get(3).map(|t| t.to_string()),
+        };
+        let ddoc = match to_document(&dev) {
+            Ok(d) => d,
+            Err(e) => return format!("Unable to serialize TV device: {}", e),
+        };
+        match remove_device(name.as_str(), db.clone()).await {
+            Ok(_) => {},
+            Err(e) => return error::report(&e),
+        };
+        match db.insert_data_to_collection(db::CONFIG_COLLECTION_NAME, vec![ddoc]).await {
+            Ok(_) => {},
+            Err(e) => return error::report(&e),
+        };
+        match adopt_default_lineup(db).await {
+            Ok(_) => return format!("TV \"{}\" saved", name),
+            Err(e) => return format!("TV \"{}\" saved, but unable to move the channels of the unnamed TV to it: {}", name, error::report(&e)),
+        };
+    } else if action == "rem" {
+        match remove_device(name.as_str(), db.clone()).await {
+            Ok(_) => {},
+            Err(e) => return error::report(&e),
+        };
+        match adopt_default_lineup(db).await {
+            Ok(_) => return format!("TV \"{}\" removed", name),
+            Err(e) => return format!("TV \"{}\" removed, but unable to move the channels of the unnamed TV: {}", name, error::report(&e)),
+        };
+    } else if action == "default" {
+        match resolve_device(Some(name.as_str()), room, db.clone()).await {
+            Ok(_) => {},
+            Err(e) => return error::report(&e),
+        };
+        match set_room_device(room, name.as_str(), db).await {
+            Ok(_) => return format!("TV \"{}\" is now the default in this room", name),
+            Err(e) => return error::report(&e),
+        };
+    }
+    return String::from(DEVICE_HELP);
+}
+
+async fn remove_device(name: &str, db: Box<db::Homechatbotdb>) -> Result<(), Error> {
+    if name == DEFAULT_TV {
+        // null also matches documents without the field
+        db.remove_data(db::CONFIG_COLLECTION_NAME, doc!{"tv_url": {"$exists": true}, "tv_device": mongodb::bson::Bson::Null}).await?;
+    }
+    return db.remove_data(db::CONFIG_COLLECTION_NAME, doc!{"tv_url": {"$exists": true}, "tv_device": name}).await;
+}
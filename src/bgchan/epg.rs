@@ -0,0 +1,339 @@
+use crate::bgchan::lineup::{self, Channel};
+use crate::db;
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use mongodb::bson::doc;
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeZone, Utc};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// Guides are usually regenerated once a day, a few hours is fresh enough
+const EPG_CACHE_SECS : u64 = 6 * 3600;
+const XMLTV_TIME_FORMAT : &str = "%Y%m%d%H%M%S %z";
+const XMLTV_TIME_FORMAT_NO_TZ : &str = "%Y%m%d%H%M%S";
+const TONIGHT_FROM_HOUR : u32 = 20;
+const TONIGHT_TO_HOUR : u32 = 23;
+const MAX_FIND_RESULTS : usize = 10;
+const MAX_NEXT_RESULTS : usize = 4;
+
+static CACHE : Mutex<Option<CachedGuide>> = Mutex::new(None);
+
+struct CachedGuide {
+    source: String,
+    expires: Instant,
+    guide: Arc<Guide>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EpgConfig {
+    tv_epg_source: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct GuideChannel {
+    pub id: String,
+    pub names: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Programme {
+    pub channel: String,
+    pub start: DateTime<FixedOffset>,
+    pub stop: Option<DateTime<FixedOffset>>,
+    pub title: String,
+}
+
+// A parsed XMLTV file, programmes sorted by start time
+#[derive(Debug, Default)]
+pub struct Guide {
+    pub channels: Vec<GuideChannel>,
+    pub programmes: Vec<Programme>,
+}
+
+fn parse_time(t: &str) -> Result<DateTime<FixedOffset>, Error> {
+    let t = t.trim();
+    if let Ok(dt) = DateTime::parse_from_str(t, XMLTV_TIME_FORMAT) {
+        return Ok(dt);
+    }
+    // Times without an offset are UTC according to the XMLTV DTD
+    let digits : String = t.chars().take(14).collect();
+    match NaiveDateTime::parse_from_str(digits.as_str(), XMLTV_TIME_FORMAT_NO_TZ) {
+        Ok(n) => return Ok(Utc.from_utc_datetime(&n).into()),
+        Err(e) => return Err(Error::InvalidInput(format!("Invalid XMLTV time \"{}\": {}", t, e))),
+    };
+}
+
+fn child_texts(node: roxmltree::Node, tag: &str) -> Vec<String> {
+    return node.children()
+        .filter(|c| c.has_tag_name(tag))
+        .filter_map(|c| c.text())
+        .map(|t| t.trim().to_string())
+        .filter(|t| t != "")
+        .collect();
+}
+
+pub fn parse_xmltv(data: &str) -> Result<Guide, Error> {
+    let xml = match roxmltree::Document::parse(data) {
+        Ok(x) => x,
+        Err(e) => return Err(Error::InvalidInput(format!("Invalid XMLTV file: {}", e))),
+    };
+    let root = xml.root_element();
+    if !root.has_tag_name("tv") {
+        return Err(Error::InvalidInput(String::from("Invalid XMLTV file: the root element is not <tv>")));
+    }
+    let mut guide = Guide::default();
+    for node in root.children().filter(|n| n.is_element()) {
+        if node.has_tag_name("channel") {
+            let id = match node.attribute("id") {
+                Some(i) => i.to_string(),
+                None => continue,
+            };
+            guide.channels.push(GuideChannel{id: id, names: child_texts(node, "display-name")});
+        } else if node.has_tag_name("programme") {
+            let (channel, start) = match (node.attribute("channel"), node.attribute("start").map(parse_time)) {
+                (Some(c), Some(Ok(s))) => (c.to_string(), s),
+                (_, Some(Err(e))) => {
+                    warn!("Skipping programme: {}", e);
+                    continue;
+                },
+                _ => continue,
+            };
+            // One broken programme must not cost the whole guide
+            let stop = match node.attribute("stop").map(parse_time) {
+                Some(Ok(s)) => Some(s),
+                Some(Err(e)) => {
+                    warn!("Skipping programme: {}", e);
+                    continue;
+                },
+                None => None,
+            };
+            let title = match child_texts(node, "title").into_iter().next() {
+                Some(t) => t,
+                None => continue,
+            };
+            guide.programmes.push(Programme{channel: channel, start: start, stop: stop, title: title});
+        }
+    }
+    guide.programmes.sort_by(|a, b| a.start.cmp(&b.start));
+    // Programmes without a stop time end when the next one on the channel starts
+    for i in 0..guide.programmes.len() {
+        if guide.programmes[i].stop.is_some() {
+            continue;
+        }
+        let ch = guide.programmes[i].channel.clone();
+        guide.programmes[i].stop = guide.programmes[i + 1..].iter().find(|p| p.channel == ch).map(|p| p.start);
+    }
+    return Ok(guide);
+}
+
+async fn fetch(source: &str) -> Result<String, Error> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        let resp = match reqwest::get(source).await.and_then(|r| r.error_for_status()) {
+            Ok(r) => r,
+            Err(e) => return Err(Error::from_http("Unable to download the TV guide", e)),
+        };
+        match resp.text().await {
+            Ok(t) => return Ok(t),
+            Err(e) => return Err(Error::from_http("Unable to read the TV guide", e)),
+        };
+    }
+    match tokio::fs::read_to_string(source).await {
+        Ok(t) => return Ok(t),
+        Err(e) => return Err(Error::NotFound(format!("Unable to read the TV guide {}: {}", source, e))),
+    };
+}
+
+// Returns the guide from the cache, loading it again when the source changed
+// or the cached copy is too old
+pub async fn load_guide(source: &str) -> Result<Arc<Guide>, Error> {
+    {
+        let cache = match CACHE.lock() {
+            Ok(c) => c,
+            Err(p) => p.into_inner(),
+        };
+        if let Some(c) = cache.as_ref() {
+            if c.source == source && Instant::now() < c.expires {
+                return Ok(c.guide.clone());
+            }
+        }
+    }
+    let guide = Arc::new(parse_xmltv(fetch(source).await?.as_str())?);
+    info!(source = source, channels = guide.channels.len(), programmes = guide.programmes.len(), "Loaded TV guide");
+    let mut cache = match CACHE.lock() {
+        Ok(c) => c,
+        Err(p) => p.into_inner(),
+    };
+    *cache = Some(CachedGuide{source: source.to_string(), expires: Instant::now() + Duration::from_secs(EPG_CACHE_SECS), guide: guide.clone()});
+    return Ok(guide);
+}
+
+// Makes the next load_guide read the source again, as if the cached copy
+// had grown old
+#[cfg(test)]
+pub fn expire_cache() {
+    let mut cache = CACHE.lock().unwrap_or_else(|p| p.into_inner());
+    if let Some(c) = cache.as_mut() {
+        c.expires = Instant::now();
+    }
+}
+
+pub async fn get_guide(db: Box<db::Homechatbotdb>) -> Result<Arc<Guide>, Error> {
+    let mut items = db.get_generic_data_collection::<EpgConfig>(db::CONFIG_COLLECTION_NAME, doc!{"tv_epg_source": {"$exists": true}}, doc!{}).await?;
+    match items.pop() {
+        Some(c) => return load_guide(c.tv_epg_source.as_str()).await,
+        None => return Err(Error::NotFound(String::from("No TV guide configured, set \"tv_epg_source\" in the config collection"))),
+    };
+}
+
+// Guide channels are tied to the lineup by display name or by the XMLTV id
+// without its domain ("bnt1.bg" is "bnt1")
+fn same_channel(gc: &GuideChannel, chan: &Channel) -> bool {
+    let keys : Vec<String> = std::iter::once(&chan.name).chain(chan.aliases.iter()).map(|k| lineup::normalize(k)).collect();
+    let id_stem = lineup::normalize(gc.id.split('.').next().unwrap_or(""));
+    return keys.contains(&id_stem) || gc.names.iter().any(|n| keys.contains(&lineup::normalize(n)));
+}
+
+impl Guide {
+    fn channel_name(&self, id: &str, lineup: &[Channel]) -> String {
+        let gc = match self.channels.iter().find(|c| c.id == id) {
+            Some(c) => c,
+            None => return id.to_string(),
+        };
+        match lineup.iter().find(|c| same_channel(gc, c)) {
+            Some(c) => return c.name.clone(),
+            None => return gc.names.first().unwrap_or(&gc.id).clone(),
+        };
+    }
+
+    pub fn lineup_channel<'a>(&self, id: &str, lineup: &'a [Channel]) -> Option<&'a Channel> {
+        let gc = self.channels.iter().find(|c| c.id == id)?;
+        return lineup.iter().find(|c| same_channel(gc, c));
+    }
+
+    // With a lineup only its channels are of interest, and only the
+    // favourites if there are any
+    fn watched_ids(&self, lineup: &[Channel]) -> Vec<String> {
+        if lineup.len() == 0 {
+            return self.channels.iter().map(|c| c.id.clone()).collect();
+        }
+        let favs : Vec<&Channel> = lineup.iter().filter(|c| c.favourite).collect();
+        let wanted : Vec<&Channel> = if favs.len() > 0 { favs } else { lineup.iter().collect() };
+        return self.channels.iter().filter(|gc| wanted.iter().any(|c| same_channel(gc, c))).map(|c| c.id.clone()).collect();
+    }
+
+    fn ids_for(&self, query: &str, lineup: &[Channel]) -> Result<Vec<String>, Error> {
+        if lineup.len() > 0 {
+            let chan = lineup::find_channel(query, lineup)?;
+            return Ok(self.channels.iter().filter(|gc| same_channel(gc, chan)).map(|c| c.id.clone()).collect());
+        }
+        let q = lineup::normalize(query);
+        return Ok(self.channels.iter()
+            .filter(|gc| lineup::normalize(&gc.id).starts_with(q.as_str()) || gc.names.iter().any(|n| lineup::normalize(n) == q))
+            .map(|c| c.id.clone()).collect());
+    }
+
+    pub fn airing<'a>(&'a self, id: &str, at: DateTime<FixedOffset>) -> Option<&'a Programme> {
+        return self.programmes.iter().find(|p| p.channel == id && p.start <= at && p.stop.map_or(false, |s| s > at));
+    }
+
+    // Programmes on air right now whose title contains the text
+    pub fn find_airing(&self, text: &str, at: DateTime<FixedOffset>) -> Vec<&Programme> {
+        let text = text.trim().to_lowercase();
+        return self.programmes.iter()
+            .filter(|p| p.start <= at && p.stop.map_or(false, |s| s > at) && p.title.to_lowercase().contains(text.as_str()))
+            .collect();
+    }
+}
+
+// The requests below answer as of at, showing times in its time zone
+pub fn now() -> DateTime<FixedOffset> {
+    return Local::now().into();
+}
+
+fn hhmm(t: &DateTime<FixedOffset>, at: &DateTime<FixedOffset>) -> String {
+    return t.with_timezone(&at.timezone()).format("%H:%M").to_string();
+}
+
+pub fn now_request(guide: &Guide, lineup: &[Channel], at: DateTime<FixedOffset>) -> String {
+    let mut msg = "".to_string();
+    for id in guide.watched_ids(lineup) {
+        if let Some(p) = guide.airing(id.as_str(), at) {
+            msg = format!("{}{}: {} (until {})\n", msg, guide.channel_name(id.as_str(), lineup), p.title, p.stop.as_ref().map_or("?".to_string(), |s| hhmm(s, &at)));
+        }
+    }
+    if msg == "" {
+        return String::from("Nothing on air according to the guide");
+    }
+    return msg;
+}
+
+pub fn next_request(query: &str, guide: &Guide, lineup: &[Channel], at: DateTime<FixedOffset>) -> Result<String, Error> {
+    let ids = guide.ids_for(query, lineup)?;
+    if ids.len() == 0 {
+        return Err(Error::NotFound(format!("\"{}\" is not in the TV guide", query.trim())));
+    }
+    let mut msg = format!("{}:\n", guide.channel_name(ids[0].as_str(), lineup));
+    let upcoming = guide.programmes.iter()
+        .filter(|p| ids.contains(&p.channel) && p.stop.map_or(p.start > at, |s| s > at))
+        .take(MAX_NEXT_RESULTS);
+    for p in upcoming {
+        msg = format!("{}{} {}\n", msg, hhmm(&p.start, &at), p.title);
+    }
+    return Ok(msg);
+}
+
+pub fn tonight_request(guide: &Guide, lineup: &[Channel], at: DateTime<FixedOffset>) -> String {
+    let from = at.date().and_hms(TONIGHT_FROM_HOUR, 0, 0);
+    let to = at.date().and_hms(TONIGHT_TO_HOUR, 0, 0);
+    let mut msg = "".to_string();
+    for id in guide.watched_ids(lineup) {
+        let shows : Vec<String> = guide.programmes.iter()
+            .filter(|p| p.channel == id && p.start < to && p.stop.map_or(p.start >= from, |s| s > from))
+            .map(|p| format!("    {} {}", hhmm(&p.start, &at), p.title))
+            .collect();
+        if shows.len() > 0 {
+            msg = format!("{}{}:\n{}\n", msg, guide.channel_name(id.as_str(), lineup), shows.join("\n"));
+        }
+    }
+    if msg == "" {
+        return String::from("Nothing in the guide for tonight");
+    }
+    return msg;
+}
+
+pub fn find_request(text: &str, guide: &Guide, lineup: &[Channel], at: DateTime<FixedOffset>) -> String {
+    let text = text.trim().to_lowercase();
+    let found = guide.programmes.iter()
+        .filter(|p| p.stop.map_or(p.start > at, |s| s > at) && p.title.to_lowercase().contains(text.as_str()))
+        .take(MAX_FIND_RESULTS);
+    let mut msg = "".to_string();
+    for p in found {
+        let start = p.start.with_timezone(&at.timezone());
+        let day = if start.date() == at.date() {
+            "".to_string()
+        } else {
+            format!("{} ", start.format("%a"))
+        };
+        msg = format!("{}{}{} {}: {}\n", msg, day, hhmm(&p.start, &at), guide.channel_name(p.channel.as_str(), lineup), p.title);
+    }
+    if msg == "" {
+        return format!("Nothing matching \"{}\" in the guide", text);
+    }
+    return msg;
+}
+
+// Picks the lineup channel currently showing something matching the text
+pub fn channel_showing<'a>(text: &str, guide: &Guide, lineup: &'a [Channel], at: DateTime<FixedOffset>) -> Result<(&'a Channel, String), Error> {
+    let airing = guide.find_airing(text, at);
+    for p in airing.iter() {
+        if let Some(c) = guide.lineup_channel(p.channel.as_str(), lineup) {
+            return Ok((c, p.title.clone()));
+        }
+    }
+    if airing.len() > 0 {
+        return Err(Error::NotFound(format!("\"{}\" is on {}, which is not in the lineup", airing[0].title, guide.channel_name(airing[0].channel.as_str(), lineup))));
+    }
+    return Err(Error::NotFound(format!("Nothing matching \"{}\" is on air", text.trim())));
+}
+
@@ -0,0 +1,110 @@
+use crate::bgchan::TvBackend;
+use crate::error::Error;
+use async_trait::async_trait;
+use serde::Serialize;
+use std::time::Duration;
+
+const HTTP_TIMEOUT_SECS : u64 = 10;
+
+#[derive(Serialize)]
+struct PowerRequest {
+    on: bool,
+}
+
+#[derive(Serialize)]
+struct ChannelRequest {
+    number: u32,
+}
+
+#[derive(Serialize)]
+struct VolumeRequest {
+    level: u32,
+}
+
+#[derive(Serialize)]
+struct InputRequest {
+    source: String,
+}
+
+// Talks to a TV (or a bridge in front of it) exposing a small JSON API:
+//   POST {base}/power           {"on": true}
+//   POST {base}/channel         {"number": 5}
+//   POST {base}/channel/up
+//   POST {base}/channel/down
+//   POST {base}/volume          {"level": 20}
+//   POST {base}/volume/up
+//   POST {base}/volume/down
+//   POST {base}/input           {"source": "hdmi1"}
+// Any 2xx answer counts as success.
+pub struct HttpBackend {
+    client: reqwest::Client,
+    base_url: String,
+    token: Option<String>,
+}
+
+impl HttpBackend {
+    pub fn new(base_url: &str, token: Option<String>) -> Result<HttpBackend, Error> {
+        let client = match reqwest::Client::builder().timeout(Duration::from_secs(HTTP_TIMEOUT_SECS)).build() {
+            Ok(c) => c,
+            Err(e) => return Err(Error::from_http("Unable to create HTTP client", e)),
+        };
+        return Ok(HttpBackend{
+            client: client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: token,
+        });
+    }
+
+    async fn post<T: Serialize + ?Sized>(&self, path: &str, body: Option<&T>) -> Result<(), Error> {
+        let mut req = self.client.post(format!("{}{}", self.base_url, path));
+        if let Some(t) = &self.token {
+            req = req.bearer_auth(t);
+        }
+        if let Some(b) = body {
+            req = req.json(b);
+        }
+        let resp = match req.send().await {
+            Ok(r) => r,
+            Err(e) => return Err(Error::from_http(&format!("Unable to reach the TV at {}", path), e)),
+        };
+        match resp.error_for_status() {
+            Ok(_) => return Ok(()),
+            Err(e) => return Err(Error::from_http(&format!("The TV rejected {}", path), e)),
+        };
+    }
+}
+
+#[async_trait]
+impl TvBackend for HttpBackend {
+    async fn power(&self, on: bool) -> Result<(), Error> {
+        return self.post("/power", Some(&PowerRequest{on: on})).await;
+    }
+
+    async fn set_channel(&self, number: u32) -> Result<(), Error> {
+        return self.post("/channel", Some(&ChannelRequest{number: number})).await;
+    }
+
+    async fn channel_up(&self) -> Result<(), Error> {
+        return self.post::<()>("/channel/up", None).await;
+    }
+
+    async fn channel_down(&self) -> Result<(), Error> {
+        return self.post::<()>("/channel/down", None).await;
+    }
+
+    async fn set_volume(&self, level: u32) -> Result<(), Error> {
+        return self.post("/volume", Some(&VolumeRequest{level: level})).await;
+    }
+
+    async fn volume_up(&self) -> Result<(), Error> {
+        return self.post::<()>("/volume/up", None).await;
+    }
+
+    async fn volume_down(&self) -> Result<(), Error> {
+        return self.post::<()>("/volume/down", None).await;
+    }
+
+    async fn set_input(&self, source: &str) -> Result<(), Error> {
+        return self.post("/input", Some(&InputRequest{source: source.to_string()})).await;
+    }
+}
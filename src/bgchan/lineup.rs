@@ -0,0 +1,266 @@
+use crate::db;
+use crate::error::{self, Error};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use mongodb::bson::{doc, to_document};
+
+pub const CHANNEL_COLLECTION_NAME : &str = "tv_channels";
+pub const CHANNEL_HELP : &str = "TV lineup commands:
+    channels
+    channel add {number} {name} [lang {language}] [aka {alias}, {alias}...]
+    channel rem {channel}
+    fav
+    fav add {channel}
+    fav rem {channel}";
+
+// One entry of a TV's lineup. chanid is "{tv}/{number}" and keeps numbers
+// unique per TV.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Channel {
+    pub chanid: String,
+    pub tv: String,
+    pub number: u32,
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub language: String,
+    pub favourite: bool,
+}
+
+pub async fn get_lineup(tv: &str, db: Box<db::Homechatbotdb>) -> Result<Vec<Channel>, Error> {
+    return db.get_generic_data_collection::<Channel>(CHANNEL_COLLECTION_NAME, doc!{"tv": tv}, doc!{"number": 1}).await;
+}
+
+// Hands the channels of one TV over to another. Numbers the other TV already
+// has keep its own entry, and a channel is only removed once it was added.
+pub async fn move_lineup(from: &str, to: &str, db: Box<db::Homechatbotdb>) -> Result<usize, Error> {
+    let target = get_lineup(to, db.clone()).await?;
+    let mut moved = 0;
+    for c in get_lineup(from, db.clone()).await? {
+        if target.iter().any(|t| t.number == c.number) {
+            continue;
+        }
+        let old = c.chanid.clone();
+        let chan = Channel{chanid: format!("{}/{}", to, c.number), tv: to.to_string(), ..c};
+        let cdoc = match to_document(&chan) {
+            Ok(d) => d,
+            Err(e) => return Err(Error::Internal(format!("Unable to serialize channel: {}", e))),
+        };
+        db.insert_data_to_collection(CHANNEL_COLLECTION_NAME, vec![cdoc]).await?;
+        db.remove_data(CHANNEL_COLLECTION_NAME, doc!{"chanid": old.as_str()}).await?;
+        moved += 1;
+    }
+    return Ok(moved);
+}
+
+// Only letters and digits count, so "BNT 1", "bnt-1" and "bnt1" are the same
+pub fn normalize(s: &str) -> String {
+    return s.chars().filter(|c| c.is_alphanumeric()).flat_map(|c| c.to_lowercase()).collect();
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a : Vec<char> = a.chars().collect();
+    let b : Vec<char> = b.chars().collect();
+    let mut prev : Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut cur = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            cur[j] = (prev[j] + 1).min(cur[j - 1] + 1).min(prev[j - 1] + cost);
+        }
+        prev = cur;
+    }
+    return prev[b.len()];
+}
+
+// Lower is better: exact match, then prefix, then substring, then a couple
+// of typos depending on the length of the name
+fn match_score(query: &str, key: &str) -> Option<usize> {
+    if key == "" {
+        return None;
+    }
+    if key == query {
+        return Some(0);
+    } else if key.starts_with(query) {
+        return Some(1);
+    } else if key.contains(query) {
+        return Some(2);
+    }
+    let dist = edit_distance(query, key);
+    if dist <= (key.chars().count() / 4).max(1) {
+        return Some(2 + dist);
+    }
+    return None;
+}
+
+// Finds a channel by number, name or alias. Ambiguous queries are rejected
+// with the candidates, so nobody lands on the wrong channel.
+pub fn find_channel<'a>(query: &str, lineup: &'a [Channel]) -> Result<&'a Channel, Error> {
+    if let Ok(n) = query.trim().parse::<u32>() {
+        match lineup.iter().find(|c| c.number == n) {
+            Some(c) => return Ok(c),
+            None => return Err(Error::NotFound(format!("Channel {} is not in the lineup", n))),
+        };
+    }
+    let q = normalize(query);
+    if q == "" {
+        return Err(Error::InvalidInput(String::from("A channel name is needed")));
+    }
+    let mut best : Option<usize> = None;
+    let mut found : Vec<&Channel> = vec![];
+    for c in lineup {
+        let score = std::iter::once(&c.name).chain(c.aliases.iter())
+            .filter_map(|k| match_score(q.as_str(), normalize(k).as_str()))
+            .min();
+        let score = match score {
+            Some(s) => s,
+            None => continue,
+        };
+        if best.map_or(true, |b| score < b) {
+            best = Some(score);
+            found = vec![c];
+        } else if best == Some(score) {
+            found.push(c);
+        }
+    }
+    if found.len() == 1 {
+        return Ok(found[0]);
+    } else if found.len() == 0 {
+        return Err(Error::NotFound(format!("No channel matches \"{}\"", query.trim())));
+    }
+    let names : Vec<String> = found.iter().map(|c| c.name.clone()).collect();
+    return Err(Error::InvalidInput(format!("\"{}\" could be {}", query.trim(), names.join(", "))));
+}
+
+pub fn format_channel(c: &Channel) -> String {
+    let mut msg = format!("{} {}", c.number, c.name);
+    if c.language != "" {
+        msg = format!("{} ({})", msg, c.language);
+    }
+    if c.aliases.len() > 0 {
+        msg = format!("{} aka {}", msg, c.aliases.join(", "));
+    }
+    if c.favourite {
+        msg = format!("{} *", msg);
+    }
+    return msg;
+}
+
+pub async fn handle_channels_request(tv: &str, favourites_only: bool, db: Box<db::Homechatbotdb>) -> String {
+    let lineup = match get_lineup(tv, db).await {
+        Ok(l) => l,
+        Err(e) => return error::report(&e),
+    };
+    let mut msg = "".to_string();
+    for c in lineup.iter().filter(|c| c.favourite || !favourites_only) {
+        msg = format!("{}{}\n", msg, format_channel(c));
+    }
+    if msg == "" {
+        return String::from(if favourites_only { "No favourite channels" } else { "No channels in the lineup" });
+    }
+    return msg;
+}
+
+pub async fn handle_channel_command(cmd: &str, tv: &str, db: Box<db::Homechatbotdb>) -> String {
+    let re = match Regex::new(r"^(\w+)(?:\s+(.*))?$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)),
+    };
+    let caps = match re.captures(cmd.trim()) {
+        Some(c) => c,
+        None => return String::from(CHANNEL_HELP),
+    };
+    let action = caps.get(1).map_or("".to_string(), |c| c.as_str().to_lowercase());
+    let rest = match caps.get(2) {
+        Some(r) => r.as_str().trim(),
+        None => return String::from(CHANNEL_HELP),
+    };
+    if action == "add" {
+        return handle_add_request(rest, tv, db).await;
+    } else if action == "rem" {
+        let lineup = match get_lineup(tv, db.clone()).await {
+            Ok(l) => l,
+            Err(e) => return error::report(&e),
+        };
+        let c = match find_channel(rest, &lineup) {
+            Ok(c) => c,
+            Err(e) => return error::report(&e),
+        };
+        match db.remove_data(CHANNEL_COLLECTION_NAME, doc!{"chanid": c.chanid.as_str()}).await {
+            Ok(_) => return format!("Channel {} removed from the lineup", c.name),
+            Err(e) => return error::report(&e),
+        };
+    }
+    return String::from(CHANNEL_HELP);
+}
+
+async fn handle_add_request(cmd_rest: &str, tv: &str, db: Box<db::Homechatbotdb>) -> String {
+    let re = match Regex::new(r"^(\d+)\s+(\S+)(?:\s+lang\s+(\S+))?(?:\s+aka\s+(.+))?$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)),
+    };
+    let caps = match re.captures(cmd_rest) {
+        Some(c) => c,
+        None => return String::from(CHANNEL_HELP),
+    };
+    let number = match caps.get(1).map_or("", |c| c.as_str()).parse::<u32>() {
+        Ok(n) => n,
+        Err(e) => return format!("Invalid channel number: {}", e),
+    };
+    let aliases : Vec<String> = caps.get(4).map_or("", |c| c.as_str())
+        .split(",").map(|a| a.trim().to_string()).filter(|a| a != "").collect();
+    let chanid = format!("{}/{}", tv, number);
+    // Re-adding a number replaces the entry but keeps it a favourite
+    let favourite = match db.get_generic_data_collection::<Channel>(CHANNEL_COLLECTION_NAME, doc!{"chanid": chanid.as_str()}, doc!{}).await {
+        Ok(items) => items.iter().any(|c| c.favourite),
+        Err(e) => return error::report(&e),
+    };
+    let chan = Channel{
+        chanid: chanid.clone(),
+        tv: tv.to_string(),
+        number: number,
+        name: caps.get(2).map_or("", |c| c.as_str()).to_string(),
+        aliases: aliases,
+        language: caps.get(3).map_or("", |c| c.as_str()).to_lowercase(),
+        favourite: favourite,
+    };
+    let cdoc = match to_document(&chan) {
+        Ok(d) => d,
+        Err(e) => return format!("Unable to serialize channel: {}", e),
+    };
+    match db.remove_data(CHANNEL_COLLECTION_NAME, doc!{"chanid": chanid.as_str()}).await {
+        Ok(_) => {},
+        Err(e) => return error::report(&e),
+    };
+    match db.insert_data_to_collection(CHANNEL_COLLECTION_NAME, vec![cdoc]).await {
+        Ok(_) => return format!("Channel {} added: {}", number, format_channel(&chan)),
+        Err(e) => return error::report(&e),
+    };
+}
+
+pub async fn handle_fav_command(cmd: &str, tv: &str, db: Box<db::Homechatbotdb>) -> String {
+    let cmd = cmd.trim();
+    if cmd == "" {
+        return handle_channels_request(tv, true, db).await;
+    }
+    let (action, rest) = match cmd.split_once(char::is_whitespace) {
+        Some((a, r)) => (a.to_lowercase(), r.trim()),
+        None => return String::from(CHANNEL_HELP),
+    };
+    let favourite = match action.as_str() {
+        "add" => true,
+        "rem" => false,
+        _ => return String::from(CHANNEL_HELP),
+    };
+    let lineup = match get_lineup(tv, db.clone()).await {
+        Ok(l) => l,
+        Err(e) => return error::report(&e),
+    };
+    let c = match find_channel(rest, &lineup) {
+        Ok(c) => c,
+        Err(e) => return error::report(&e),
+    };
+    match db.update_data(CHANNEL_COLLECTION_NAME, doc!{"chanid": c.chanid.as_str()}, doc!{"favourite": favourite}).await {
+        Ok(_) => return format!("{} {} favourites", c.name, if favourite { "added to" } else { "removed from" }),
+        Err(e) => return error::report(&e),
+    };
+}
@@ -0,0 +1,322 @@
+use crate::db;
+use crate::error::{self, Error};
+use crate::events;
+use crate::migrate;
+use async_trait::async_trait;
+use regex::Regex;
+
+mod devices;
+pub(crate) mod epg;
+pub(crate) mod http;
+pub(crate) mod lineup;
+mod schedule;
+
+use http::HttpBackend;
+
+// Upgrades from before TVs had names, see devices::adopt_default_lineup
+pub async fn migrate_default_lineup(db: Box<db::Homechatbotdb>) -> Result<(), Error> {
+    return devices::adopt_default_lineup(db).await;
+}
+
+pub const COLLECTIONS : &[migrate::Collection] = &[
+    migrate::Collection{name: lineup::CHANNEL_COLLECTION_NAME, index: Some("chanid")},
+    migrate::Collection{name: schedule::SCHEDULE_COLLECTION_NAME, index: Some("schedid")},
+];
+
+const BGCHAN_HELP : &str = "TV allowed commands:
+    on|off
+    {channel number or name}
+    up|down
+    vol {level}|up|down
+    input {source}
+    channels
+    channel add|rem ...
+    fav [add|rem {channel}]
+    now
+    next {channel}
+    tonight
+    find {title}
+    switch to the channel showing {title}
+    at {HH:MM} {command}
+    {command} in {minutes}m|{hours}h
+    schedule [cancel {id}]
+    devices
+    device add|rem|default ...
+Any command can be sent to a given TV with @{name}, e.g. \"@kids-room off\"";
+const MAX_VOLUME : u32 = 100;
+
+// What a TV has to support to be driven from the chat. Each call is a single
+// remote-control action; backends report failures through the shared Error.
+#[async_trait]
+pub trait TvBackend: Send + Sync {
+    async fn power(&self, on: bool) -> Result<(), Error>;
+    async fn set_channel(&self, number: u32) -> Result<(), Error>;
+    async fn channel_up(&self) -> Result<(), Error>;
+    async fn channel_down(&self) -> Result<(), Error>;
+    async fn set_volume(&self, level: u32) -> Result<(), Error>;
+    async fn volume_up(&self) -> Result<(), Error>;
+    async fn volume_down(&self) -> Result<(), Error>;
+    async fn set_input(&self, source: &str) -> Result<(), Error>;
+}
+
+pub async fn handle_bgchan_command(cmd: String, sender: &str, room: &str, is_dm: bool, db: Box<db::Homechatbotdb>) -> String {
+    let mut cmd = cmd.trim();
+    let mut explicit = None;
+    if let Some(addressed) = cmd.strip_prefix('@') {
+        let (name, rest) = addressed.split_once(char::is_whitespace).unwrap_or((addressed, ""));
+        explicit = Some(name);
+        cmd = rest.trim();
+    }
+    let (first, rest) = cmd.split_once(char::is_whitespace).unwrap_or((cmd, ""));
+    match first.to_lowercase().as_str() {
+        "devices" => return devices::handle_devices_request(room, db).await,
+        "device" => return devices::handle_device_command(rest, sender, room, is_dm, db).await,
+        "schedule" => return schedule::handle_schedule_command(rest, db).await,
+        _ => {},
+    };
+    let tv = match devices::resolve_device(explicit, room, db.clone()).await {
+        Ok(t) => t,
+        Err(e) => return error::report(&e),
+    };
+    match first.to_lowercase().as_str() {
+        "channels" => return lineup::handle_channels_request(tv.as_str(), false, db).await,
+        "channel" => return lineup::handle_channel_command(rest, tv.as_str(), db).await,
+        "fav" => return lineup::handle_fav_command(rest, tv.as_str(), db).await,
+        _ => {},
+    };
+    let channels = match lineup::get_lineup(tv.as_str(), db.clone()).await {
+        Ok(l) => l,
+        Err(e) => return error::report(&e),
+    };
+    match parse_schedule(cmd) {
+        Ok(Some((command, run_at))) => return handle_schedule_request(command.as_str(), run_at, tv.as_str(), sender, room, &channels, db).await,
+        Ok(None) => {},
+        Err(e) => return error::report(&e),
+    };
+    let re = match Regex::new(r"(?i)^(now|next|tonight|find|switch to the channel showing|watch)(?:\s+(.*))?$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)),
+    };
+    if let Some(caps) = re.captures(cmd) {
+        return handle_guide_request(caps.get(1).map_or("", |c| c.as_str()).to_lowercase().as_str(), caps.get(2).map_or("", |c| c.as_str()), tv.as_str(), &channels, db).await;
+    }
+    let backend = match devices::get_backend(tv.as_str(), db).await {
+        Ok(Some(b)) => b,
+        Ok(None) => return no_tv_message(tv.as_str()),
+        Err(e) => return error::report(&e),
+    };
+    return run_command(cmd, tv.as_str(), backend.as_ref(), &channels).await;
+}
+
+// Recognizes "at 20:00 {command}" and "{command} in 45m", returning the
+// command and when to run it
+fn parse_schedule(cmd: &str) -> Result<Option<(String, i64)>, Error> {
+    let at = match Regex::new(r"(?i)^at\s+(\d{1,2}:\d{2})\s+(.+)$") {
+        Ok(r) => r,
+        Err(e) => return Err(Error::Internal(format!("ERROR: {}", e))),
+    };
+    if let Some(caps) = at.captures(cmd) {
+        let run_at = schedule::next_occurrence(caps.get(1).map_or("", |c| c.as_str()))?;
+        return Ok(Some((caps.get(2).map_or("", |c| c.as_str()).trim().to_string(), run_at)));
+    }
+    let delay = match Regex::new(r"(?i)^(.+?)\s+in\s+(\d+)\s*(m|min|mins|minutes|h|hour|hours)$") {
+        Ok(r) => r,
+        Err(e) => return Err(Error::Internal(format!("ERROR: {}", e))),
+    };
+    if let Some(caps) = delay.captures(cmd) {
+        let run_at = schedule::after_delay(caps.get(2).map_or("", |c| c.as_str()), caps.get(3).map_or("", |c| c.as_str()))?;
+        return Ok(Some((caps.get(1).map_or("", |c| c.as_str()).trim().to_string(), run_at)));
+    }
+    return Ok(None);
+}
+
+async fn handle_schedule_request(command: &str, run_at: i64, tv: &str, sender: &str, room: &str, channels: &[lineup::Channel], db: Box<db::Homechatbotdb>) -> String {
+    let action = match parse_action(command, channels) {
+        Ok(a) => a,
+        Err(e) => return error::report(&e),
+    };
+    let item = schedule::ScheduledAction{
+        schedid: 0,
+        tv: tv.to_string(),
+        command: command.to_string(),
+        run_at: run_at,
+        room: room.to_string(),
+        requested_by: sender.to_string(),
+    };
+    match schedule::add_action(item, db).await {
+        Ok(id) => return format!("({}) Will {} at {}", id, describe_action(&action), schedule::format_time(run_at)),
+        Err(e) => return error::report(&e),
+    };
+}
+
+// Runs the scheduled commands which are due. Returns the rooms to notify
+// with the outcome of each command.
+pub async fn run_scheduled_actions(db: Box<db::Homechatbotdb>) -> Result<Vec<(String, String)>, Error> {
+    let due = schedule::take_due_actions(db.clone()).await?;
+    let mut results : Vec<(String, String)> = vec![];
+    for item in due {
+        let channels = lineup::get_lineup(item.tv.as_str(), db.clone()).await?;
+        let msg = match devices::get_backend(item.tv.as_str(), db.clone()).await {
+            Ok(Some(b)) => run_command(item.command.as_str(), item.tv.as_str(), b.as_ref(), &channels).await,
+            Ok(None) => no_tv_message(item.tv.as_str()),
+            Err(e) => error::report(&e),
+        };
+        events::publish("tv.scheduled_action", serde_json::json!({"id": item.schedid, "tv": item.tv, "command": item.command, "requested_by": item.requested_by, "result": msg}));
+        results.push((item.room.clone(), format!("Scheduled \"{}\": {}", item.command, msg)));
+    }
+    return Ok(results);
+}
+
+async fn handle_guide_request(action: &str, arg: &str, tv: &str, channels: &[lineup::Channel], db: Box<db::Homechatbotdb>) -> String {
+    let guide = match epg::get_guide(db.clone()).await {
+        Ok(g) => g,
+        Err(e) => return error::report(&e),
+    };
+    if action == "now" {
+        return epg::now_request(&guide, channels, epg::now());
+    } else if action == "tonight" {
+        return epg::tonight_request(&guide, channels, epg::now());
+    } else if arg.trim() == "" {
+        return String::from(BGCHAN_HELP);
+    } else if action == "next" {
+        return match epg::next_request(arg, &guide, channels, epg::now()) {
+            Ok(msg) => msg,
+            Err(e) => error::report(&e),
+        };
+    } else if action == "find" {
+        return epg::find_request(arg, &guide, channels, epg::now());
+    }
+    // Switching to whatever is showing the title right now
+    let (chan, title) = match epg::channel_showing(arg, &guide, channels, epg::now()) {
+        Ok(c) => c,
+        Err(e) => return error::report(&e),
+    };
+    let backend = match devices::get_backend(tv, db).await {
+        Ok(Some(b)) => b,
+        Ok(None) => return no_tv_message(tv),
+        Err(e) => return error::report(&e),
+    };
+    match backend.set_channel(chan.number).await {
+        Ok(_) => return format!("Switched to {} ({}) for {}", chan.name, chan.number, title),
+        Err(e) => return report_tv_error(&e),
+    };
+}
+
+// Like error::report, but an unreachable TV is not blamed on the database
+fn report_tv_error(e: &Error) -> String {
+    let msg = error::report(e);
+    if let Error::ConnectionLost(_) = e {
+        return String::from("The TV is not reachable at the moment");
+    }
+    return msg;
+}
+
+fn no_tv_message(tv: &str) -> String {
+    return format!("TV \"{}\" is not configured, add it with \"tv device add {} {{url}}\"", tv, tv);
+}
+
+// A single remote-control action, parsed from the chat so that scheduled
+// commands can be checked when they are scheduled and not when they run
+#[derive(Debug, Clone)]
+pub enum TvAction {
+    Power(bool),
+    ChannelUp,
+    ChannelDown,
+    Channel(u32, Option<String>),
+    Volume(u32),
+    VolumeUp,
+    VolumeDown,
+    Input(String),
+}
+
+pub fn parse_action(cmd: &str, channels: &[lineup::Channel]) -> Result<TvAction, Error> {
+    let cmd = cmd.trim();
+    let (action, arg) = cmd.split_once(char::is_whitespace).unwrap_or((cmd, ""));
+    let action = action.to_lowercase();
+    let arg = arg.trim();
+    if action == "on" {
+        return Ok(TvAction::Power(true));
+    } else if action == "off" {
+        return Ok(TvAction::Power(false));
+    } else if action == "up" {
+        return Ok(TvAction::ChannelUp);
+    } else if action == "down" {
+        return Ok(TvAction::ChannelDown);
+    } else if action == "vol" || action == "volume" {
+        if arg == "up" {
+            return Ok(TvAction::VolumeUp);
+        } else if arg == "down" {
+            return Ok(TvAction::VolumeDown);
+        }
+        match arg.parse::<u32>() {
+            Ok(l) if l <= MAX_VOLUME => return Ok(TvAction::Volume(l)),
+            _ => return Err(Error::InvalidInput(format!("Volume must be up, down or a number between 0 and {}", MAX_VOLUME))),
+        };
+    } else if action == "input" {
+        if arg == "" {
+            return Err(Error::InvalidInput(String::from(BGCHAN_HELP)));
+        }
+        return Ok(TvAction::Input(arg.to_string()));
+    }
+    // Anything else is a channel number or a name from the lineup
+    if let Ok(n) = action.parse::<u32>() {
+        return Ok(TvAction::Channel(n, None));
+    }
+    match lineup::find_channel(cmd, channels) {
+        Ok(c) => return Ok(TvAction::Channel(c.number, Some(c.name.clone()))),
+        Err(Error::NotFound(_)) if channels.len() == 0 => return Err(Error::InvalidInput(String::from(BGCHAN_HELP))),
+        Err(e) => return Err(e),
+    };
+}
+
+pub fn describe_action(action: &TvAction) -> String {
+    match action {
+        TvAction::Power(true) => return String::from("turn on"),
+        TvAction::Power(false) => return String::from("turn off"),
+        TvAction::ChannelUp => return String::from("channel up"),
+        TvAction::ChannelDown => return String::from("channel down"),
+        TvAction::Channel(n, Some(name)) => return format!("switch to {} ({})", name, n),
+        TvAction::Channel(n, None) => return format!("switch to channel {}", n),
+        TvAction::Volume(l) => return format!("set volume to {}", l),
+        TvAction::VolumeUp => return String::from("volume up"),
+        TvAction::VolumeDown => return String::from("volume down"),
+        TvAction::Input(i) => return format!("switch input to {}", i),
+    };
+}
+
+fn publish_action(device: &str, action: &TvAction) {
+    match action {
+        TvAction::Channel(n, name) => events::publish("tv.switched", serde_json::json!({"tv": device, "channel": n, "name": name})),
+        TvAction::Power(on) => events::publish("tv.power", serde_json::json!({"tv": device, "on": on})),
+        _ => events::publish("tv.action", serde_json::json!({"tv": device, "action": describe_action(action)})),
+    };
+}
+
+pub async fn execute_action(action: &TvAction, tv: &dyn TvBackend) -> Result<String, Error> {
+    return match action {
+        TvAction::Power(on) => tv.power(*on).await.map(|_| String::from(if *on { "TV turned on" } else { "TV turned off" })),
+        TvAction::ChannelUp => tv.channel_up().await.map(|_| String::from("Channel up")),
+        TvAction::ChannelDown => tv.channel_down().await.map(|_| String::from("Channel down")),
+        TvAction::Channel(n, Some(name)) => tv.set_channel(*n).await.map(|_| format!("Switched to {} ({})", name, n)),
+        TvAction::Channel(n, None) => tv.set_channel(*n).await.map(|_| format!("Switched to channel {}", n)),
+        TvAction::Volume(l) => tv.set_volume(*l).await.map(|_| format!("Volume set to {}", l)),
+        TvAction::VolumeUp => tv.volume_up().await.map(|_| String::from("Volume up")),
+        TvAction::VolumeDown => tv.volume_down().await.map(|_| String::from("Volume down")),
+        TvAction::Input(i) => tv.set_input(i).await.map(|_| format!("Input switched to {}", i)),
+    };
+}
+
+// device is the TV's name, only used to tell event subscribers which one
+pub async fn run_command(cmd: &str, device: &str, tv: &dyn TvBackend, channels: &[lineup::Channel]) -> String {
+    let action = match parse_action(cmd, channels) {
+        Ok(a) => a,
+        Err(e) => return error::report(&e),
+    };
+    match execute_action(&action, tv).await {
+        Ok(msg) => {
+            publish_action(device, &action);
+            return msg;
+        },
+        Err(e) => return report_tv_error(&e),
+    };
+}
@@ -0,0 +1,134 @@
+use crate::db;
+use crate::error::{self, Error};
+use serde::{Deserialize, Serialize};
+use mongodb::bson::{doc, to_document};
+use chrono::{Duration, Local, NaiveTime, TimeZone, Utc};
+
+pub const SCHEDULE_COLLECTION_NAME : &str = "tv_schedule";
+const MAX_ITEMS_IN_DB : u32 = 1000;
+// Sleep timers longer than a day are most likely typos
+const MAX_DELAY_MINUTES : i64 = 24 * 60;
+
+// A TV command waiting to be run. The command is kept as typed, so the
+// lineup is looked up again when it runs; room is where the result goes.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScheduledAction {
+    pub schedid: u32,
+    pub tv: String,
+    pub command: String,
+    pub run_at: i64,
+    pub room: String,
+    pub requested_by: String,
+}
+
+pub async fn move_schedules(from: &str, to: &str, db: Box<db::Homechatbotdb>) -> Result<(), Error> {
+    return db.update_data(SCHEDULE_COLLECTION_NAME, doc!{"tv": from}, doc!{"tv": to}).await;
+}
+
+// Next occurrence of "HH:MM" in local time, today or tomorrow
+pub fn next_occurrence(hhmm: &str) -> Result<i64, Error> {
+    let t = match NaiveTime::parse_from_str(hhmm.trim(), "%H:%M") {
+        Ok(t) => t,
+        Err(e) => return Err(Error::InvalidInput(format!("Invalid time \"{}\", expected HH:MM: {}", hhmm.trim(), e))),
+    };
+    let now = Local::now();
+    let mut at = match Local.from_local_datetime(&now.date().naive_local().and_time(t)).earliest() {
+        Some(a) => a,
+        None => return Err(Error::InvalidInput(format!("{} does not exist today", hhmm.trim()))),
+    };
+    if at <= now {
+        at = at + Duration::days(1);
+    }
+    return Ok(at.timestamp());
+}
+
+// "45m", "45 min" or "2h" from now
+pub fn after_delay(amount: &str, unit: &str) -> Result<i64, Error> {
+    let amount = match amount.parse::<i64>() {
+        Ok(a) if a > 0 => a,
+        _ => return Err(Error::InvalidInput(format!("Invalid delay \"{}\"", amount))),
+    };
+    let minutes = if unit.to_lowercase().starts_with('h') { amount * 60 } else { amount };
+    if minutes > MAX_DELAY_MINUTES {
+        return Err(Error::InvalidInput(String::from("Delays are limited to 24 hours, use \"at HH:MM\" instead")));
+    }
+    return Ok((Utc::now() + Duration::minutes(minutes)).timestamp());
+}
+
+pub fn format_time(ts: i64) -> String {
+    let at = Local.timestamp(ts, 0);
+    if at.date() == Local::now().date() {
+        return at.format("%H:%M").to_string();
+    }
+    return at.format("%a %H:%M").to_string();
+}
+
+pub async fn add_action(action: ScheduledAction, db: Box<db::Homechatbotdb>) -> Result<u32, Error> {
+    loop {
+        let id = get_smallest_available_id(db.clone()).await?;
+        let item = ScheduledAction{schedid: id, ..action.clone()};
+        let d = match to_document(&item) {
+            Ok(d) => d,
+            Err(e) => return Err(Error::Internal(format!("Unable to serialize scheduled action: {}", e))),
+        };
+        match db.insert_data_to_collection(SCHEDULE_COLLECTION_NAME, vec![d]).await {
+            Ok(_) => return Ok(id),
+            Err(Error::Duplicate(_)) => continue,
+            Err(e) => return Err(e),
+        };
+    }
+}
+
+// Removes and returns everything that is due. Removing first means a
+// failing TV is not retried every few seconds until someone notices.
+pub async fn take_due_actions(db: Box<db::Homechatbotdb>) -> Result<Vec<ScheduledAction>, Error> {
+    let now = Utc::now().timestamp();
+    let items = db.get_generic_data_collection::<ScheduledAction>(SCHEDULE_COLLECTION_NAME, doc!{"run_at": {"$lte": now}}, doc!{"run_at": 1}).await?;
+    if items.len() > 0 {
+        let ids : Vec<u32> = items.iter().map(|i| i.schedid).collect();
+        db.remove_data(SCHEDULE_COLLECTION_NAME, doc!{"schedid": {"$in": ids}}).await?;
+    }
+    return Ok(items);
+}
+
+pub async fn handle_schedule_command(cmd: &str, db: Box<db::Homechatbotdb>) -> String {
+    let cmd = cmd.trim();
+    if cmd == "" || cmd == "list" {
+        let items = match db.get_generic_data_collection::<ScheduledAction>(SCHEDULE_COLLECTION_NAME, doc!{}, doc!{"run_at": 1}).await {
+            Ok(i) => i,
+            Err(e) => return error::report(&e),
+        };
+        if items.len() == 0 {
+            return String::from("Nothing scheduled");
+        }
+        let mut msg = "".to_string();
+        for i in items {
+            msg = format!("{}({}) {} @{}: {} (by {})\n", msg, i.schedid, format_time(i.run_at), i.tv, i.command, i.requested_by);
+        }
+        return msg;
+    }
+    let id = match cmd.strip_prefix("cancel").map(|r| r.trim().parse::<u32>()) {
+        Some(Ok(id)) => id,
+        _ => return String::from("Usage: schedule [list|cancel {id}]"),
+    };
+    match db.get_generic_data_collection::<ScheduledAction>(SCHEDULE_COLLECTION_NAME, doc!{"schedid": id}, doc!{}).await {
+        Ok(items) if items.len() == 0 => return format!("Scheduled action {} not found", id),
+        Ok(_) => {},
+        Err(e) => return error::report(&e),
+    };
+    match db.remove_data(SCHEDULE_COLLECTION_NAME, doc!{"schedid": id}).await {
+        Ok(_) => return format!("Scheduled action {} cancelled", id),
+        Err(e) => return error::report(&e),
+    };
+}
+
+async fn get_smallest_available_id(db: Box<db::Homechatbotdb>) -> Result<u32, Error> {
+    let items = db.get_generic_data_collection::<ScheduledAction>(SCHEDULE_COLLECTION_NAME, doc!{}, doc!{}).await?;
+    let bufv : Vec<u32> = items.iter().map(|i| i.schedid).collect();
+    for n in 1..MAX_ITEMS_IN_DB {
+        if !bufv.contains(&n) {
+            return Ok(n);
+        }
+    }
+    return Err(Error::InvalidInput(String::from("Too many scheduled TV actions")))
+}
@@ -0,0 +1,195 @@
+use crate::db;
+use crate::error::Error;
+use crate::migrate;
+use crate::registry::Registry;
+use crate::reply::Reply;
+use std::io::Write;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+// Who the commands come from and where, as far as the modules can tell.
+// Admin commands need --sender to be one of the admin_users.
+const DEFAULT_SENDER : &str = "@cli:localhost";
+const DEFAULT_ROOM : &str = "!cli:localhost";
+const REPL_PROMPT : &str = "> ";
+const CLI_USAGE : &str = "Usage:
+    home-chatbot cli [--sender {user_id}] [--room {room_id}] [command]...
+    home-chatbot repl [--sender {user_id}] [--room {room_id}]
+    home-chatbot migrate [--dry-run]
+Each argument of cli is one command, \\n in it starts a new line. Without
+commands, standard input is read as one command. In the repl, a line ending
+with \\ continues on the next line; quit or Ctrl-D leaves. migrate sets up
+the database schema, which the bot otherwise does when it starts.";
+
+#[derive(PartialEq)]
+pub enum Mode {
+    Cli,
+    Repl,
+    Migrate{dry_run: bool},
+}
+
+pub struct Options {
+    pub mode: Mode,
+    pub sender: String,
+    pub room: String,
+    pub commands: Vec<String>,
+}
+
+// args are the program arguments after the program name, starting with
+// "cli", "repl" or "migrate". Returns None when the bot should run normally.
+pub fn parse_args(args: &[String]) -> Option<Result<Options, String>> {
+    let mode = match args.get(0).map(|a| a.as_str()) {
+        Some("cli") => Mode::Cli,
+        Some("repl") => Mode::Repl,
+        Some("migrate") => Mode::Migrate{dry_run: false},
+        _ => return None,
+    };
+    let mut opts = Options{mode: mode, sender: String::from(DEFAULT_SENDER), room: String::from(DEFAULT_ROOM), commands: vec![]};
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        if let Mode::Migrate{dry_run} = &mut opts.mode {
+            if arg != "--dry-run" {
+                return Some(Err(format!("Unknown option {}\n{}", arg, CLI_USAGE)));
+            }
+            *dry_run = true;
+        } else if arg == "--sender" || arg == "--room" {
+            let value = match rest.next() {
+                Some(v) => v.clone(),
+                None => return Some(Err(format!("{} needs a value\n{}", arg, CLI_USAGE))),
+            };
+            if arg == "--sender" {
+                opts.sender = value;
+            } else {
+                opts.room = value;
+            }
+        } else if arg == "--help" || arg == "-h" {
+            return Some(Err(String::from(CLI_USAGE)));
+        } else if opts.mode == Mode::Repl {
+            return Some(Err(format!("repl takes no commands\n{}", CLI_USAGE)));
+        } else {
+            opts.commands.push(arg.replace("\\n", "\n"));
+        }
+    }
+    return Some(Ok(opts));
+}
+
+// Prints a reply the way it would show up in the room. Files are written
+// to the current directory. Returns false if the command was not handled.
+fn print_reply(reply: Reply) -> bool {
+    match reply {
+        Reply::Text(msg) => println!("{}", msg),
+        Reply::Unknown => {
+            eprintln!("UNKNOWN");
+            return false;
+        },
+        Reply::File{name, content_type: _, data} => {
+            // The name comes from the module, but never write outside of here
+            let name = match Path::new(name.as_str()).file_name() {
+                Some(n) => n.to_owned(),
+                None => {
+                    eprintln!("Invalid file name \"{}\"", name);
+                    return false;
+                },
+            };
+            match std::fs::write(&name, &data) {
+                Ok(_) => println!("Wrote {} ({} bytes)", name.to_string_lossy(), data.len()),
+                Err(e) => {
+                    eprintln!("Unable to write {}: {}", name.to_string_lossy(), e);
+                    return false;
+                },
+            };
+        },
+    };
+    return true;
+}
+
+async fn run_one(cmd: String, opts: &Options, registry: &Registry, db: Box<db::Homechatbotdb>) -> bool {
+    // Nobody else can read the terminal
+    let reply = crate::message_triage(cmd, opts.sender.as_str(), opts.room.as_str(), true, registry, db).await;
+    return print_reply(reply);
+}
+
+async fn run_repl(opts: &Options, registry: &Registry, db: Box<db::Homechatbotdb>) -> Result<(), Error> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut pending = "".to_string();
+    loop {
+        print!("{}", REPL_PROMPT);
+        let _ = std::io::stdout().flush();
+        let line = match lines.next_line().await {
+            Ok(Some(l)) => l,
+            Ok(None) => {
+                println!();
+                return Ok(());
+            },
+            Err(e) => return Err(Error::Internal(format!("Unable to read standard input: {}", e))),
+        };
+        if let Some(start) = line.strip_suffix('\\') {
+            pending = format!("{}{}\n", pending, start);
+            continue;
+        }
+        let cmd = format!("{}{}", pending, line);
+        pending = "".to_string();
+        let trimmed = cmd.trim();
+        if trimmed == "" {
+            continue;
+        } else if trimmed == "quit" || trimmed == "exit" {
+            return Ok(());
+        }
+        run_one(cmd, opts, registry, db.clone()).await;
+    }
+}
+
+// Runs the commands without Matrix. Returns the process exit code.
+pub async fn run(opts: Options, registry: &Registry, db: Box<db::Homechatbotdb>) -> i32 {
+    if let Mode::Migrate{dry_run} = opts.mode {
+        match migrate::run(db, dry_run).await {
+            Ok(steps) if steps.len() == 0 => println!("The database is up to date"),
+            Ok(steps) => {
+                let verb = if dry_run { "Would run" } else { "Done" };
+                for s in steps {
+                    println!("{}: {}", verb, s);
+                }
+            },
+            Err(e) => {
+                eprintln!("Migration failed: {}", e);
+                return 1;
+            },
+        };
+        return 0;
+    }
+    // Commands expect the schema the bot sets up when it starts
+    match migrate::run(db.clone(), false).await {
+        Ok(_) => {},
+        Err(e) => {
+            eprintln!("Migration failed: {}", e);
+            return 1;
+        },
+    };
+    if opts.mode == Mode::Repl {
+        match run_repl(&opts, registry, db).await {
+            Ok(_) => return 0,
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            },
+        };
+    }
+    let mut commands = opts.commands.clone();
+    if commands.len() == 0 {
+        let mut input = "".to_string();
+        match tokio::io::stdin().read_to_string(&mut input).await {
+            Ok(_) => commands.push(input.trim_end().to_string()),
+            Err(e) => {
+                eprintln!("Unable to read standard input: {}", e);
+                return 1;
+            },
+        };
+    }
+    let mut code = 0;
+    for cmd in commands {
+        if !run_one(cmd, &opts, registry, db.clone()).await {
+            code = 1;
+        }
+    }
+    return code;
+}
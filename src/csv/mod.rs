@@ -0,0 +1,40 @@
+// The bits of CSV the exports and imports need
+
+// Quotes a field when it would otherwise break the line apart
+pub fn field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') {
+        return format!("\"{}\"", field.replace("\"", "\"\""));
+    }
+    return field.to_string();
+}
+
+// Splits one CSV line into fields, honouring double quoted fields
+pub fn parse_line(line: &str) -> Vec<String> {
+    let mut fields : Vec<String> = vec![];
+    let mut cur = "".to_string();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            if c == '"' {
+                if chars.peek() == Some(&'"') {
+                    cur.push('"');
+                    chars.next();
+                } else {
+                    in_quotes = false;
+                }
+            } else {
+                cur.push(c);
+            }
+        } else if c == '"' {
+            in_quotes = true;
+        } else if c == ',' {
+            fields.push(cur.trim().to_string());
+            cur = "".to_string();
+        } else {
+            cur.push(c);
+        }
+    }
+    fields.push(cur.trim().to_string());
+    return fields;
+}
@@ -0,0 +1,263 @@
+use crate::db::Storage;
+use crate::error::Error;
+use async_trait::async_trait;
+use mongodb::bson::{oid::ObjectId, Bson, Document};
+use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+
+// An in-process stand-in for the database, so tests run without MongoDB.
+// It understands the subset of queries the modules use: equality, $exists,
+// $in, $ne, $lt, $lte, $gt, $gte and $regex, plus sorting and unique indexes.
+#[derive(Debug, Default)]
+pub struct Store {
+    collections: BTreeMap<String, Collection>,
+}
+
+#[derive(Debug, Default)]
+struct Collection {
+    docs: Vec<Document>,
+    // Fields with a unique index
+    unique: Vec<String>,
+}
+
+fn as_number(v: &Bson) -> Option<f64> {
+    match v {
+        Bson::Int32(i) => return Some(*i as f64),
+        Bson::Int64(i) => return Some(*i as f64),
+        Bson::Double(d) => return Some(*d),
+        _ => return None,
+    };
+}
+
+fn compare(a: &Bson, b: &Bson) -> Option<Ordering> {
+    if let (Some(x), Some(y)) = (as_number(a), as_number(b)) {
+        return x.partial_cmp(&y);
+    }
+    match (a, b) {
+        (Bson::String(x), Bson::String(y)) => return Some(x.cmp(y)),
+        (Bson::Boolean(x), Bson::Boolean(y)) => return Some(x.cmp(y)),
+        (Bson::Null, Bson::Null) => return Some(Ordering::Equal),
+        _ => return if a == b { Some(Ordering::Equal) } else { None },
+    };
+}
+
+// A missing field equals null, and an array equals any of its elements
+fn equals(value: Option<&Bson>, cond: &Bson) -> bool {
+    match value {
+        None | Some(Bson::Null) => return *cond == Bson::Null,
+        Some(Bson::Array(items)) if !matches!(cond, Bson::Array(_)) => return items.iter().any(|i| equals(Some(i), cond)),
+        Some(v) => return compare(v, cond) == Some(Ordering::Equal),
+    };
+}
+
+fn check_operator(value: Option<&Bson>, op: &str, arg: &Bson) -> Result<bool, Error> {
+    let ordered = |want: &[Ordering]| match value {
+        Some(v) => compare(v, arg).map_or(false, |o| want.contains(&o)),
+        None => false,
+    };
+    match op {
+        "$exists" => return Ok(value.is_some() == matches!(arg, Bson::Boolean(true))),
+        "$ne" => return Ok(!equals(value, arg)),
+        "$in" => match arg {
+            Bson::Array(items) => return Ok(items.iter().any(|i| equals(value, i))),
+            _ => return Err(Error::InvalidInput(String::from("$in needs an array"))),
+        },
+        "$lt" => return Ok(ordered(&[Ordering::Less])),
+        "$lte" => return Ok(ordered(&[Ordering::Less, Ordering::Equal])),
+        "$gt" => return Ok(ordered(&[Ordering::Greater])),
+        "$gte" => return Ok(ordered(&[Ordering::Greater, Ordering::Equal])),
+        "$regex" => {
+            let re = match arg {
+                Bson::String(s) => match Regex::new(s.as_str()) {
+                    Ok(r) => r,
+                    Err(e) => return Err(Error::InvalidInput(format!("Invalid regex: {}", e))),
+                },
+                _ => return Err(Error::InvalidInput(String::from("$regex needs a string"))),
+            };
+            return Ok(match value {
+                Some(Bson::String(s)) => re.is_match(s.as_str()),
+                _ => false,
+            });
+        },
+        _ => return Err(Error::InvalidInput(format!("Operator {} is not supported", op))),
+    };
+}
+
+fn matches(doc: &Document, filter: &Document) -> Result<bool, Error> {
+    for (key, cond) in filter.iter() {
+        let value = doc.get(key);
+        let ok = match cond {
+            Bson::Document(ops) if ops.keys().next().map_or(false, |k| k.starts_with('$')) => {
+                let mut ok = true;
+                for (op, arg) in ops.iter() {
+                    ok = ok && check_operator(value, op.as_str(), arg)?;
+                }
+                ok
+            },
+            c => equals(value, c),
+        };
+        if !ok {
+            return Ok(false);
+        }
+    }
+    return Ok(true);
+}
+
+impl Store {
+    pub fn collection_names(&self) -> Vec<String> {
+        return self.collections.keys().cloned().collect();
+    }
+
+    pub fn create_collection(&mut self, name: &str) {
+        self.collections.entry(name.to_string()).or_default();
+    }
+
+    pub fn index_names(&self, name: &str) -> Vec<String> {
+        let mut names = vec![String::from("_id_")];
+        if let Some(c) = self.collections.get(name) {
+            names.extend(c.unique.iter().map(|u| format!("{}_1", u)));
+        }
+        return names;
+    }
+
+    pub fn create_unique_index(&mut self, name: &str, field: &str) {
+        let coll = self.collections.entry(name.to_string()).or_default();
+        if !coll.unique.iter().any(|u| u == field) {
+            coll.unique.push(field.to_string());
+        }
+    }
+
+    pub fn find(&self, name: &str, filter: &Document, sort: &Document) -> Result<Vec<Document>, Error> {
+        let coll = match self.collections.get(name) {
+            Some(c) => c,
+            None => return Ok(vec![]),
+        };
+        let mut found : Vec<Document> = vec![];
+        for d in coll.docs.iter() {
+            if matches(d, filter)? {
+                found.push(d.clone());
+            }
+        }
+        found.sort_by(|a, b| {
+            for (key, dir) in sort.iter() {
+                // Missing fields sort first, like null does
+                let ord = match (a.get(key), b.get(key)) {
+                    (Some(x), Some(y)) => compare(x, y).unwrap_or(Ordering::Equal),
+                    (None, Some(_)) => Ordering::Less,
+                    (Some(_), None) => Ordering::Greater,
+                    (None, None) => Ordering::Equal,
+                };
+                let ord = if as_number(dir).map_or(false, |d| d < 0.0) { ord.reverse() } else { ord };
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            return Ordering::Equal;
+        });
+        return Ok(found);
+    }
+
+    // Inserts in order and stops at the first duplicate, like insert_many
+    pub fn insert(&mut self, name: &str, docs: Vec<Document>) -> Result<(), Error> {
+        let coll = self.collections.entry(name.to_string()).or_default();
+        for mut d in docs {
+            for field in coll.unique.iter() {
+                let value = d.get(field).cloned().unwrap_or(Bson::Null);
+                if coll.docs.iter().any(|e| equals(e.get(field), &value)) {
+                    return Err(Error::Duplicate(format!("Duplicate {} in {}", field, name)));
+                }
+            }
+            if !d.contains_key("_id") {
+                d.insert("_id", ObjectId::new());
+            }
+            coll.docs.push(d);
+        }
+        return Ok(());
+    }
+
+    pub fn remove(&mut self, name: &str, filter: &Document) -> Result<(), Error> {
+        let coll = match self.collections.get_mut(name) {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let mut kept : Vec<Document> = vec![];
+        for d in coll.docs.drain(..) {
+            if !matches(&d, filter)? {
+                kept.push(d);
+            }
+        }
+        coll.docs = kept;
+        return Ok(());
+    }
+
+    // Sets the given fields on every matching document, like $set
+    pub fn update(&mut self, name: &str, filter: &Document, set: &Document) -> Result<(), Error> {
+        let coll = match self.collections.get_mut(name) {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        for d in coll.docs.iter_mut() {
+            if matches(d, filter)? {
+                for (k, v) in set.iter() {
+                    d.insert(k.clone(), v.clone());
+                }
+            }
+        }
+        return Ok(());
+    }
+}
+
+// The store behind a lock, as the storage of Homechatbotdb::in_memory
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    store: Mutex<Store>,
+}
+
+impl MemoryStorage {
+    fn lock(&self) -> MutexGuard<'_, Store> {
+        return self.store.lock().unwrap_or_else(|e| e.into_inner());
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn collection_names(&self) -> Result<Vec<String>, Error> {
+        return Ok(self.lock().collection_names());
+    }
+
+    async fn create_collection(&self, name: &str) -> Result<(), Error> {
+        self.lock().create_collection(name);
+        return Ok(());
+    }
+
+    async fn index_names(&self, name: &str) -> Result<Vec<String>, Error> {
+        return Ok(self.lock().index_names(name));
+    }
+
+    async fn create_unique_index(&self, name: &str, field: &str) -> Result<(), Error> {
+        self.lock().create_unique_index(name, field);
+        return Ok(());
+    }
+
+    async fn find(&self, name: &str, filter: Document, sort: Document) -> Result<Vec<Document>, Error> {
+        return self.lock().find(name, &filter, &sort);
+    }
+
+    async fn insert(&self, name: &str, docs: Vec<Document>) -> Result<(), Error> {
+        return self.lock().insert(name, docs);
+    }
+
+    async fn remove(&self, name: &str, filter: Document) -> Result<(), Error> {
+        return self.lock().remove(name, &filter);
+    }
+
+    async fn update(&self, name: &str, filter: Document, set: Document) -> Result<(), Error> {
+        return self.lock().update(name, &filter, &set);
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        return Ok(());
+    }
+}
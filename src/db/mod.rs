@@ -1,14 +1,40 @@
-use mongodb::{Client, options::ClientOptions, IndexModel, options::IndexOptions, options::FindOptions};
+use mongodb::Client;
 use mongodb::bson::{doc, Document};
 use serde::{Deserialize, Serialize};
-use futures::stream::{StreamExt, TryStreamExt};
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Instant;
+use crate::metrics;
+use crate::error::Error;
 
-const DB_NAME : &str = "homechatbot_db";
-const CONFIG_COLLECTION_NAME : &str = "config";
+#[cfg(test)]
+mod memory;
+mod mongo;
+mod settings;
+
+pub use settings::Settings;
+pub const CONFIG_COLLECTION_NAME : &str = "config";
+
+// Where the documents are kept: MongoDB, or memory for the tests. Everything
+// above this, the metrics and turning documents into types included, is the
+// same for both.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn collection_names(&self) -> Result<Vec<String>, Error>;
+    async fn create_collection(&self, name: &str) -> Result<(), Error>;
+    async fn index_names(&self, name: &str) -> Result<Vec<String>, Error>;
+    async fn create_unique_index(&self, name: &str, field: &str) -> Result<(), Error>;
+    async fn find(&self, name: &str, filter: Document, sort: Document) -> Result<Vec<Document>, Error>;
+    async fn insert(&self, name: &str, docs: Vec<Document>) -> Result<(), Error>;
+    async fn remove(&self, name: &str, filter: Document) -> Result<(), Error>;
+    // Sets the fields of set on every matching document
+    async fn update(&self, name: &str, filter: Document, set: Document) -> Result<(), Error>;
+    async fn ping(&self) -> Result<(), Error>;
+}
 
 #[derive(Clone)]
 pub struct Homechatbotdb {
-    client: Client,
+    storage: Arc<dyn Storage>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -17,154 +43,102 @@ struct AllowedUsers {
 }
 
 impl Homechatbotdb {
-    pub async fn new(address: String, username: String, password: String) -> Result<Homechatbotdb, String> {
-        let client_options = match ClientOptions::parse(format!("mongodb://{}:{}@{}/", username, password, address)).await {
-            Ok(co) => co,
-            Err(e) => return Err(String::from(format!("Unable tp create client options: {}", e))),
-        };
+    pub async fn new(settings: &Settings) -> Result<Homechatbotdb, Error> {
+        let client_options = settings.client_options().await?;
         let client = match Client::with_options(client_options) {
             Ok(c) => c,
-            Err(e) => return Err(String::from(format!("Unable to create DB client: {}", e))),
+            Err(e) => return Err(Error::from_mongo("Unable to create DB client", e)),
         };
-        let hmcb = Homechatbotdb{client: client};
-        match hmcb.check_db_exists(DB_NAME).await {
-            Ok(r) => {
-                if !r {
-                    return Err(String::from(format!("Homechatbot DB \"{}\" not found", DB_NAME)))
-                }
-            },
-            Err(e) => return Err(String::from(format!("{}", e))),
-        };
-        match hmcb.check_collection_exists(CONFIG_COLLECTION_NAME).await {
-            Ok(r) => {
-                if !r {
-                    return Err(String::from(format!("Homechatbot config collection \"{}\" not found", CONFIG_COLLECTION_NAME)))
-                }
-            },
-            Err(e) => return Err(String::from(format!("{}", e))),
-        };
-        return Ok(hmcb);
+        // A new database and its collections are set up by the migrations
+        return Ok(Homechatbotdb{storage: Arc::new(mongo::MongoStorage::new(client, settings.database.as_str()))});
     }
 
-    async fn check_db_exists(&self, dbname: &str) -> Result<bool, String> {
-        let dbs = &self.client.list_database_names(None, None).await;
-        let dbs = match dbs {
-            Ok(d) => d,
-            Err(e) => return Err(String::from(format!("Unable to list databases: {}", e))),
-        };
-        let mut has_db = false;
-        for db in dbs {
-            if db == dbname {
-                has_db = true;
-                break;
-            }
-        }
-        return Ok(has_db);
+    // An empty database which lives as long as its clones
+    #[cfg(test)]
+    pub async fn in_memory() -> Result<Homechatbotdb, Error> {
+        let hmcb = Homechatbotdb{storage: Arc::new(memory::MemoryStorage::default())};
+        hmcb.create_collection(CONFIG_COLLECTION_NAME).await?;
+        return Ok(hmcb);
     }
 
-    pub async fn check_collection_exists(&self, coll_name: &str) -> Result<bool, String> {
-        let db = &self.client.database(DB_NAME);
-        let colls = match db.list_collection_names(None).await {
-            Ok(c) => c,
-            Err(e) => return Err(String::from(format!("Unable to list collections: {}", e))),
-        };
-        let mut has_coll = false;
-        for coll in colls {
-            if coll == coll_name {
-                has_coll = true;
-                break;
-            }
-        }
-        return Ok(has_coll);
+    pub async fn check_collection_exists(&self, coll_name: &str) -> Result<bool, Error> {
+        let colls = self.storage.collection_names().await?;
+        return Ok(colls.iter().any(|c| c == coll_name));
     }
 
-    pub async fn create_collection(&self, coll_name: &str) -> Result<(), String> {
-        let db = &self.client.database(DB_NAME);
-        match db.create_collection(coll_name, None).await {
-            Ok(_) => return Ok(()),
-            Err(e) => return Err(String::from(format!("Unable to create collection: {}", e))),
-        };
+    pub async fn create_collection(&self, coll_name: &str) -> Result<(), Error> {
+        return self.storage.create_collection(coll_name).await;
     }
 
-    pub async fn get_collection_index(&self, name: &str) -> Result<Vec<String>, String> {
-        let db = &self.client.database(DB_NAME);
-        let coll = db.collection::<Document>(name);
-        match coll.list_index_names().await {
-            Ok(i) => return Ok(i),
-            Err(e) => return Err(format!("Cannot get indexes: {}", e).to_string()),
-        };
+    pub async fn get_collection_index(&self, name: &str) -> Result<Vec<String>, Error> {
+        return self.storage.index_names(name).await;
     }
 
-    pub async fn create_collection_index(&self, coll_name: &str, index_name: &str) -> Result<(), String> {
-        let db = &self.client.database(DB_NAME);
-        let coll = db.collection::<Document>(coll_name);
-        let imo = IndexOptions::builder().unique(true).build();
-        let im = IndexModel::builder().keys(doc!{index_name:1}).options(imo).build();
-        match coll.create_index(im, None).await {
-            Ok(_) => return Ok(()),
-            Err(e) => return Err(format!("Cannot create index: {}", e).to_string()),
-        };
+    pub async fn create_collection_index(&self, coll_name: &str, index_name: &str) -> Result<(), Error> {
+        return self.storage.create_unique_index(coll_name, index_name).await;
     }
 
     pub async fn is_valid_inviting_user(&self, userid: &String) -> bool {
-        let db = &self.client.database(DB_NAME);
-        let typed_collection = db.collection::<AllowedUsers>(CONFIG_COLLECTION_NAME);
-        let filter = doc! {"allowed_users": {"$exists": true}};
-        let mut cursor = match typed_collection.find(filter, None).await {
-            Ok(c) => c,
+        match self.get_allowed_users().await {
+            Ok(users) => return users.contains(userid),
             Err(_) => return false,
         };
-        loop {
-            match cursor.next().await {
-                Some(v) => {
-                    let obj = match v {
-                        Ok(o) => o,
-                        Err(_) => continue,
-                    };
-                    for allu in obj.allowed_users {
-                        println!("Comparing inviting user \"{}\" with user in DB: \"{}\"", *userid, allu);
-                        if allu == *userid {
-                            return true;
-                        }
-                    }
-                },
-                None => break,
+    }
+
+    pub async fn get_allowed_users(&self) -> Result<Vec<String>, Error> {
+        let items = self.get_generic_data_collection::<AllowedUsers>(CONFIG_COLLECTION_NAME, doc! {"allowed_users": {"$exists": true}}, doc!{}).await?;
+        let mut users : Vec<String> = vec![];
+        for obj in items {
+            for allu in obj.allowed_users {
+                if !users.contains(&allu) {
+                    users.push(allu);
+                }
             }
         }
-        return false;
+        return Ok(users);
+    }
+
+    pub async fn ping(&self) -> Result<(), Error> {
+        let start = Instant::now();
+        let res = self.storage.ping().await;
+        metrics::record_db_operation(start.elapsed(), res.is_ok());
+        return res;
     }
 
-    pub async fn get_generic_data_collection<T>(&self, coll_name: &str, filter: Document, sort: Document) -> Result<Vec<T>, String>
+    pub async fn get_generic_data_collection<T>(&self, coll_name: &str, filter: Document, sort: Document) -> Result<Vec<T>, Error>
     where
     for<'de> T: Deserialize<'de> + Sync + Unpin + Send {
-        let db = &self.client.database(DB_NAME);
-        let coll = db.collection::<T>(coll_name);
-        let filo = FindOptions::builder().sort(sort).build();
-        let cursor = match coll.find(filter, filo).await {
-            Ok(c) => c,
-            Err(e) => return Err(format!("Unable to get cursor: {}", e).to_string()),
-        };
-        match cursor.try_collect().await {
-            Ok(v) => return Ok(v),
-            Err(e) => return Err(format!("Unable to retrieve items: {}", e).to_string()),
+        let start = Instant::now();
+        let res = self.storage.find(coll_name, filter, sort).await;
+        metrics::record_db_operation(start.elapsed(), res.is_ok());
+        let mut items : Vec<T> = vec![];
+        for d in res? {
+            match mongodb::bson::from_document::<T>(d) {
+                Ok(i) => items.push(i),
+                Err(e) => return Err(Error::Internal(format!("Unable to retrieve items: {}", e))),
+            };
         }
+        return Ok(items);
     }
 
-    pub async fn insert_data_to_collection(&self, coll_name: &str, docs: Vec<Document>) -> Result<(), String> {
-        let db = &self.client.database(DB_NAME);
-        let coll = db.collection::<Document>(coll_name);
-        match coll.insert_many(docs, None).await {
-            Ok(_) => return Ok(()),
-            Err(e) => return Err(format!("Unable to insert items: {}", e)),
-        };
+    pub async fn insert_data_to_collection(&self, coll_name: &str, docs: Vec<Document>) -> Result<(), Error> {
+        let start = Instant::now();
+        let res = self.storage.insert(coll_name, docs).await;
+        metrics::record_db_operation(start.elapsed(), res.is_ok());
+        return res;
     }
 
-    pub async fn remove_data(&self, coll_name: &str, fltr: Document) -> Result<(), String> {
-        let db = &self.client.database(DB_NAME);
-        let coll = db.collection::<Document>(coll_name);
-        match coll.delete_many(fltr, None).await {
-            Ok(_) => return Ok(()),
-            Err(e) => return Err(format!("Unable to remove items: {}", e)),
-        };
+    pub async fn remove_data(&self, coll_name: &str, fltr: Document) -> Result<(), Error> {
+        let start = Instant::now();
+        let res = self.storage.remove(coll_name, fltr).await;
+        metrics::record_db_operation(start.elapsed(), res.is_ok());
+        return res;
+    }
+
+    pub async fn update_data(&self, coll_name: &str, fltr: Document, upd: Document) -> Result<(), Error> {
+        let start = Instant::now();
+        let res = self.storage.update(coll_name, fltr, upd).await;
+        metrics::record_db_operation(start.elapsed(), res.is_ok());
+        return res;
     }
-}
\ No newline at end of file
+}
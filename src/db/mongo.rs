@@ -0,0 +1,95 @@
+use crate::db::Storage;
+use crate::error::Error;
+use async_trait::async_trait;
+use mongodb::{Client, Database, IndexModel, options::IndexOptions, options::FindOptions};
+use mongodb::bson::{doc, Document};
+use futures::stream::TryStreamExt;
+
+// The real database
+pub struct MongoStorage {
+    client: Client,
+    db_name: String,
+}
+
+impl MongoStorage {
+    pub fn new(client: Client, db_name: &str) -> MongoStorage {
+        return MongoStorage{client: client, db_name: db_name.to_string()};
+    }
+
+    fn db(&self) -> Database {
+        return self.client.database(self.db_name.as_str());
+    }
+}
+
+#[async_trait]
+impl Storage for MongoStorage {
+    async fn collection_names(&self) -> Result<Vec<String>, Error> {
+        match self.db().list_collection_names(None).await {
+            Ok(c) => return Ok(c),
+            Err(e) => return Err(Error::from_mongo("Unable to list collections", e)),
+        };
+    }
+
+    async fn create_collection(&self, name: &str) -> Result<(), Error> {
+        match self.db().create_collection(name, None).await {
+            Ok(_) => return Ok(()),
+            Err(e) => return Err(Error::from_mongo("Unable to create collection", e)),
+        };
+    }
+
+    async fn index_names(&self, name: &str) -> Result<Vec<String>, Error> {
+        match self.db().collection::<Document>(name).list_index_names().await {
+            Ok(i) => return Ok(i),
+            Err(e) => return Err(Error::from_mongo("Cannot get indexes", e)),
+        };
+    }
+
+    async fn create_unique_index(&self, name: &str, field: &str) -> Result<(), Error> {
+        let imo = IndexOptions::builder().unique(true).build();
+        let im = IndexModel::builder().keys(doc!{field:1}).options(imo).build();
+        match self.db().collection::<Document>(name).create_index(im, None).await {
+            Ok(_) => return Ok(()),
+            Err(e) => return Err(Error::from_mongo("Cannot create index", e)),
+        };
+    }
+
+    async fn find(&self, name: &str, filter: Document, sort: Document) -> Result<Vec<Document>, Error> {
+        let filo = FindOptions::builder().sort(sort).build();
+        let cursor = match self.db().collection::<Document>(name).find(filter, filo).await {
+            Ok(c) => c,
+            Err(e) => return Err(Error::from_mongo("Unable to get cursor", e)),
+        };
+        match cursor.try_collect().await {
+            Ok(v) => return Ok(v),
+            Err(e) => return Err(Error::from_mongo("Unable to retrieve items", e)),
+        };
+    }
+
+    async fn insert(&self, name: &str, docs: Vec<Document>) -> Result<(), Error> {
+        match self.db().collection::<Document>(name).insert_many(docs, None).await {
+            Ok(_) => return Ok(()),
+            Err(e) => return Err(Error::from_mongo("Unable to insert items", e)),
+        };
+    }
+
+    async fn remove(&self, name: &str, filter: Document) -> Result<(), Error> {
+        match self.db().collection::<Document>(name).delete_many(filter, None).await {
+            Ok(_) => return Ok(()),
+            Err(e) => return Err(Error::from_mongo("Unable to remove items", e)),
+        };
+    }
+
+    async fn update(&self, name: &str, filter: Document, set: Document) -> Result<(), Error> {
+        match self.db().collection::<Document>(name).update_many(filter, doc!{"$set": set}, None).await {
+            Ok(_) => return Ok(()),
+            Err(e) => return Err(Error::from_mongo("Unable to update items", e)),
+        };
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        match self.db().run_command(doc!{"ping": 1}, None).await {
+            Ok(_) => return Ok(()),
+            Err(e) => return Err(Error::from_mongo("Unable to ping the database", e)),
+        };
+    }
+}
@@ -0,0 +1,111 @@
+use crate::error::Error;
+use mongodb::options::{ClientOptions, Tls, TlsOptions};
+use std::env;
+use std::path::PathBuf;
+
+const ENV_VAR_HOMECHATBOT_MONGO_URI : &str = "HOMECHATBOT_MONGO_URI";
+const ENV_VAR_HOMECHATBOT_MONGO_ADDRESS : &str = "HOMECHATBOT_MONGO_ADDRESS";
+const ENV_VAR_HOMECHATBOT_MONGO_USERNAME : &str = "HOMECHATBOT_MONGO_USERNAME";
+const ENV_VAR_HOMECHATBOT_MONGO_PASSWORD : &str = "HOMECHATBOT_MONGO_PASSWORD";
+const ENV_VAR_HOMECHATBOT_MONGO_DATABASE : &str = "HOMECHATBOT_MONGO_DATABASE";
+const ENV_VAR_HOMECHATBOT_MONGO_AUTH_SOURCE : &str = "HOMECHATBOT_MONGO_AUTH_SOURCE";
+const ENV_VAR_HOMECHATBOT_MONGO_REPLICA_SET : &str = "HOMECHATBOT_MONGO_REPLICA_SET";
+const ENV_VAR_HOMECHATBOT_MONGO_TLS : &str = "HOMECHATBOT_MONGO_TLS";
+const ENV_VAR_HOMECHATBOT_MONGO_TLS_CA_FILE : &str = "HOMECHATBOT_MONGO_TLS_CA_FILE";
+const ENV_VAR_HOMECHATBOT_MONGO_TLS_CERT_KEY_FILE : &str = "HOMECHATBOT_MONGO_TLS_CERT_KEY_FILE";
+pub const DEFAULT_DB_NAME : &str = "homechatbot_db";
+
+// How to reach the database. Either a full connection string in
+// HOMECHATBOT_MONGO_URI (mongodb:// or mongodb+srv://, with credentials
+// percent-encoded if it carries any), or host[:port][,host[:port]...] in
+// HOMECHATBOT_MONGO_ADDRESS. The other settings override what the URI says.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub uri: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub database: String,
+    pub auth_source: Option<String>,
+    pub replica_set: Option<String>,
+    pub tls: bool,
+    pub tls_ca_file: Option<String>,
+    pub tls_cert_key_file: Option<String>,
+}
+
+impl Settings {
+    pub fn from_env() -> Result<Settings, Error> {
+        return Settings::from_vars(|name| env::var(name).ok());
+    }
+
+    // Like from_env, with the variables looked up by var
+    pub fn from_vars<F: Fn(&str) -> Option<String>>(var: F) -> Result<Settings, Error> {
+        let get_env = |name: &str| var(name).map(|v| v.trim().to_string()).filter(|v| v != "");
+        let uri = match (get_env(ENV_VAR_HOMECHATBOT_MONGO_URI), get_env(ENV_VAR_HOMECHATBOT_MONGO_ADDRESS)) {
+            (Some(u), _) => u,
+            (None, Some(a)) if a.contains('/') => format!("mongodb://{}", a),
+            (None, Some(a)) => format!("mongodb://{}/", a),
+            (None, None) => return Err(Error::InvalidInput(format!("Please set the environment variable {} or {}", ENV_VAR_HOMECHATBOT_MONGO_URI, ENV_VAR_HOMECHATBOT_MONGO_ADDRESS))),
+        };
+        if !uri.starts_with("mongodb://") && !uri.starts_with("mongodb+srv://") {
+            return Err(Error::InvalidInput(format!("{} has to start with mongodb:// or mongodb+srv://", ENV_VAR_HOMECHATBOT_MONGO_URI)));
+        }
+        let username = get_env(ENV_VAR_HOMECHATBOT_MONGO_USERNAME);
+        // Spaces can be part of a password, so it is taken as it is
+        let password = var(ENV_VAR_HOMECHATBOT_MONGO_PASSWORD).filter(|v| v != "");
+        if username.is_some() != password.is_some() {
+            return Err(Error::InvalidInput(format!("Please set both {} and {}, or neither", ENV_VAR_HOMECHATBOT_MONGO_USERNAME, ENV_VAR_HOMECHATBOT_MONGO_PASSWORD)));
+        }
+        let tls_ca_file = get_env(ENV_VAR_HOMECHATBOT_MONGO_TLS_CA_FILE);
+        let tls_cert_key_file = get_env(ENV_VAR_HOMECHATBOT_MONGO_TLS_CERT_KEY_FILE);
+        let tls = match get_env(ENV_VAR_HOMECHATBOT_MONGO_TLS) {
+            Some(t) => t.to_lowercase() == "true" || t == "1",
+            None => false,
+        };
+        return Ok(Settings{
+            uri: uri,
+            username: username,
+            password: password,
+            database: get_env(ENV_VAR_HOMECHATBOT_MONGO_DATABASE).unwrap_or(DEFAULT_DB_NAME.to_string()),
+            auth_source: get_env(ENV_VAR_HOMECHATBOT_MONGO_AUTH_SOURCE),
+            replica_set: get_env(ENV_VAR_HOMECHATBOT_MONGO_REPLICA_SET),
+            tls: tls || tls_ca_file.is_some() || tls_cert_key_file.is_some(),
+            tls_ca_file: tls_ca_file,
+            tls_cert_key_file: tls_cert_key_file,
+        });
+    }
+
+    // Credentials are set on the options rather than put into the URI, so
+    // they need no escaping whatever characters they contain
+    pub async fn client_options(&self) -> Result<ClientOptions, Error> {
+        let mut co = match ClientOptions::parse(self.uri.as_str()).await {
+            Ok(co) => co,
+            Err(e) => return Err(Error::from_mongo("Unable to create client options", e)),
+        };
+        if let Some(u) = &self.username {
+            let mut cred = co.credential.take().unwrap_or_default();
+            cred.username = Some(u.clone());
+            cred.password = self.password.clone();
+            co.credential = Some(cred);
+        }
+        if let (Some(src), Some(cred)) = (&self.auth_source, co.credential.as_mut()) {
+            cred.source = Some(src.clone());
+        }
+        if let Some(rs) = &self.replica_set {
+            co.repl_set_name = Some(rs.clone());
+        }
+        if self.tls {
+            let mut tls = match co.tls.take() {
+                Some(Tls::Enabled(t)) => t,
+                _ => TlsOptions::default(),
+            };
+            if let Some(ca) = &self.tls_ca_file {
+                tls.ca_file_path = Some(PathBuf::from(ca));
+            }
+            if let Some(cert) = &self.tls_cert_key_file {
+                tls.cert_key_file_path = Some(PathBuf::from(cert));
+            }
+            co.tls = Some(Tls::Enabled(tls));
+        }
+        return Ok(co);
+    }
+}
@@ -0,0 +1,107 @@
+use std::fmt;
+use mongodb::error::{ErrorKind, WriteFailure};
+use tracing::{error, warn};
+
+const MONGO_DUPLICATE_KEY : i32 = 11000;
+const MONGO_UNAUTHORIZED : i32 = 13;
+
+// Error shared by the database layer and the command modules. The text
+// carried by every variant is meant for the logs; what the family room gets
+// to see comes from user_message().
+#[derive(Debug, Clone)]
+pub enum Error {
+    NotFound(String),
+    Duplicate(String),
+    ConnectionLost(String),
+    PermissionDenied(String),
+    InvalidInput(String),
+    Internal(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound(m) => write!(f, "not found: {}", m),
+            Error::Duplicate(m) => write!(f, "duplicate: {}", m),
+            Error::ConnectionLost(m) => write!(f, "connection lost: {}", m),
+            Error::PermissionDenied(m) => write!(f, "permission denied: {}", m),
+            Error::InvalidInput(m) => write!(f, "invalid input: {}", m),
+            Error::Internal(m) => write!(f, "internal error: {}", m),
+        }
+    }
+}
+
+impl Error {
+    // Classifies a MongoDB error, adding what was being done as context
+    pub fn from_mongo(context: &str, e: mongodb::error::Error) -> Error {
+        let msg = format!("{}: {}", context, e);
+        match e.kind.as_ref() {
+            ErrorKind::BulkWrite(bwf) => {
+                let dup = match &bwf.write_errors {
+                    Some(wes) => wes.iter().any(|we| we.code == MONGO_DUPLICATE_KEY),
+                    None => false,
+                };
+                if dup {
+                    return Error::Duplicate(msg);
+                }
+            },
+            ErrorKind::Write(WriteFailure::WriteError(we)) => {
+                if we.code == MONGO_DUPLICATE_KEY {
+                    return Error::Duplicate(msg);
+                }
+            },
+            ErrorKind::Command(ce) => {
+                if ce.code == MONGO_DUPLICATE_KEY {
+                    return Error::Duplicate(msg);
+                } else if ce.code == MONGO_UNAUTHORIZED {
+                    return Error::PermissionDenied(msg);
+                }
+            },
+            ErrorKind::Authentication{..} => return Error::PermissionDenied(msg),
+            ErrorKind::Io(_) | ErrorKind::ServerSelection{..} | ErrorKind::ConnectionPoolCleared{..} | ErrorKind::DnsResolve{..} => {
+                return Error::ConnectionLost(msg);
+            },
+            ErrorKind::InvalidArgument{..} => return Error::InvalidInput(msg),
+            _ => {},
+        };
+        return Error::Internal(msg);
+    }
+
+    // Classifies an error from an HTTP call to a device or service
+    pub fn from_http(context: &str, e: reqwest::Error) -> Error {
+        let msg = format!("{}: {}", context, e);
+        if e.is_connect() || e.is_timeout() {
+            return Error::ConnectionLost(msg);
+        }
+        match e.status() {
+            Some(s) if s == reqwest::StatusCode::UNAUTHORIZED || s == reqwest::StatusCode::FORBIDDEN => return Error::PermissionDenied(msg),
+            // Shown in the room, so only the context and the status
+            Some(s) if s.is_client_error() => return Error::InvalidInput(format!("{} (HTTP {})", context, s.as_u16())),
+            _ => {},
+        };
+        return Error::Internal(msg);
+    }
+
+    // What can be shown in a chat room. Input and lookup errors are caused
+    // by the user and explain themselves, everything else is kept generic.
+    pub fn user_message(&self) -> String {
+        match self {
+            Error::NotFound(m) => m.clone(),
+            Error::InvalidInput(m) => m.clone(),
+            Error::Duplicate(_) => String::from("This already exists"),
+            Error::ConnectionLost(_) => String::from("The database is not reachable at the moment, please try again later"),
+            Error::PermissionDenied(_) => String::from("The bot is not allowed to do this"),
+            Error::Internal(_) => String::from("Something went wrong, the details were logged"),
+        }
+    }
+}
+
+// Logs the full error and returns the message for the chat room
+pub fn report(e: &Error) -> String {
+    match e {
+        Error::NotFound(_) | Error::InvalidInput(_) => {},
+        Error::Duplicate(_) | Error::PermissionDenied(_) => warn!("{}", e),
+        Error::ConnectionLost(_) | Error::Internal(_) => error!("{}", e),
+    };
+    return e.user_message();
+}
@@ -0,0 +1,31 @@
+use chrono::Utc;
+use serde::Serialize;
+use std::sync::OnceLock;
+use tokio::sync::broadcast;
+
+// Events waiting for slow subscribers; beyond that they lose the oldest
+const BUS_CAPACITY : usize = 256;
+
+static BUS : OnceLock<broadcast::Sender<Event>> = OnceLock::new();
+
+// Something that happened in a module, e.g. "grocery.item_added". data is
+// whatever describes it best, time is RFC 3339.
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub event: String,
+    pub time: String,
+    pub data: serde_json::Value,
+}
+
+fn bus() -> &'static broadcast::Sender<Event> {
+    return BUS.get_or_init(|| broadcast::channel(BUS_CAPACITY).0);
+}
+
+// Never blocks and never fails: without subscribers the event is dropped
+pub fn publish(event: &str, data: serde_json::Value) {
+    let _ = bus().send(Event{event: event.to_string(), time: Utc::now().to_rfc3339(), data: data});
+}
+
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    return bus().subscribe();
+}
@@ -0,0 +1,319 @@
+use crate::csv;
+use crate::db;
+use crate::error::{self, Error};
+use crate::migrate;
+use crate::reply::Reply;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use mongodb::bson::doc;
+use chrono::Local;
+
+const EXPENSE_COLLECTION_NAME : &str = "expenses";
+pub const COLLECTIONS : &[migrate::Collection] = &[
+    migrate::Collection{name: EXPENSE_COLLECTION_NAME, index: None},
+];
+const EXPENSE_HELP : &str = "Expense allowed commands:
+    {amount} {category} [paid by {me|member}] [split {all|member1,member2,...}]
+    list [YYYY-MM]
+    balance
+    settle
+    export [YYYY-MM]
+Members are the allowed users, given by full ID or by name (e.g. alice for @alice:example.org)";
+
+// Anything above 1,000,000.00 is taken for a typo
+const MAX_AMOUNT_CENTS : i64 = 100_000_000;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Expense {
+    date: String,
+    amount_cents: i64,
+    category: String,
+    paid_by: String,
+    split: Vec<String>,
+}
+
+pub async fn handle_expense_command(cmd: String, sender: &str, db: Box<db::Homechatbotdb>) -> Reply {
+    let re = match Regex::new(r"^(?s)(\S+)(?:\s+(.*))?$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)).into(),
+    };
+    let caps = match re.captures(cmd.trim()) {
+        Some(c) => c,
+        None => return String::from(EXPENSE_HELP).into(),
+    };
+    let cmd = match caps.get(1) {
+        Some(c) => c.as_str().to_lowercase(),
+        None => return String::from(EXPENSE_HELP).into(),
+    };
+    let rest = caps.get(2).map(|c| c.as_str().trim());
+    if cmd == "list" {
+        return handle_list_request(rest, db).await.into();
+    } else if cmd == "balance" {
+        return handle_balance_request(db).await.into();
+    } else if cmd == "settle" {
+        return handle_settle_request(db).await.into();
+    } else if cmd == "export" {
+        return handle_export_request(rest, db).await;
+    } else if cmd.starts_with(|c: char| c.is_ascii_digit()) {
+        match parse_amount(cmd.as_str()) {
+            Ok(amount) => return handle_add_request(amount, rest.unwrap_or(""), sender, db).await.into(),
+            Err(e) => return format!("{}\n{}", e.user_message(), EXPENSE_HELP).into(),
+        };
+    }
+    return String::from(EXPENSE_HELP).into();
+}
+
+pub fn parse_amount(amount: &str) -> Result<i64, Error> {
+    let re = match Regex::new(r"^(\d+)(?:[.,](\d{1,2}))?$") {
+        Ok(r) => r,
+        Err(e) => return Err(Error::Internal(format!("ERROR: {}", e))),
+    };
+    let caps = match re.captures(amount) {
+        Some(c) => c,
+        None => return Err(Error::InvalidInput(format!("Invalid amount \"{}\"", amount))),
+    };
+    let whole = match caps.get(1).map_or("0", |c| c.as_str()).parse::<i64>() {
+        Ok(w) => w,
+        Err(e) => return Err(Error::InvalidInput(format!("Invalid amount \"{}\": {}", amount, e))),
+    };
+    let cents = match caps.get(2) {
+        Some(c) if c.as_str().len() == 1 => c.as_str().parse::<i64>().unwrap_or(0) * 10,
+        Some(c) => c.as_str().parse::<i64>().unwrap_or(0),
+        None => 0,
+    };
+    match whole.checked_mul(100).and_then(|w| w.checked_add(cents)) {
+        Some(a) if a <= MAX_AMOUNT_CENTS => return Ok(a),
+        _ => return Err(Error::InvalidInput(format!("Invalid amount \"{}\", it can be at most {}", amount, format_amount(MAX_AMOUNT_CENTS)))),
+    };
+}
+
+pub fn format_amount(cents: i64) -> String {
+    let sign = if cents < 0 { "-" } else { "" };
+    return format!("{}{}.{:02}", sign, cents.abs() / 100, cents.abs() % 100);
+}
+
+fn display_name(member: &str) -> String {
+    let name = member.trim_start_matches('@');
+    return match name.find(':') {
+        Some(i) => name[..i].to_string(),
+        None => name.to_string(),
+    };
+}
+
+fn resolve_member(name: &str, sender: &str, members: &Vec<String>) -> Result<String, Error> {
+    let name = name.trim();
+    if name.to_lowercase() == "me" {
+        return Ok(sender.to_string());
+    }
+    for m in members {
+        if m == name || display_name(m).to_lowercase() == name.to_lowercase() {
+            return Ok(m.clone());
+        }
+    }
+    return Err(Error::InvalidInput(format!("Unknown member \"{}\"", name)));
+}
+
+// Records an expense paid by the given member and split equally between
+// all allowed users
+pub async fn record_shared_expense(amount_cents: i64, category: &str, paid_by: &str, db: Box<db::Homechatbotdb>) -> Result<(), Error> {
+    let mut members = db.get_allowed_users().await?;
+    if !members.contains(&paid_by.to_string()) {
+        members.push(paid_by.to_string());
+    }
+    return insert_expense(amount_cents, category, paid_by, members, db).await;
+}
+
+async fn insert_expense(amount_cents: i64, category: &str, paid_by: &str, split: Vec<String>, db: Box<db::Homechatbotdb>) -> Result<(), Error> {
+    let today = Local::today().naive_local().format("%Y-%m-%d").to_string();
+    return db.insert_data_to_collection(EXPENSE_COLLECTION_NAME, vec![doc!{
+        "date": today,
+        "amount_cents": amount_cents,
+        "category": category,
+        "paid_by": paid_by,
+        "split": split,
+    }]).await;
+}
+
+async fn handle_add_request(amount: i64, cmd_rest: &str, sender: &str, db: Box<db::Homechatbotdb>) -> String {
+    let re = match Regex::new(r"(?i)^(\S+)(?:\s+paid\s+by\s+(\S+))?(?:\s+split\s+(\S+))?\s*$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)),
+    };
+    let caps = match re.captures(cmd_rest) {
+        Some(c) => c,
+        None => return String::from(EXPENSE_HELP),
+    };
+    if amount <= 0 {
+        return "The amount must be greater than zero".to_string();
+    }
+    let category = caps.get(1).map_or("", |c| c.as_str()).to_lowercase();
+    let members = match db.get_allowed_users().await {
+        Ok(m) => m,
+        Err(e) => return error::report(&e),
+    };
+    let paid_by = match resolve_member(caps.get(2).map_or("me", |c| c.as_str()), sender, &members) {
+        Ok(p) => p,
+        Err(e) => return error::report(&e),
+    };
+    let split = match caps.get(3).map_or("all", |c| c.as_str()) {
+        s if s.to_lowercase() == "all" => {
+            let mut all = members.clone();
+            if !all.contains(&paid_by) {
+                all.push(paid_by.clone());
+            }
+            all
+        },
+        s => {
+            let mut names : Vec<String> = vec![];
+            for n in s.split(",") {
+                match resolve_member(n, sender, &members) {
+                    Ok(m) => {
+                        if !names.contains(&m) {
+                            names.push(m);
+                        }
+                    },
+                    Err(e) => return error::report(&e),
+                };
+            }
+            names
+        },
+    };
+    match insert_expense(amount, category.as_str(), paid_by.as_str(), split.clone(), db).await {
+        Ok(_) => return format!("Recorded {} for {} paid by {}, split between {}", format_amount(amount), category, display_name(&paid_by), split.iter().map(|m| display_name(m)).collect::<Vec<String>>().join(", ")),
+        Err(e) => return error::report(&e),
+    };
+}
+
+fn month_filter(month: Option<&str>) -> Result<mongodb::bson::Document, Error> {
+    let month = match month {
+        Some(m) if m != "" => m.to_string(),
+        _ => Local::today().naive_local().format("%Y-%m").to_string(),
+    };
+    let re = match Regex::new(r"^\d{4}-\d{2}$") {
+        Ok(r) => r,
+        Err(e) => return Err(Error::Internal(format!("ERROR: {}", e))),
+    };
+    if !re.is_match(month.as_str()) {
+        return Err(Error::InvalidInput(format!("Invalid month \"{}\", expected YYYY-MM", month)));
+    }
+    return Ok(doc!{"date": {"$regex": format!("^{}-", month)}});
+}
+
+async fn handle_list_request(month: Option<&str>, db: Box<db::Homechatbotdb>) -> String {
+    let fltr = match month_filter(month) {
+        Ok(f) => f,
+        Err(e) => return error::report(&e),
+    };
+    let items = match db.get_generic_data_collection::<Expense>(EXPENSE_COLLECTION_NAME, fltr, doc!{"date":1}).await {
+        Ok(i) => i,
+        Err(e) => return error::report(&e),
+    };
+    if items.len() == 0 {
+        return "No expenses recorded".to_string();
+    }
+    let mut msg = "".to_string();
+    let mut total = 0;
+    for exp in items {
+        total += exp.amount_cents;
+        msg = format!("{}{} {} {} paid by {}\n", msg, exp.date, format_amount(exp.amount_cents), exp.category, display_name(&exp.paid_by));
+    }
+    return format!("{}Total: {}", msg, format_amount(total));
+}
+
+// Net balance per member in cents: positive means the member is owed money
+async fn get_balances(db: Box<db::Homechatbotdb>) -> Result<Vec<(String, i64)>, Error> {
+    let items = db.get_generic_data_collection::<Expense>(EXPENSE_COLLECTION_NAME, doc!{}, doc!{"date":1}).await?;
+    let mut balances : Vec<(String, i64)> = vec![];
+    fn add(balances: &mut Vec<(String, i64)>, member: &str, cents: i64) {
+        match balances.iter_mut().find(|b| b.0 == member) {
+            Some(b) => b.1 += cents,
+            None => balances.push((member.to_string(), cents)),
+        };
+    }
+    for exp in items {
+        if exp.split.len() == 0 {
+            continue;
+        }
+        add(&mut balances, exp.paid_by.as_str(), exp.amount_cents);
+        let share = exp.amount_cents / exp.split.len() as i64;
+        let mut remainder = exp.amount_cents % exp.split.len() as i64;
+        for m in exp.split.iter() {
+            let extra = if remainder > 0 { 1 } else { 0 };
+            remainder -= extra;
+            add(&mut balances, m.as_str(), -(share + extra));
+        }
+    }
+    return Ok(balances);
+}
+
+async fn handle_balance_request(db: Box<db::Homechatbotdb>) -> String {
+    let balances = match get_balances(db).await {
+        Ok(b) => b,
+        Err(e) => return error::report(&e),
+    };
+    if balances.len() == 0 {
+        return "No expenses recorded".to_string();
+    }
+    let mut msg = "".to_string();
+    for (m, b) in balances {
+        msg = format!("{}{}: {}\n", msg, display_name(&m), format_amount(b));
+    }
+    return msg;
+}
+
+// Greedily matches the largest debtor with the largest creditor, which
+// settles everything in at most n-1 transfers
+fn compute_transfers(balances: Vec<(String, i64)>) -> Vec<(String, String, i64)> {
+    let mut creditors : Vec<(String, i64)> = balances.iter().filter(|b| b.1 > 0).cloned().collect();
+    let mut debtors : Vec<(String, i64)> = balances.iter().filter(|b| b.1 < 0).map(|b| (b.0.clone(), -b.1)).collect();
+    let mut transfers : Vec<(String, String, i64)> = vec![];
+    loop {
+        creditors.sort_by(|a, b| b.1.cmp(&a.1));
+        debtors.sort_by(|a, b| b.1.cmp(&a.1));
+        if creditors.len() == 0 || debtors.len() == 0 || creditors[0].1 == 0 || debtors[0].1 == 0 {
+            break;
+        }
+        let amount = std::cmp::min(creditors[0].1, debtors[0].1);
+        transfers.push((debtors[0].0.clone(), creditors[0].0.clone(), amount));
+        creditors[0].1 -= amount;
+        debtors[0].1 -= amount;
+    }
+    return transfers;
+}
+
+async fn handle_settle_request(db: Box<db::Homechatbotdb>) -> String {
+    let balances = match get_balances(db).await {
+        Ok(b) => b,
+        Err(e) => return error::report(&e),
+    };
+    let transfers = compute_transfers(balances);
+    if transfers.len() == 0 {
+        return "Everybody is settled up".to_string();
+    }
+    let mut msg = "".to_string();
+    for (from, to, amount) in transfers {
+        msg = format!("{}{} pays {} to {}\n", msg, display_name(&from), format_amount(amount), display_name(&to));
+    }
+    return format!("{}Record a payment with: expense {{amount}} settlement split {{member}}", msg);
+}
+
+async fn handle_export_request(month: Option<&str>, db: Box<db::Homechatbotdb>) -> Reply {
+    let month = month.filter(|m| *m != "").map(|m| m.to_string()).unwrap_or(Local::today().naive_local().format("%Y-%m").to_string());
+    let fltr = match month_filter(Some(month.as_str())) {
+        Ok(f) => f,
+        Err(e) => return error::report(&e).into(),
+    };
+    let items = match db.get_generic_data_collection::<Expense>(EXPENSE_COLLECTION_NAME, fltr, doc!{"date":1}).await {
+        Ok(i) => i,
+        Err(e) => return error::report(&e).into(),
+    };
+    let mut csv = "date,amount,category,paid_by,split\n".to_string();
+    for exp in items {
+        csv = format!("{}{},{},{},{},{}\n", csv, exp.date, format_amount(exp.amount_cents), csv::field(&exp.category), csv::field(&exp.paid_by), csv::field(&exp.split.join(";")));
+    }
+    return Reply::File{
+        name: format!("expenses-{}.csv", month),
+        content_type: mime::TEXT_CSV,
+        data: csv.into_bytes(),
+    };
+}
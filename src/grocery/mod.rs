@@ -1,9 +1,18 @@
+use crate::csv;
 use crate::db;
+use crate::error::{self, Error};
+use crate::events;
+use crate::expense;
+use crate::migrate;
+use crate::reply::Reply;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use mongodb::bson::doc;
+use mongodb::bson::{doc, to_document};
+use chrono::{Local, Utc};
+use std::convert::TryFrom;
 
 const GROCERY_COLLECTION_NAME : &str = "groceries";
+const PRICE_COLLECTION_NAME : &str = "grocery_prices";
 const GROCERY_HELP : &str = "Grocery allowed commands:
     list [category]
     add {category}
@@ -11,77 +20,128 @@ const GROCERY_HELP : &str = "Grocery allowed commands:
         product2
         product3
         ...
-    rem {product_id}";
+    rem {product_id}
+    bought {product_id} {price} [store]
+    prices {product}
+    spend [YYYY-MM]
+    export [csv|json|md]
+    import [csv|json|md data] (without data, the next file you send is imported)
+    done (asks for the total, then clears the list)";
+const PENDING_IMPORT_COLLECTION_NAME : &str = "grocery_pending_imports";
+const PENDING_DONE_COLLECTION_NAME : &str = "grocery_pending_done";
 const MAX_ITEMS_IN_DB : u32 = 10000;
+// How long to wait for the file after "import" was sent
+const PENDING_IMPORT_TIMEOUT_SECS : i64 = 600;
+// How long "done" waits for the total
+const PENDING_DONE_TIMEOUT_SECS : i64 = 600;
+// A price is reported as unusual when it is this many percent above the
+// average of the previously recorded prices
+const PRICE_JUMP_PERCENT : i64 = 20;
+pub const COLLECTIONS : &[migrate::Collection] = &[
+    migrate::Collection{name: GROCERY_COLLECTION_NAME, index: Some("groid")},
+    migrate::Collection{name: PRICE_COLLECTION_NAME, index: None},
+    migrate::Collection{name: PENDING_IMPORT_COLLECTION_NAME, index: None},
+    migrate::Collection{name: PENDING_DONE_COLLECTION_NAME, index: None},
+];
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Groceries {
     category: String,
     groid: u32,
     product: String,
+    // YYYY-MM-DD
+    added: String,
 }
 
-pub async fn handle_grocery_command(cmd: String, db: Box<db::Homechatbotdb>) -> String {
-    match db.check_collection_exists(GROCERY_COLLECTION_NAME).await {
-        Ok(exists) => {
-            if !exists {
-                match db.create_collection(GROCERY_COLLECTION_NAME).await {
-                    Ok(_) => {},
-                    Err(e) => return String::from(format!("{}", e)),
-                };
-            }
-        },
-        Err(e) => return String::from(format!("{}", e)),
-    };
-    match db.get_collection_index(GROCERY_COLLECTION_NAME).await {
-        Ok(indxs) => {
-            let mut groid_inside = false;
-            for indx in indxs {
-                if indx.starts_with("groid") {
-                    groid_inside = true;
-                    break;
-                }
-            }
-            if !groid_inside {
-                println!("Setting grocery ID as index");
-                match db.create_collection_index(GROCERY_COLLECTION_NAME, "groid").await {
-                    Ok(_) => {},
-                    Err(e) => return String::from(format!("{}", e)),
-                };
-            }
-        },
-        Err(e) => return String::from(format!("{}", e)),
-    };
+#[derive(Debug, Serialize, Deserialize)]
+struct ImportedItem {
+    category: String,
+    product: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingImport {
+    sender: String,
+    requested: i64,
+}
+
+// A "done" waiting for its answer. Only the items listed when it was sent
+// are cleared, anything added in the meantime stays, even under a reused ID.
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingDone {
+    sender: String,
+    room: String,
+    requested: i64,
+    items: Vec<DoneItem>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DoneItem {
+    groid: u32,
+    product: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PriceRecord {
+    product: String,
+    category: String,
+    store: String,
+    price_cents: i64,
+    date: String,
+}
+
+pub async fn handle_grocery_command(cmd: String, sender: &str, room: &str, db: Box<db::Homechatbotdb>) -> Reply {
     let re = match Regex::new(r"^(?s)(\w+)(?:\s+(.*))?$") {
         Ok(r) => r,
-        Err(e) => return String::from(format!("ERROR: {}", e)),
+        Err(e) => return String::from(format!("ERROR: {}", e)).into(),
     };
     let caps = match re.captures(cmd.as_str()) {
         Some(c) => c,
-        None => return String::from(GROCERY_HELP),
+        None => return String::from(GROCERY_HELP).into(),
     };
     let cmd = match caps.get(1) {
         Some(c) => c.as_str().to_lowercase(),
-        None => return String::from(GROCERY_HELP),
+        None => return String::from(GROCERY_HELP).into(),
     };
     if cmd == "list" {
         let fltr = match caps.get(2) {
             Some(c) => vec![c.as_str()],
             None => vec![],
         };
-        return handle_list_request(fltr, db).await;
+        return handle_list_request(fltr, db).await.into();
     } else if cmd == "add" {
         match caps.get(2) {
-            Some(c) => return handle_add_request(c.as_str(), db).await,
-            None => return String::from(GROCERY_HELP),
+            Some(c) => return handle_add_request(c.as_str(), db).await.into(),
+            None => return String::from(GROCERY_HELP).into(),
         };
     } else if cmd == "rem" {
         match caps.get(2) {
-            Some(c) => return handle_remove_request(c.as_str(), db).await,
-            None => return String::from(GROCERY_HELP),
+            Some(c) => return handle_remove_request(c.as_str(), db).await.into(),
+            None => return String::from(GROCERY_HELP).into(),
+        };
+    } else if cmd == "bought" {
+        match caps.get(2) {
+            Some(c) => return handle_bought_request(c.as_str(), db).await.into(),
+            None => return String::from(GROCERY_HELP).into(),
+        };
+    } else if cmd == "prices" {
+        match caps.get(2) {
+            Some(c) => return handle_prices_request(c.as_str(), db).await.into(),
+            None => return String::from(GROCERY_HELP).into(),
+        };
+    } else if cmd == "spend" {
+        return handle_spend_request(caps.get(2).map(|c| c.as_str().trim()), db).await.into();
+    } else if cmd == "export" {
+        return handle_export_request(caps.get(2).map_or("csv", |c| c.as_str().trim()), db).await;
+    } else if cmd == "import" {
+        return handle_import_request(caps.get(2).map(|c| c.as_str()), sender, db).await.into();
+    } else if cmd == "done" {
+        match caps.get(2) {
+            Some(c) if c.as_str().trim() != "" => return String::from(GROCERY_HELP).into(),
+            _ => return handle_done_request(sender, room, db).await.into(),
         };
     }
-    return String::from(GROCERY_HELP);
+    return String::from(GROCERY_HELP).into();
 }
 
 async fn handle_add_request(cmd_rest: &str, db: Box<db::Homechatbotdb>) -> String {
@@ -101,8 +161,24 @@ async fn handle_add_request(cmd_rest: &str, db: Box<db::Homechatbotdb>) -> Strin
         Some(p) => p.as_str(),
         None => return String::from(GROCERY_HELP),
     };
-    let prodarr = products.split("\n");
-    for sprod in prodarr {
+    let prodarr : Vec<String> = products.split("\n").map(|p| p.to_string()).collect();
+    match add_products(category, prodarr, db).await {
+        Ok(_) => {},
+        Err(e) => return error::report(&e),
+    };
+    return String::from("Items successfully added!");
+}
+
+// Items listed before the date was recorded count as added on the day of
+// the migration
+pub async fn migrate_added_dates(db: Box<db::Homechatbotdb>) -> Result<(), Error> {
+    let today = Local::today().naive_local().format("%Y-%m-%d").to_string();
+    return db.update_data(GROCERY_COLLECTION_NAME, doc!{"added": {"$exists": false}}, doc!{"added": today}).await;
+}
+
+pub async fn add_products(category: &str, products: Vec<String>, db: Box<db::Homechatbotdb>) -> Result<(), Error> {
+    let today = Local::today().naive_local().format("%Y-%m-%d").to_string();
+    for sprod in products {
         if sprod.trim() == "" {
             continue;
         }
@@ -110,30 +186,42 @@ async fn handle_add_request(cmd_rest: &str, db: Box<db::Homechatbotdb>) -> Strin
         while !success {
             let id = match get_smallest_available_id(db.clone()).await {
                 Ok(i) => i,
-                Err(e) => return String::from(format!("ERROR: {}", e)),
+                Err(e) => return Err(e),
             };
-            match db.insert_data_to_collection(GROCERY_COLLECTION_NAME, vec![doc! {"product": sprod, "category": category, "groid": id}]).await {
+            match db.insert_data_to_collection(GROCERY_COLLECTION_NAME, vec![doc! {"product": sprod.as_str(), "category": category, "groid": id, "added": today.as_str()}]).await {
                 Ok(_) => {
+                    events::publish("grocery.item_added", serde_json::json!({"id": id, "category": category, "product": sprod}));
                     success = true;
                 },
-                Err(e) => {
-                    let err = format!("{}", e);
-                    if err.contains("E11000 duplicate key error collection") {
-                        continue;
-                    } else {
-                        return err;
-                    }
-                },
+                Err(Error::Duplicate(_)) => continue,
+                Err(e) => return Err(e),
             };
         }
     }
-    return String::from("Items successfully added!");
+    return Ok(());
+}
+
+pub async fn get_listed_products(db: Box<db::Homechatbotdb>) -> Result<Vec<String>, Error> {
+    let items = db.get_generic_data_collection::<Groceries>(GROCERY_COLLECTION_NAME, doc!{}, doc!{}).await?;
+    return Ok(items.into_iter().map(|i| i.product).collect());
 }
 
-async fn get_smallest_available_id(db: Box<db::Homechatbotdb>) -> Result<u32, String> {
+// The category and product of a listed item. Callers moving it elsewhere
+// remove it with remove_product only once it has arrived, so a failure
+// never loses it.
+pub async fn get_product(groid: u32, db: Box<db::Homechatbotdb>) -> Result<Option<(String, String)>, Error> {
+    let mut items = db.get_generic_data_collection::<Groceries>(GROCERY_COLLECTION_NAME, doc!{"groid": groid}, doc!{}).await?;
+    return Ok(items.pop().map(|i| (i.category, i.product)));
+}
+
+pub async fn remove_product(groid: u32, db: Box<db::Homechatbotdb>) -> Result<(), Error> {
+    return db.remove_data(GROCERY_COLLECTION_NAME, doc!{"groid": groid}).await;
+}
+
+async fn get_smallest_available_id(db: Box<db::Homechatbotdb>) -> Result<u32, Error> {
     let items = match db.get_generic_data_collection::<Groceries>(GROCERY_COLLECTION_NAME, doc!{}, doc!{}).await {
         Ok(i) => i,
-        Err(e) => return Err(String::from(format!("{}", e))),
+        Err(e) => return Err(e),
     };
     let mut bufv : Vec<u32> = vec![];
     for pro in items {
@@ -144,7 +232,7 @@ async fn get_smallest_available_id(db: Box<db::Homechatbotdb>) -> Result<u32, St
             return Ok(n);
         }
     }
-    return Err(format!("Too many products in the database"))
+    return Err(Error::InvalidInput(String::from("Too many products in the database")))
 }
 
 async fn handle_list_request(spec_cat: Vec<&str>, db: Box<db::Homechatbotdb>) -> String {
@@ -155,7 +243,7 @@ async fn handle_list_request(spec_cat: Vec<&str>, db: Box<db::Homechatbotdb>) ->
     };
     let items = match db.get_generic_data_collection::<Groceries>(GROCERY_COLLECTION_NAME, fltr, doc!{"category":1}).await {
         Ok(i) => i,
-        Err(e) => return format!("Error getting groceries: {}", e).to_string(),
+        Err(e) => return error::report(&e),
     };
     let mut msg : String = if items.len() > 0 {
         "".to_string()
@@ -181,9 +269,382 @@ async fn handle_remove_request(cmd_rest: &str, db: Box<db::Homechatbotdb>) -> St
             Err(e) => return format!("Only numbers are allowed: {}\n{}", e, GROCERY_HELP).to_string(),
         };
         match db.remove_data(GROCERY_COLLECTION_NAME, doc!{"groid":id}).await {
-            Ok(_) => {},
-            Err(e) => return format!("{}", e),
+            Ok(_) => events::publish("grocery.item_removed", serde_json::json!({"id": id})),
+            Err(e) => return error::report(&e),
         };
     }
     return "Items successfully removed".to_string();
-}
\ No newline at end of file
+}
+
+// Nothing is cleared yet, the sender is asked for the total first and
+// handle_done_answer takes it from there
+async fn handle_done_request(sender: &str, room: &str, db: Box<db::Homechatbotdb>) -> String {
+    let items = match db.get_generic_data_collection::<Groceries>(GROCERY_COLLECTION_NAME, doc!{}, doc!{}).await {
+        Ok(i) => i,
+        Err(e) => return error::report(&e),
+    };
+    if items.len() == 0 {
+        return "List is empty".to_string();
+    }
+    let pending = PendingDone{
+        sender: sender.to_string(),
+        room: room.to_string(),
+        requested: Utc::now().timestamp(),
+        items: items.into_iter().map(|i| DoneItem{groid: i.groid, product: i.product}).collect(),
+    };
+    let count = pending.items.len();
+    let pdoc = match to_document(&pending) {
+        Ok(d) => d,
+        Err(e) => return format!("Unable to serialize the grocery list: {}", e),
+    };
+    match db.remove_data(PENDING_DONE_COLLECTION_NAME, doc!{"sender": sender, "room": room}).await {
+        Ok(_) => {},
+        Err(e) => return error::report(&e),
+    };
+    match db.insert_data_to_collection(PENDING_DONE_COLLECTION_NAME, vec![pdoc]).await {
+        Ok(_) => return format!("This clears the {} items on the grocery list. What was the total? Answer with the amount to log it as a shared expense, \"skip\" to clear the list without logging it, or \"cancel\" to keep the list", count),
+        Err(e) => return error::report(&e),
+    };
+}
+
+// Takes the answer to a "done" the sender sent in this room. Returns None
+// when there is no such question or the message is no answer to it, the
+// message is then handled as a command and the question stays open.
+pub async fn handle_done_answer(answer: &str, sender: &str, room: &str, db: Box<db::Homechatbotdb>) -> Result<Option<String>, Error> {
+    let mut pending = db.get_generic_data_collection::<PendingDone>(PENDING_DONE_COLLECTION_NAME, doc!{"sender": sender, "room": room}, doc!{}).await?;
+    let pending = match pending.pop() {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    if Utc::now().timestamp() - pending.requested > PENDING_DONE_TIMEOUT_SECS {
+        db.remove_data(PENDING_DONE_COLLECTION_NAME, doc!{"sender": sender, "room": room}).await?;
+        return Ok(None);
+    }
+    let answer = answer.trim().to_lowercase();
+    let total = if answer == "cancel" || answer == "skip" {
+        None
+    } else if answer.starts_with(|c: char| c.is_ascii_digit()) {
+        match expense::parse_amount(answer.as_str()) {
+            Ok(a) if a > 0 => Some(a),
+            Ok(_) => return Ok(Some("The total must be greater than zero".to_string())),
+            Err(e) => return Ok(Some(e.user_message())),
+        }
+    } else {
+        return Ok(None);
+    };
+    db.remove_data(PENDING_DONE_COLLECTION_NAME, doc!{"sender": sender, "room": room}).await?;
+    if answer == "cancel" {
+        return Ok(Some("The grocery list was kept".to_string()));
+    }
+    for item in pending.items.iter() {
+        db.remove_data(GROCERY_COLLECTION_NAME, doc!{"groid": item.groid, "product": item.product.as_str()}).await?;
+    }
+    events::publish("grocery.list_cleared", serde_json::json!({"by": sender, "total_cents": total}));
+    match total {
+        Some(a) => match expense::record_shared_expense(a, "groceries", sender, db).await {
+            Ok(_) => return Ok(Some(format!("Grocery list cleared and {} logged as a shared expense", expense::format_amount(a)))),
+            Err(e) => return Ok(Some(format!("Grocery list cleared, but unable to log the expense: {}", error::report(&e)))),
+        },
+        None => return Ok(Some("Grocery list cleared".to_string())),
+    };
+}
+
+async fn handle_bought_request(cmd_rest: &str, db: Box<db::Homechatbotdb>) -> String {
+    let re = match Regex::new(r"^(\d+)\s+(\S+)(?:\s+(.+?))?\s*$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)),
+    };
+    let caps = match re.captures(cmd_rest.trim()) {
+        Some(c) => c,
+        None => return String::from(GROCERY_HELP),
+    };
+    let groid = match caps.get(1).map_or("", |c| c.as_str()).parse::<u32>() {
+        Ok(i) => i,
+        Err(e) => return format!("Only numbers are allowed: {}\n{}", e, GROCERY_HELP),
+    };
+    let price = match expense::parse_amount(caps.get(2).map_or("", |c| c.as_str())) {
+        Ok(p) => p,
+        Err(e) => return format!("{}\n{}", e.user_message(), GROCERY_HELP),
+    };
+    let store = caps.get(3).map_or("", |c| c.as_str()).to_lowercase();
+    let (category, product) = match get_product(groid, db.clone()).await {
+        Ok(Some(p)) => p,
+        Ok(None) => return format!("Grocery item {} not found", groid),
+        Err(e) => return error::report(&e),
+    };
+    let product_key = product.trim().to_lowercase();
+    let history = match db.get_generic_data_collection::<PriceRecord>(PRICE_COLLECTION_NAME, doc!{"product": product_key.as_str()}, doc!{}).await {
+        Ok(h) => h,
+        Err(e) => return error::report(&e),
+    };
+    let today = Local::today().naive_local().format("%Y-%m-%d").to_string();
+    match db.insert_data_to_collection(PRICE_COLLECTION_NAME, vec![doc!{
+        "product": product_key.as_str(),
+        "category": category.as_str(),
+        "store": store.as_str(),
+        "price_cents": price,
+        "date": today,
+    }]).await {
+        Ok(_) => {},
+        Err(e) => return error::report(&e),
+    };
+    // Only once the price is recorded, so a failure keeps the item listed
+    match remove_product(groid, db).await {
+        Ok(_) => {},
+        Err(e) => return error::report(&e),
+    };
+    events::publish("grocery.item_bought", serde_json::json!({"id": groid, "category": category, "product": product, "store": store, "price_cents": price}));
+    let mut msg = format!("{} bought for {}", product, expense::format_amount(price));
+    // Prefer comparing against the same store, prices differ a lot between stores
+    let same_store : Vec<&PriceRecord> = history.iter().filter(|h| h.store == store).collect();
+    let compare : Vec<&PriceRecord> = if same_store.len() > 0 { same_store } else { history.iter().collect() };
+    if let Some(jump) = price_jump(price, compare.iter().map(|h| h.price_cents).collect()) {
+        msg = format!("{}\nWARNING: this is {}% above the usual price of {}", msg, jump.0, expense::format_amount(jump.1));
+    }
+    return msg;
+}
+
+// How many percent the price is above the average of the earlier ones, and
+// that average, when it is more than PRICE_JUMP_PERCENT. Recorded prices
+// were not always limited when stored, so the sums are done in i128.
+pub fn price_jump(price: i64, earlier: Vec<i64>) -> Option<(i64, i64)> {
+    let earlier : Vec<i128> = earlier.into_iter().filter(|p| *p > 0).map(|p| p as i128).collect();
+    if earlier.len() == 0 {
+        return None;
+    }
+    let avg = earlier.iter().sum::<i128>() / earlier.len() as i128;
+    let price = price as i128;
+    if price * 100 <= avg * (100 + PRICE_JUMP_PERCENT as i128) {
+        return None;
+    }
+    let percent = (price - avg) * 100 / avg;
+    return Some((i64::try_from(percent).unwrap_or(i64::MAX), i64::try_from(avg).unwrap_or(i64::MAX)));
+}
+
+async fn handle_prices_request(product: &str, db: Box<db::Homechatbotdb>) -> String {
+    let product_key = product.trim().to_lowercase();
+    let history = match db.get_generic_data_collection::<PriceRecord>(PRICE_COLLECTION_NAME, doc!{"product": product_key.as_str()}, doc!{"store":1, "date":1}).await {
+        Ok(h) => h,
+        Err(e) => return error::report(&e),
+    };
+    if history.len() == 0 {
+        return format!("No prices recorded for {}", product.trim());
+    }
+    let mut msg = "".to_string();
+    let mut prev_store : Option<String> = None;
+    for rec in history {
+        if prev_store.as_ref() != Some(&rec.store) {
+            msg = format!("{}{}:\n", msg, if rec.store == "" { "(unknown store)" } else { rec.store.as_str() });
+            prev_store = Some(rec.store.clone());
+        }
+        msg = format!("{}{} {}\n", msg, rec.date, expense::format_amount(rec.price_cents));
+    }
+    return msg;
+}
+
+async fn handle_spend_request(month: Option<&str>, db: Box<db::Homechatbotdb>) -> String {
+    let month = match month {
+        Some(m) if m != "" => m.to_string(),
+        _ => Local::today().naive_local().format("%Y-%m").to_string(),
+    };
+    let re = match Regex::new(r"^\d{4}-\d{2}$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)),
+    };
+    if !re.is_match(month.as_str()) {
+        return format!("Invalid month \"{}\", expected YYYY-MM", month);
+    }
+    let records = match db.get_generic_data_collection::<PriceRecord>(PRICE_COLLECTION_NAME, doc!{"date": {"$regex": format!("^{}-", month)}}, doc!{"category":1}).await {
+        Ok(r) => r,
+        Err(e) => return error::report(&e),
+    };
+    if records.len() == 0 {
+        return format!("Nothing bought in {}", month);
+    }
+    let mut per_cat : Vec<(String, i64)> = vec![];
+    for rec in records {
+        match per_cat.iter_mut().find(|c| c.0 == rec.category) {
+            Some(c) => c.1 += rec.price_cents,
+            None => per_cat.push((rec.category, rec.price_cents)),
+        };
+    }
+    let mut msg = format!("Grocery spend in {}:\n", month);
+    let mut total = 0;
+    for (cat, cents) in per_cat {
+        total += cents;
+        msg = format!("{}{}: {}\n", msg, cat, expense::format_amount(cents));
+    }
+    return format!("{}Total: {}", msg, expense::format_amount(total));
+}
+
+async fn handle_export_request(format: &str, db: Box<db::Homechatbotdb>) -> Reply {
+    let items = match db.get_generic_data_collection::<Groceries>(GROCERY_COLLECTION_NAME, doc!{}, doc!{"category":1, "groid":1}).await {
+        Ok(i) => i,
+        Err(e) => return error::report(&e).into(),
+    };
+    let format = format.to_lowercase();
+    let (data, content_type) = if format == "csv" {
+        let mut csv = "id,category,product,added\n".to_string();
+        for pro in items {
+            csv = format!("{}{},{},{},{}\n", csv, pro.groid, csv::field(&pro.category), csv::field(&pro.product), pro.added);
+        }
+        (csv, mime::TEXT_CSV)
+    } else if format == "json" {
+        let exported : Vec<ImportedItem> = items.into_iter().map(|i| ImportedItem{category: i.category, product: i.product}).collect();
+        match serde_json::to_string_pretty(&exported) {
+            Ok(j) => (j, mime::APPLICATION_JSON),
+            Err(e) => return format!("Unable to create JSON: {}", e).into(),
+        }
+    } else if format == "md" {
+        let mut md = "# Grocery list\n".to_string();
+        let mut prev_cat : Option<String> = None;
+        for pro in items {
+            if prev_cat.as_ref() != Some(&pro.category) {
+                md = format!("{}\n## {}\n", md, pro.category);
+                prev_cat = Some(pro.category.clone());
+            }
+            md = format!("{}- [ ] {}\n", md, pro.product);
+        }
+        (md, mime::TEXT_PLAIN_UTF_8)
+    } else {
+        return String::from(GROCERY_HELP).into();
+    };
+    return Reply::File{
+        name: format!("groceries-{}.{}", Local::today().naive_local().format("%Y-%m-%d"), format),
+        content_type: content_type,
+        data: data.into_bytes(),
+    };
+}
+
+fn parse_csv_import(data: &str) -> Result<Vec<ImportedItem>, Error> {
+    let lines : Vec<&str> = data.lines().filter(|l| l.trim() != "").collect();
+    if lines.len() == 0 {
+        return Ok(vec![]);
+    }
+    let header : Vec<String> = csv::parse_line(lines[0]).iter().map(|f| f.to_lowercase()).collect();
+    // Without a header the columns are expected to be category,product
+    let (cat_idx, prod_idx, skip) = match (header.iter().position(|f| f == "category"), header.iter().position(|f| f == "product")) {
+        (Some(c), Some(p)) => (c, p, 1),
+        _ => (0, 1, 0),
+    };
+    let mut items : Vec<ImportedItem> = vec![];
+    for line in lines.into_iter().skip(skip) {
+        let fields = csv::parse_line(line);
+        match (fields.get(cat_idx), fields.get(prod_idx)) {
+            (Some(c), Some(p)) if p != "" => items.push(ImportedItem{category: c.clone(), product: p.clone()}),
+            _ => return Err(Error::InvalidInput(format!("Invalid CSV line \"{}\", expected category,product", line))),
+        };
+    }
+    return Ok(items);
+}
+
+fn parse_md_import(data: &str) -> Vec<ImportedItem> {
+    let mut items : Vec<ImportedItem> = vec![];
+    let mut category = "imported".to_string();
+    for line in data.lines() {
+        let line = line.trim();
+        if line.starts_with("## ") {
+            category = line.trim_start_matches('#').trim().to_string();
+        } else if line.starts_with("- ") || line.starts_with("* ") {
+            let product = line[2..].trim().trim_start_matches("[ ]").trim_start_matches("[x]").trim();
+            if product != "" {
+                items.push(ImportedItem{category: category.clone(), product: product.to_string()});
+            }
+        }
+    }
+    return items;
+}
+
+fn parse_import(format: &str, data: &str) -> Result<Vec<ImportedItem>, Error> {
+    let format = format.to_lowercase();
+    if format == "json" {
+        match serde_json::from_str::<Vec<ImportedItem>>(data) {
+            Ok(i) => return Ok(i),
+            Err(e) => return Err(Error::InvalidInput(format!("Invalid JSON, expected a list of {{\"category\", \"product\"}} objects: {}", e))),
+        };
+    } else if format == "md" {
+        return Ok(parse_md_import(data));
+    } else if format == "csv" {
+        return parse_csv_import(data);
+    }
+    return Err(Error::InvalidInput(format!("Unknown import format \"{}\"", format)));
+}
+
+async fn import_items(items: Vec<ImportedItem>, db: Box<db::Homechatbotdb>) -> String {
+    let count = items.len();
+    let mut categories : Vec<String> = vec![];
+    for i in items.iter() {
+        if !categories.contains(&i.category) {
+            categories.push(i.category.clone());
+        }
+    }
+    for cat in categories {
+        let products : Vec<String> = items.iter().filter(|i| i.category == cat).map(|i| i.product.clone()).collect();
+        match add_products(cat.as_str(), products, db.clone()).await {
+            Ok(_) => {},
+            Err(e) => return error::report(&e),
+        };
+    }
+    return format!("{} items imported!", count);
+}
+
+async fn handle_import_request(cmd_rest: Option<&str>, sender: &str, db: Box<db::Homechatbotdb>) -> String {
+    let cmd_rest = match cmd_rest {
+        Some(c) if c.trim() != "" => c,
+        _ => {
+            match db.remove_data(PENDING_IMPORT_COLLECTION_NAME, doc!{"sender": sender}).await {
+                Ok(_) => {},
+                Err(e) => return error::report(&e),
+            };
+            match db.insert_data_to_collection(PENDING_IMPORT_COLLECTION_NAME, vec![doc!{"sender": sender, "requested": Utc::now().timestamp()}]).await {
+                Ok(_) => return "Send the file to import (csv, json or md)".to_string(),
+                Err(e) => return error::report(&e),
+            };
+        },
+    };
+    let re = match Regex::new(r"^(?s)(\w+)\s*\n(.*)$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)),
+    };
+    let caps = match re.captures(cmd_rest) {
+        Some(c) => c,
+        None => return String::from(GROCERY_HELP),
+    };
+    let items = match parse_import(caps.get(1).map_or("", |c| c.as_str()), caps.get(2).map_or("", |c| c.as_str())) {
+        Ok(i) => i,
+        Err(e) => return error::report(&e),
+    };
+    return import_items(items, db).await;
+}
+
+// Returns true if the sender asked to import a file and is still within the
+// time window; the request is consumed either way
+pub async fn take_pending_import(sender: &str, db: Box<db::Homechatbotdb>) -> Result<bool, Error> {
+    let pending = db.get_generic_data_collection::<PendingImport>(PENDING_IMPORT_COLLECTION_NAME, doc!{"sender": sender}, doc!{}).await?;
+    if pending.len() == 0 {
+        return Ok(false);
+    }
+    db.remove_data(PENDING_IMPORT_COLLECTION_NAME, doc!{"sender": sender}).await?;
+    let now = Utc::now().timestamp();
+    return Ok(pending.iter().any(|p| now - p.requested <= PENDING_IMPORT_TIMEOUT_SECS));
+}
+
+pub async fn handle_import_file(filename: &str, data: Vec<u8>, db: Box<db::Homechatbotdb>) -> String {
+    let data = match String::from_utf8(data) {
+        Ok(d) => d,
+        Err(e) => return format!("The file is not a text file: {}", e),
+    };
+    let lower = filename.to_lowercase();
+    let format = if lower.ends_with(".json") || data.trim_start().starts_with('[') {
+        "json"
+    } else if lower.ends_with(".md") || lower.ends_with(".markdown") || data.trim_start().starts_with('#') {
+        "md"
+    } else {
+        "csv"
+    };
+    let items = match parse_import(format, data.as_str()) {
+        Ok(i) => i,
+        Err(e) => return error::report(&e),
+    };
+    return import_items(items, db).await;
+}
@@ -0,0 +1,316 @@
+use crate::admin;
+use crate::db;
+use crate::error::{self, Error};
+use crate::migrate;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use mongodb::bson::{doc, to_document};
+use std::time::Duration;
+
+const ALIAS_COLLECTION_NAME : &str = "ha_aliases";
+const HTTP_TIMEOUT_SECS : u64 = 10;
+pub const COLLECTIONS : &[migrate::Collection] = &[
+    migrate::Collection{name: ALIAS_COLLECTION_NAME, index: Some("alias")},
+];
+const HA_HELP : &str = "Home Assistant allowed commands:
+    state {entity}
+    call {domain.service} {entity} [key=value ...]
+    aliases
+    alias add {alias} {entity_id} (for the users in \"admin_users\")
+    alias rem {alias} (for the users in \"admin_users\")";
+
+// {ha_url, ha_token} in the config collection, the token being a long-lived
+// access token created in the Home Assistant profile
+#[derive(Debug, Serialize, Deserialize)]
+struct HaConfig {
+    ha_url: String,
+    ha_token: String,
+}
+
+// Who may touch which entities. Entities are entity IDs where a "*" matches
+// anything, e.g. "light.kids_*" or "sensor.*". Without any role every
+// allowed user may use every entity.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct HaRole {
+    role: String,
+    users: Vec<String>,
+    entities: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HaRoles {
+    ha_roles: Vec<HaRole>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Alias {
+    alias: String,
+    entity_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EntityState {
+    pub entity_id: String,
+    pub state: String,
+    #[serde(default)]
+    pub attributes: serde_json::Map<String, serde_json::Value>,
+}
+
+pub struct HaClient {
+    client: reqwest::Client,
+    base_url: String,
+    token: String,
+}
+
+impl HaClient {
+    pub fn new(base_url: &str, token: &str) -> Result<HaClient, Error> {
+        let client = match reqwest::Client::builder().timeout(Duration::from_secs(HTTP_TIMEOUT_SECS)).build() {
+            Ok(c) => c,
+            Err(e) => return Err(Error::from_http("Unable to create HTTP client", e)),
+        };
+        return Ok(HaClient{client: client, base_url: base_url.trim_end_matches('/').to_string(), token: token.to_string()});
+    }
+
+    pub async fn get_state(&self, entity_id: &str) -> Result<EntityState, Error> {
+        let resp = match self.client.get(format!("{}/api/states/{}", self.base_url, entity_id)).bearer_auth(&self.token).send().await {
+            Ok(r) => r,
+            Err(e) => return Err(Error::from_http("Unable to reach Home Assistant", e)),
+        };
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(Error::NotFound(format!("Home Assistant has no entity {}", entity_id)));
+        }
+        let resp = match resp.error_for_status() {
+            Ok(r) => r,
+            Err(e) => return Err(Error::from_http("Home Assistant refused the state request", e)),
+        };
+        match resp.json::<EntityState>().await {
+            Ok(s) => return Ok(s),
+            Err(e) => return Err(Error::from_http("Unexpected answer from Home Assistant", e)),
+        };
+    }
+
+    pub async fn call_service(&self, domain: &str, service: &str, data: serde_json::Value) -> Result<(), Error> {
+        let url = format!("{}/api/services/{}/{}", self.base_url, domain, service);
+        let resp = match self.client.post(url).bearer_auth(&self.token).json(&data).send().await {
+            Ok(r) => r,
+            Err(e) => return Err(Error::from_http("Unable to reach Home Assistant", e)),
+        };
+        if resp.status() == reqwest::StatusCode::BAD_REQUEST || resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(Error::InvalidInput(format!("Home Assistant does not know the service {}.{} or its data", domain, service)));
+        }
+        match resp.error_for_status() {
+            Ok(_) => return Ok(()),
+            Err(e) => return Err(Error::from_http("Home Assistant refused the service call", e)),
+        };
+    }
+}
+
+pub async fn handle_ha_command(cmd: String, sender: &str, db: Box<db::Homechatbotdb>) -> String {
+    let re = match Regex::new(r"^(\w+)(?:\s+(.*))?$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)),
+    };
+    let caps = match re.captures(cmd.trim()) {
+        Some(c) => c,
+        None => return String::from(HA_HELP),
+    };
+    let action = caps.get(1).map_or("".to_string(), |c| c.as_str().to_lowercase());
+    let rest = caps.get(2).map_or("", |c| c.as_str().trim());
+    if action == "aliases" {
+        return handle_aliases_request(db).await;
+    } else if action == "alias" {
+        return handle_alias_command(rest, sender, db).await;
+    } else if rest == "" {
+        return String::from(HA_HELP);
+    }
+    let res = if action == "state" {
+        handle_state_request(rest, sender, db).await
+    } else if action == "call" {
+        handle_call_request(rest, sender, db).await
+    } else {
+        return String::from(HA_HELP);
+    };
+    match res {
+        Ok(msg) => return msg,
+        Err(e) => {
+            let msg = error::report(&e);
+            if let Error::ConnectionLost(_) = e {
+                return String::from("Home Assistant is not reachable at the moment");
+            }
+            return msg;
+        },
+    };
+}
+
+async fn get_client(db: Box<db::Homechatbotdb>) -> Result<HaClient, Error> {
+    let mut items = db.get_generic_data_collection::<HaConfig>(db::CONFIG_COLLECTION_NAME, doc!{"ha_url": {"$exists": true}}, doc!{}).await?;
+    match items.pop() {
+        Some(c) => return HaClient::new(c.ha_url.as_str(), c.ha_token.as_str()),
+        None => return Err(Error::NotFound(String::from("Home Assistant is not configured, set \"ha_url\" and \"ha_token\" in the config collection"))),
+    };
+}
+
+// domain.object_id as Home Assistant names them. Entity IDs end up in the
+// URL path, so nothing else may get through or "../" could reach entities
+// the sender's roles do not allow.
+fn is_entity_id(s: &str) -> bool {
+    let re = match Regex::new(r"^[a-z0-9_]+\.[a-z0-9_]+$") {
+        Ok(r) => r,
+        Err(_) => return false,
+    };
+    return re.is_match(s);
+}
+
+// An alias or an entity ID
+async fn resolve_entity(name: &str, db: Box<db::Homechatbotdb>) -> Result<String, Error> {
+    let name = name.trim().to_lowercase();
+    let mut items = db.get_generic_data_collection::<Alias>(ALIAS_COLLECTION_NAME, doc!{"alias": name.as_str()}, doc!{}).await?;
+    let entity_id = match items.pop() {
+        Some(a) => a.entity_id,
+        None => name,
+    };
+    if is_entity_id(entity_id.as_str()) {
+        return Ok(entity_id);
+    }
+    return Err(Error::NotFound(format!("\"{}\" is neither an alias nor an entity ID", entity_id)));
+}
+
+fn pattern_matches(pattern: &str, entity_id: &str) -> bool {
+    let parts : Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == entity_id;
+    }
+    let mut rest = entity_id;
+    for (i, p) in parts.iter().enumerate() {
+        if i == 0 {
+            match rest.strip_prefix(p) {
+                Some(r) => rest = r,
+                None => return false,
+            };
+        } else if i == parts.len() - 1 {
+            return rest.ends_with(p);
+        } else {
+            match rest.find(p) {
+                Some(pos) => rest = &rest[pos + p.len()..],
+                None => return false,
+            };
+        }
+    }
+    return true;
+}
+
+async fn check_allowed(sender: &str, entity_id: &str, db: Box<db::Homechatbotdb>) -> Result<(), Error> {
+    let roles : Vec<HaRole> = db.get_generic_data_collection::<HaRoles>(db::CONFIG_COLLECTION_NAME, doc!{"ha_roles": {"$exists": true}}, doc!{}).await?
+        .into_iter().flat_map(|r| r.ha_roles).collect();
+    if roles.len() == 0 {
+        return Ok(());
+    }
+    let allowed = roles.iter()
+        .filter(|r| r.users.iter().any(|u| u == sender))
+        .any(|r| r.entities.iter().any(|p| pattern_matches(p.as_str(), entity_id)));
+    if allowed {
+        return Ok(());
+    }
+    return Err(Error::InvalidInput(format!("You are not allowed to use {}", entity_id)));
+}
+
+fn format_state(s: &EntityState) -> String {
+    let name = s.attributes.get("friendly_name").and_then(|n| n.as_str()).unwrap_or(s.entity_id.as_str());
+    let unit = s.attributes.get("unit_of_measurement").and_then(|u| u.as_str()).unwrap_or("");
+    if unit == "" {
+        return format!("{}: {}", name, s.state);
+    }
+    return format!("{}: {} {}", name, s.state, unit);
+}
+
+async fn handle_state_request(name: &str, sender: &str, db: Box<db::Homechatbotdb>) -> Result<String, Error> {
+    let entity_id = resolve_entity(name, db.clone()).await?;
+    check_allowed(sender, entity_id.as_str(), db.clone()).await?;
+    let state = get_client(db).await?.get_state(entity_id.as_str()).await?;
+    return Ok(format_state(&state));
+}
+
+// "key=value" pairs become service data; numbers and booleans keep their type
+fn parse_service_data(pairs: &[&str], data: &mut serde_json::Map<String, serde_json::Value>) -> Result<(), Error> {
+    for p in pairs {
+        let (k, v) = match p.split_once('=') {
+            Some((k, v)) if k != "" => (k, v),
+            _ => return Err(Error::InvalidInput(format!("Invalid service data \"{}\", expected key=value", p))),
+        };
+        let value = match serde_json::from_str::<serde_json::Value>(v) {
+            Ok(j) if j.is_number() || j.is_boolean() => j,
+            _ => serde_json::Value::String(v.to_string()),
+        };
+        data.insert(k.to_string(), value);
+    }
+    return Ok(());
+}
+
+async fn handle_call_request(cmd_rest: &str, sender: &str, db: Box<db::Homechatbotdb>) -> Result<String, Error> {
+    let parts : Vec<&str> = cmd_rest.split_whitespace().collect();
+    if parts.len() < 2 {
+        return Err(Error::InvalidInput(String::from(HA_HELP)));
+    }
+    // Services are named like entities, and end up in the URL path as well
+    let (domain, service) = match parts[0].split_once('.') {
+        Some((d, s)) if is_entity_id(parts[0].to_lowercase().as_str()) => (d.to_lowercase(), s.to_lowercase()),
+        _ => return Err(Error::InvalidInput(format!("Invalid service \"{}\", expected domain.service", parts[0]))),
+    };
+    let entity_id = resolve_entity(parts[1], db.clone()).await?;
+    check_allowed(sender, entity_id.as_str(), db.clone()).await?;
+    let mut data = serde_json::Map::new();
+    data.insert("entity_id".to_string(), serde_json::Value::String(entity_id.clone()));
+    parse_service_data(&parts[2..], &mut data)?;
+    get_client(db).await?.call_service(domain.as_str(), service.as_str(), serde_json::Value::Object(data)).await?;
+    return Ok(format!("Called {}.{} on {}", domain, service, entity_id));
+}
+
+async fn handle_aliases_request(db: Box<db::Homechatbotdb>) -> String {
+    let items = match db.get_generic_data_collection::<Alias>(ALIAS_COLLECTION_NAME, doc!{}, doc!{"alias": 1}).await {
+        Ok(i) => i,
+        Err(e) => return error::report(&e),
+    };
+    if items.len() == 0 {
+        return String::from("No Home Assistant aliases");
+    }
+    let mut msg = "".to_string();
+    for a in items {
+        msg = format!("{}{}: {}\n", msg, a.alias, a.entity_id);
+    }
+    return msg;
+}
+
+// Aliases are shared by everybody, repointing one would have the others act
+// on an entity they did not name, so only admins change them
+async fn handle_alias_command(cmd_rest: &str, sender: &str, db: Box<db::Homechatbotdb>) -> String {
+    let parts : Vec<&str> = cmd_rest.split_whitespace().collect();
+    match admin::is_admin(sender, db.clone()).await {
+        Ok(true) => {},
+        Ok(false) => return String::from("Aliases can only be changed by the users listed in \"admin_users\""),
+        Err(e) => return error::report(&e),
+    };
+    if parts.len() == 3 && parts[0].to_lowercase() == "add" {
+        if !is_entity_id(parts[2].to_lowercase().as_str()) {
+            return format!("\"{}\" is not an entity ID, expected domain.name", parts[2]);
+        }
+        let alias = Alias{alias: parts[1].to_lowercase(), entity_id: parts[2].to_lowercase()};
+        let adoc = match to_document(&alias) {
+            Ok(d) => d,
+            Err(e) => return format!("Unable to serialize alias: {}", e),
+        };
+        match db.remove_data(ALIAS_COLLECTION_NAME, doc!{"alias": alias.alias.as_str()}).await {
+            Ok(_) => {},
+            Err(e) => return error::report(&e),
+        };
+        match db.insert_data_to_collection(ALIAS_COLLECTION_NAME, vec![adoc]).await {
+            Ok(_) => return format!("Alias \"{}\" now points to {}", alias.alias, alias.entity_id),
+            Err(e) => return error::report(&e),
+        };
+    } else if parts.len() == 2 && parts[0].to_lowercase() == "rem" {
+        match db.remove_data(ALIAS_COLLECTION_NAME, doc!{"alias": parts[1].to_lowercase()}).await {
+            Ok(_) => return format!("Alias \"{}\" removed", parts[1].to_lowercase()),
+            Err(e) => return error::report(&e),
+        };
+    }
+    return String::from(HA_HELP);
+}
@@ -0,0 +1,67 @@
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use regex::Regex;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+const ENV_VAR_HOMECHATBOT_LOG : &str = "HOMECHATBOT_LOG";
+const ENV_VAR_HOMECHATBOT_LOG_FORMAT : &str = "HOMECHATBOT_LOG_FORMAT";
+const ENV_VAR_HOMECHATBOT_LOG_REDACT : &str = "HOMECHATBOT_LOG_REDACT";
+const DEFAULT_LOG_FILTER : &str = "info";
+// The command line tools print replies on stdout, only problems are logged
+const DEFAULT_CLI_LOG_FILTER : &str = "warn";
+
+static REDACT_CONTENT : AtomicBool = AtomicBool::new(true);
+
+// Sets up the global logger. The filter uses the usual "target=level"
+// syntax, e.g. "info,home_chatbot::grocery=debug". Message contents are
+// redacted unless HOMECHATBOT_LOG_REDACT is set to "false". With cli set,
+// logs go to stderr so they do not mix with the replies.
+pub fn init(cli: bool) {
+    let default_filter = if cli { DEFAULT_CLI_LOG_FILTER } else { DEFAULT_LOG_FILTER };
+    let filter = match env::var(ENV_VAR_HOMECHATBOT_LOG) {
+        Ok(f) if f != "" => f,
+        _ => String::from(default_filter),
+    };
+    let filter = match EnvFilter::try_new(filter.as_str()) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Invalid log filter \"{}\", falling back to \"{}\": {}", filter, default_filter, e);
+            EnvFilter::new(default_filter)
+        },
+    };
+    let redact = match env::var(ENV_VAR_HOMECHATBOT_LOG_REDACT) {
+        Ok(r) => r.to_lowercase() != "false" && r != "0",
+        Err(_) => true,
+    };
+    REDACT_CONTENT.store(redact, Ordering::Relaxed);
+    let json = match env::var(ENV_VAR_HOMECHATBOT_LOG_FORMAT) {
+        Ok(f) => f.to_lowercase() == "json",
+        Err(_) => false,
+    };
+    let writer = if cli { BoxMakeWriter::new(std::io::stderr) } else { BoxMakeWriter::new(std::io::stdout) };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_target(true).with_writer(writer);
+    if json {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+}
+
+// Replaces user provided content (message bodies, file names, ...) with its
+// length, unless redaction was disabled
+pub fn redact(content: &str) -> String {
+    if REDACT_CONTENT.load(Ordering::Relaxed) {
+        return format!("[redacted, {} chars]", content.chars().count());
+    }
+    return content.to_string();
+}
+
+// Masks credentials embedded in connection strings, e.g. in error messages
+pub fn redact_credentials(msg: &str) -> String {
+    let re = match Regex::new(r"://[^@/\s]+@") {
+        Ok(r) => r,
+        Err(_) => return msg.to_string(),
+    };
+    return re.replace_all(msg, "://***@").to_string();
+}
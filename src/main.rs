@@ -1,235 +1,671 @@
 use std::convert::TryFrom;
 use matrix_sdk::{
-    BaseRoom, Client, SyncSettings, Result, room::Room, room::Common,
-    ruma::{UserId, events::{SyncMessageEvent, AnyMessageEventContent, room::message::MessageEventContent, room::message::MessageType}},
+    BaseRoom, Client, LoopCtrl, SyncSettings, Result, room::Room, room::Common,
+    ruma::{UserId, api::client::r0::session::logout, events::{SyncMessageEvent, AnyMessageEventContent, room::message::MessageEventContent, room::message::MessageType}},
 };
 use matrix_sdk_common::uuid::Uuid;
-use std::{thread, time, env, process};
+use std::{time, env, process};
+use std::net::SocketAddr;
+use std::sync::Arc;
 use regex::Regex;
+use tracing::{debug, error, info, warn};
 
+mod addressing;
+mod admin;
+mod backup;
 mod bgchan;
+mod cli;
+mod csv;
 mod db;
+mod error;
+mod events;
+mod expense;
 mod grocery;
+mod ha;
+mod logging;
+mod meal;
+mod metrics;
+mod migrate;
+mod mqtt;
+mod pantry;
+mod ratelimit;
+mod recipe;
+mod registry;
+mod reply;
+mod supervisor;
+#[cfg(test)]
+mod tests;
+mod webhook;
 
 const ENV_VAR_HOMECHATBOT_USERNAME : &str = "HOMECHATBOT_USERNAME";
 const ENV_VAR_HOMECHATBOT_PASSWORD : &str = "HOMECHATBOT_PASSWORD";
-const ENV_VAR_HOMECHATBOT_MONGO_ADDRESS : &str = "HOMECHATBOT_MONGO_ADDRESS";
-const ENV_VAR_HOMECHATBOT_MONGO_USERNAME : &str = "HOMECHATBOT_MONGO_USERNAME";
-const ENV_VAR_HOMECHATBOT_MONGO_PASSWORD : &str = "HOMECHATBOT_MONGO_PASSWORD";
+const ENV_VAR_HOMECHATBOT_METRICS_ADDRESS : &str = "HOMECHATBOT_METRICS_ADDRESS";
+const ENV_VAR_HOMECHATBOT_WEBHOOK_ADDRESS : &str = "HOMECHATBOT_WEBHOOK_ADDRESS";
+const ENV_VAR_HOMECHATBOT_COMMAND_PREFIX : &str = "HOMECHATBOT_COMMAND_PREFIX";
+const SHUTDOWN_TIMEOUT_SECS : u64 = 30;
+const DB_WATCH_INTERVAL_SECS : u64 = 30;
+const TV_SCHEDULE_INTERVAL_SECS : u64 = 20;
 
 async fn do_check_rooms(client: Box<Client>, db: Box<db::Homechatbotdb>) -> Result<()> {
     loop {
-        let client_rooms = client.invited_rooms();
-        if client_rooms.len() > 0 {
-            println!("Number of rooms invited into: {}", client_rooms.len());
-        }
-        for cr in client_rooms {
-            println!("Invited room details: {:?}", cr);
-            let cm : &Common = &(*cr); // Deref trait to get inner of type Common
-            let br : &BaseRoom = &(*cm); // Deref trait to get inner of type BaseRoom
-            let cc = match br.create_content() {
-                Some(cc) => cc.creator.into_string(),
-                None => String::from("(none)"),
+        check_invites(&client, db.clone()).await;
+        tokio::time::sleep(time::Duration::from_secs(1)).await;
+    }
+}
+
+// Accepts the pending invitations from allowed users, rejects the others
+async fn check_invites(client: &Client, db: Box<db::Homechatbotdb>) {
+    let client_rooms = client.invited_rooms();
+    if client_rooms.len() > 0 {
+        info!(count = client_rooms.len(), "Invited into rooms");
+    }
+    for cr in client_rooms {
+        let cm : &Common = &(*cr); // Deref trait to get inner of type Common
+        let br : &BaseRoom = &(*cm); // Deref trait to get inner of type BaseRoom
+        let cc = match br.create_content() {
+            Some(cc) => cc.creator.into_string(),
+            None => String::from("(none)"),
+        };
+        if db.is_valid_inviting_user(&cc).await {
+            match cr.accept_invitation().await {
+                Ok(_) => {
+                    metrics::record_invite(true);
+                    info!(room = %br.room_id(), "Room joined")
+                },
+                Err(_) => {
+                    warn!(room = %br.room_id(), "Unable to join room")
+                },
+            };
+        } else {
+            info!(inviter = %cc, room = %br.room_id(), "Rejecting invitation");
+            match cr.reject_invitation().await {
+                Ok(_) => {
+                    metrics::record_invite(false);
+                    info!(room = %br.room_id(), "Room rejected")
+                },
+                Err(_) => {
+                    warn!(room = %br.room_id(), "Unable to reject room")
+                },
             };
-            if db.is_valid_inviting_user(&cc).await {
-                match cr.accept_invitation().await {
-                    Ok(_) => {
-                        println!("Room {} joined!", br.room_id())
-                    },
-                    Err(_) => {
-                        println!("Unable to join room {}", br.room_id())
-                    },
-                };
-            } else {
-                println!("Rejecting invitation from {}", cc);
-                match cr.reject_invitation().await {
-                    Ok(_) => {
-                        println!("Room {} rejected!", br.room_id())
-                    },
-                    Err(_) => {
-                        println!("Unable to reject room {}", br.room_id())
-                    },
-                };
-            }
         }
-        thread::sleep(time::Duration::from_secs(1));
     }
 }
 
-async fn message_triage(msg: String, db: Box<db::Homechatbotdb>) -> String {
+async fn do_check_pantry(client: Box<Client>, db: Box<db::Homechatbotdb>) {
+    loop {
+        match pantry::get_expiry_warnings(db.clone()).await {
+            Ok(Some((rooms, msg))) => {
+                for room in rooms {
+                    reply::send_notification(&client, room.as_str(), msg.as_str()).await;
+                }
+            },
+            Ok(None) => {},
+            Err(e) => {
+                metrics::record_error("pantry");
+                error!("Unable to check pantry expiry dates: {}", e);
+            },
+        };
+        tokio::time::sleep(time::Duration::from_secs(3600)).await;
+    }
+}
+
+async fn do_run_tv_schedule(client: Box<Client>, db: Box<db::Homechatbotdb>) {
+    loop {
+        match bgchan::run_scheduled_actions(db.clone()).await {
+            Ok(results) => {
+                for (room, msg) in results {
+                    reply::send_notification(&client, room.as_str(), msg.as_str()).await;
+                }
+            },
+            Err(e) => {
+                metrics::record_error("tv_schedule");
+                error!("Unable to run scheduled TV actions: {}", e);
+            },
+        };
+        tokio::time::sleep(time::Duration::from_secs(TV_SCHEDULE_INTERVAL_SECS)).await;
+    }
+}
+
+async fn do_run_nightly_backup(db: Box<db::Homechatbotdb>) {
+    let mut last = None;
+    loop {
+        match backup::run_scheduled(&mut last, db.clone()).await {
+            Ok(Some(path)) => info!(path = %path, "Nightly backup written"),
+            Ok(None) => {},
+            Err(e) => {
+                metrics::record_error("backup");
+                error!("Unable to write the nightly backup: {}", e);
+            },
+        };
+        tokio::time::sleep(time::Duration::from_secs(60)).await;
+    }
+}
+
+async fn do_relay_mqtt(client: Box<Client>, bridge: mqtt::Bridge) {
+    loop {
+        match bridge.next_notifications().await {
+            Ok(notes) => {
+                for (room, msg) in notes {
+                    reply::send_notification(&client, room.as_str(), msg.as_str()).await;
+                }
+            },
+            Err(e) => {
+                // Returning lets the supervisor back off before reconnecting
+                metrics::record_error("mqtt");
+                error!("{}", e);
+                return;
+            },
+        };
+    }
+}
+
+async fn do_watch_db(db: Box<db::Homechatbotdb>) {
+    let mut reachable = true;
+    loop {
+        match db.ping().await {
+            Ok(_) => {
+                if !reachable {
+                    info!("Database connection restored");
+                }
+                reachable = true;
+            },
+            Err(e) => {
+                if reachable {
+                    error!("Database connection lost: {}", logging::redact_credentials(&e.to_string()));
+                }
+                reachable = false;
+            },
+        };
+        tokio::time::sleep(time::Duration::from_secs(DB_WATCH_INTERVAL_SECS)).await;
+    }
+}
+
+async fn logout(client: &Client) {
+    match client.send(logout::Request::new(), None).await {
+        Ok(_) => info!("Logged out"),
+        Err(e) => warn!("Unable to log out: {}", e),
+    };
+}
+
+async fn message_triage(msg: String, sender: &str, room: &str, is_dm: bool, registry: &registry::Registry, db: Box<db::Homechatbotdb>) -> reply::Reply {
+    // The answer to a question the bot asked this sender, e.g. after "gro done"
+    match grocery::handle_done_answer(msg.as_str(), sender, room, db.clone()).await {
+        Ok(Some(answer)) => return answer.into(),
+        Ok(None) => {},
+        Err(e) => return error::report(&e).into(),
+    };
     if msg.to_lowercase().trim() == "test" {
-        return String::from("running");
+        return String::from("running").into();
     } else if msg.to_lowercase().trim() == "help" {
-        return String::from("The following commands are currently supported:
-    bgchan
-    gro / grocery");
+        return registry.help().into();
     }
     let re = match Regex::new(r"^(?s)(\w+)\s+(.*)$") {
         Ok(r) => r,
-        Err(e) => return String::from(format!("ERROR: {}", e)),
+        Err(e) => return String::from(format!("ERROR: {}", e)).into(),
     };
     let caps = match re.captures(msg.as_str()) {
         Some(c) => c,
-        None => return String::from("UNKNOWN"),
+        None => return reply::Reply::Unknown,
     };
     let cmd = match caps.get(1) {
         Some(c) => c.as_str().to_lowercase(),
-        None => return String::from("UNKNOWN"),
+        None => return reply::Reply::Unknown,
+    };
+    debug!(command = %cmd, "Got command");
+    let command = match registry.find(cmd.as_str()) {
+        Some(c) => c,
+        None => {
+            metrics::record_command("unknown");
+            return reply::Reply::Unknown;
+        },
+    };
+    metrics::record_command(command.label.as_str());
+    let req = registry::Request{
+        args: caps.get(2).map_or("", |c| c.as_str()).to_string(),
+        sender: sender.to_string(),
+        room: room.to_string(),
+        is_dm: is_dm,
+        db: db,
+    };
+    return (command.handler)(req).await;
+}
+
+async fn send_reply(client: &Client, room: &Room, reply: reply::Reply) {
+    let cm : &Common = &(*room); // Deref trait to get inner of type Common
+    let br : &BaseRoom = &(*cm); // Deref trait to get inner of type BaseRoom
+    match reply {
+        // handle_message decides how unknown commands are answered
+        reply::Reply::Unknown => {},
+        reply::Reply::Text(msg) => {
+            let txt_msg = AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(msg));
+            let txn_id = Uuid::new_v4();
+            match client.room_send(br.room_id(), txt_msg, Some(txn_id)).await {
+                Ok(r) => {
+                    debug!(event = %r.event_id, "Response successfully sent")
+                },
+                Err(e) => {
+                    metrics::record_error("send");
+                    error!("Unable to send response: {:?}", e)
+                },
+            };
+        },
+        reply::Reply::File{name, content_type, data} => {
+            let joined = match room {
+                Room::Joined(j) => j,
+                _ => {
+                    error!(room = %br.room_id(), "Unable to send file to a room which is not joined");
+                    return;
+                },
+            };
+            match joined.send_attachment(name.as_str(), &content_type, &mut data.as_slice(), None).await {
+                Ok(r) => {
+                    debug!(event = %r.event_id, "File successfully sent")
+                },
+                Err(e) => {
+                    metrics::record_error("send");
+                    error!("Unable to send file: {:?}", e)
+                },
+            };
+        },
     };
-    println!("Got command: {}", cmd);
-    if cmd == "bgchan" {
-        let rest_command = match caps.get(2) {
-            Some(c) => c.as_str(),
-            None => return String::from("UNKNOWN"),
-        };
-        return bgchan::handle_bgchan_command(rest_command.to_string()).await;
-    } else if cmd == "gro" || cmd == "grocery" {
-        let rest_command = match caps.get(2) {
-            Some(c) => c.as_str(),
-            None => return String::from("UNKNOWN"),
-        };
-        return grocery::handle_grocery_command(rest_command.to_string(), db).await;
-    }
-    return String::from("UNKNOWN");
 }
 
-async fn handle_message<'a>(ev: SyncMessageEvent<MessageEventContent>, room: Room, client: Client, db: Box<db::Homechatbotdb>) {
+// Returns false if the sender or the room sent too many commands lately.
+// The first refused command gets a reply, the next ones are dropped.
+async fn check_rate_limit(client: &Client, room: &Room, sender: &str, limiter: &ratelimit::Limiter) -> bool {
+    let cm : &Common = &(*room); // Deref trait to get inner of type Common
+    let br : &BaseRoom = &(*cm); // Deref trait to get inner of type BaseRoom
+    match limiter.acquire(sender, br.room_id().as_str()) {
+        ratelimit::Verdict::Allow => return true,
+        ratelimit::Verdict::CoolDown(secs) => {
+            info!(sender = %sender, room = %br.room_id(), "Rate limit reached");
+            metrics::record_command("rate_limited");
+            let msg = format!("Too many commands, please wait {} seconds", secs);
+            limiter.record_reply(br.room_id().as_str(), msg.as_str());
+            send_reply(client, room, msg.into()).await;
+            return false;
+        },
+        ratelimit::Verdict::Drop => {
+            metrics::record_command("rate_limited");
+            return false;
+        },
+    };
+}
+
+async fn handle_message<'a>(ev: SyncMessageEvent<MessageEventContent>, room: Room, client: Client, db: Box<db::Homechatbotdb>, addr: addressing::Addressing, commands: Arc<registry::Registry>, limiter: ratelimit::Limiter) {
     if let Some(my_user_id) = client.user_id().await {
-        println!("sender check: {:?} {:?}", ev.sender, my_user_id);
         if ev.sender != my_user_id {
-            if let MessageType::Text(cnt) = ev.content.msgtype {
-                let cm : &Common = &(*room); // Deref trait to get inner of type Common
-                let br : &BaseRoom = &(*cm); // Deref trait to get inner of type BaseRoom
-                println!("Received a message {:?}, {:?}", cnt.body, br.room_id());
-                let txt_msg = AnyMessageEventContent::RoomMessage(
-                    MessageEventContent::text_plain(message_triage(cnt.body.trim().to_string(), db).await)
-                );
-                let txn_id = Uuid::new_v4();
-                match client.room_send(br.room_id(), txt_msg, Some(txn_id)).await {
-                    Ok(r) => {
-                        println!("Response successfully sent: {:?}", r)
-                    },
-                    Err(e) => {
-                        eprintln!("Unable to send response: {:?}", e)
-                    },
-                };
-            }
+            let cm : &Common = &(*room); // Deref trait to get inner of type Common
+            let br : &BaseRoom = &(*cm); // Deref trait to get inner of type BaseRoom
+            // Notices are what bots send, answering them is how loops start;
+            // they never get here as only text and files are handled
+            match ev.content.msgtype {
+                MessageType::Text(cnt) => {
+                    debug!(sender = %ev.sender, room = %br.room_id(), body = %logging::redact(&cnt.body), "Received a message");
+                    if limiter.should_ignore(ev.sender.as_str(), br.room_id().as_str(), cnt.body.as_str()) {
+                        debug!(sender = %ev.sender, room = %br.room_id(), "Ignoring message");
+                        return;
+                    }
+                    let is_dm = br.is_direct() || br.active_members().await.map(|m| m.len() <= 2).unwrap_or(false);
+                    let command = match addr.extract_command(cnt.body.as_str(), is_dm) {
+                        Some(c) => c,
+                        None => return,
+                    };
+                    if !check_rate_limit(&client, &room, ev.sender.as_str(), &limiter).await {
+                        return;
+                    }
+                    let reply = message_triage(command, ev.sender.as_str(), br.room_id().as_str(), is_dm, &commands, db.clone()).await;
+                    let reply = match reply {
+                        reply::Reply::Unknown => {
+                            match addressing::is_quiet_room(br.room_id().as_str(), db).await {
+                                Ok(true) => return,
+                                Ok(false) => {},
+                                Err(e) => error!("Unable to check quiet mode: {}", e),
+                            };
+                            String::from("UNKNOWN").into()
+                        },
+                        r => r,
+                    };
+                    if let reply::Reply::Text(msg) = &reply {
+                        limiter.record_reply(br.room_id().as_str(), msg.as_str());
+                    }
+                    send_reply(&client, &room, reply).await;
+                },
+                MessageType::File(file) => {
+                    debug!(sender = %ev.sender, room = %br.room_id(), file = %logging::redact(&file.body), "Received a file");
+                    if limiter.should_ignore(ev.sender.as_str(), br.room_id().as_str(), "") {
+                        return;
+                    }
+                    let restore = match backup::take_pending_restore(ev.sender.as_str(), db.clone()).await {
+                        Ok(r) => r,
+                        Err(e) => {
+                            error!("Unable to check for pending restores: {}", e);
+                            return;
+                        },
+                    };
+                    if !restore {
+                        match grocery::take_pending_import(ev.sender.as_str(), db.clone()).await {
+                            Ok(true) => {},
+                            Ok(false) => return,
+                            Err(e) => {
+                                error!("Unable to check for pending imports: {}", e);
+                                return;
+                            },
+                        };
+                    }
+                    let filename = file.body.clone();
+                    let msg = match client.get_file(file, false).await {
+                        Ok(Some(data)) if restore => backup::handle_restore_file(data, db).await,
+                        Ok(Some(data)) => grocery::handle_import_file(filename.as_str(), data, db).await,
+                        Ok(None) => "The file has no content".to_string(),
+                        Err(e) => format!("Unable to download the file: {}", e),
+                    };
+                    send_reply(&client, &room, msg.into()).await;
+                },
+                _ => {},
+            };
         }
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let matrixusername = match env::var(ENV_VAR_HOMECHATBOT_USERNAME) {
-        Ok(val) => val,
+// Runs commands from the terminal against the database, without Matrix
+async fn run_cli(opts: cli::Options) -> i32 {
+    let mongo = match db::Settings::from_env() {
+        Ok(s) => s,
         Err(e) => {
-            eprintln!("Unable to get value for environment variable {}: {}", ENV_VAR_HOMECHATBOT_USERNAME, e);
-            process::exit(-1);
+            error!("{}", e.user_message());
+            return 1;
         },
     };
-    if matrixusername == "" {
-        eprintln!("Please set the environment variable {}", ENV_VAR_HOMECHATBOT_USERNAME);
-        process::exit(-1);
-    }
-    let matrixpassword = match env::var(ENV_VAR_HOMECHATBOT_PASSWORD) {
-        Ok(val) => val,
+    let db = match db::Homechatbotdb::new(&mongo).await {
+        Ok(d) => Box::new(d),
         Err(e) => {
-            eprintln!("Unable to get value for environment variable {}: {}", ENV_VAR_HOMECHATBOT_PASSWORD, e);
-            process::exit(-1);
+            error!("DB error: {}", logging::redact_credentials(&e.to_string()));
+            return 1;
         },
     };
-    if matrixpassword == "" {
-        eprintln!("Please set the environment variable {}", ENV_VAR_HOMECHATBOT_PASSWORD);
-        process::exit(-1);
+    let commands = registry::builtin();
+    return cli::run(opts, &commands, db).await;
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args : Vec<String> = env::args().skip(1).collect();
+    let cli_opts = cli::parse_args(&args);
+    logging::init(cli_opts.is_some());
+    if let Some(opts) = cli_opts {
+        match opts {
+            Ok(o) => process::exit(run_cli(o).await),
+            Err(usage) => {
+                eprintln!("{}", usage);
+                process::exit(2);
+            },
+        };
     }
-    let mongodbaddress = match env::var(ENV_VAR_HOMECHATBOT_MONGO_ADDRESS) {
+
+    let matrixusername = match env::var(ENV_VAR_HOMECHATBOT_USERNAME) {
         Ok(val) => val,
         Err(e) => {
-            eprintln!("Unable to get value for environment variable {}: {}", ENV_VAR_HOMECHATBOT_MONGO_ADDRESS, e);
+            error!("Unable to get value for environment variable {}: {}", ENV_VAR_HOMECHATBOT_USERNAME, e);
             process::exit(-1);
         },
     };
-    if mongodbaddress == "" {
-        eprintln!("Please set the environment variable {}", ENV_VAR_HOMECHATBOT_MONGO_ADDRESS);
+    if matrixusername == "" {
+        error!("Please set the environment variable {}", ENV_VAR_HOMECHATBOT_USERNAME);
         process::exit(-1);
     }
-    let mongodbuname = match env::var(ENV_VAR_HOMECHATBOT_MONGO_USERNAME) {
+    let matrixpassword = match env::var(ENV_VAR_HOMECHATBOT_PASSWORD) {
         Ok(val) => val,
         Err(e) => {
-            eprintln!("Unable to get value for environment variable {}: {}", ENV_VAR_HOMECHATBOT_MONGO_USERNAME, e);
+            error!("Unable to get value for environment variable {}: {}", ENV_VAR_HOMECHATBOT_PASSWORD, e);
             process::exit(-1);
         },
     };
-    if mongodbuname == "" {
-        eprintln!("Please set the environment variable {}", ENV_VAR_HOMECHATBOT_MONGO_USERNAME);
+    if matrixpassword == "" {
+        error!("Please set the environment variable {}", ENV_VAR_HOMECHATBOT_PASSWORD);
         process::exit(-1);
     }
-    let mongodbpass = match env::var(ENV_VAR_HOMECHATBOT_MONGO_PASSWORD) {
-        Ok(val) => val,
+    let mongo = match db::Settings::from_env() {
+        Ok(s) => s,
         Err(e) => {
-            eprintln!("Unable to get value for environment variable {}: {}", ENV_VAR_HOMECHATBOT_MONGO_PASSWORD, e);
+            error!("{}", e.user_message());
             process::exit(-1);
         },
     };
-    if mongodbpass == "" {
-        eprintln!("Please set the environment variable {}", ENV_VAR_HOMECHATBOT_MONGO_PASSWORD);
-        process::exit(-1);
-    }
-    println!("Env var check passed");
+    info!("Env var check passed");
 
     let user = match UserId::try_from(matrixusername) {
         Ok(us) => us,
         Err(e) => {
-            eprintln!("Unable to create a matrix user object: {}", e);
+            error!("Unable to create a matrix user object: {}", e);
             process::exit(-1);
         },
     };
     let client = match Client::new_from_user_id(user.clone()).await {
         Ok(cl) => Box::new(cl),
         Err(e) => {
-            eprintln!("Unable to create a matrix client object: {}", e);
+            error!("Unable to create a matrix client object: {}", e);
             process::exit(-1);
         },
     };
-    println!("User and client created");
+    info!("User and client created");
+
+    let shutdown = supervisor::Shutdown::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            supervisor::wait_for_signal().await;
+            shutdown.request();
+        }
+    });
 
     // First we need to log in.
-    match client.login(user.localpart(), matrixpassword.as_str(), None, None).await {
-        Ok(_) => (),
+    let login = supervisor::retry("login", &shutdown, || async {
+        match client.login(user.localpart(), matrixpassword.as_str(), None, None).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Unable to login: {}", e)),
+        }
+    }).await;
+    if login.is_none() {
+        info!("Shutdown requested before login");
+        return Ok(());
+    }
+    info!("Successful login");
+
+    let db = match supervisor::retry("database connection", &shutdown, || async {
+        match db::Homechatbotdb::new(&mongo).await {
+            Ok(d) => Ok(d),
+            Err(e) => Err(format!("DB error: {}", logging::redact_credentials(&e.to_string()))),
+        }
+    }).await {
+        Some(d) => Box::new(d),
+        None => {
+            info!("Shutdown requested before the database was reachable");
+            logout(&client).await;
+            return Ok(());
+        },
+    };
+    info!("DB connection successful");
+
+    match migrate::run(db.clone(), false).await {
+        Ok(steps) => {
+            for s in steps {
+                info!("Schema: {}", s);
+            }
+        },
         Err(e) => {
-            eprintln!("Unable to login: {}", e);
+            error!("Unable to migrate the database: {}", e);
+            logout(&client).await;
             process::exit(-1);
         },
     };
-    println!("Successful login");
 
-    let db = Box::new(match db::Homechatbotdb::new(mongodbaddress, mongodbuname, mongodbpass).await {
-        Ok(d) => d,
+    let prefix = match env::var(ENV_VAR_HOMECHATBOT_COMMAND_PREFIX) {
+        Ok(p) if p != "" => Some(p),
+        _ => None,
+    };
+    let mut names = vec![user.to_string(), user.localpart().to_string()];
+    match client.display_name().await {
+        Ok(Some(n)) => names.push(n),
+        Ok(None) => {},
+        Err(e) => warn!("Unable to get the display name: {}", e),
+    };
+    let addr = addressing::Addressing::new(prefix, names);
+
+    let mut commands = registry::builtin();
+    let bridge = match mqtt::connect(db.clone()).await {
+        Ok(b) => b,
         Err(e) => {
-            eprintln!("DB error: {}", e);
-            process::exit(-1);
+            error!("Unable to set up the MQTT bridge: {}", e);
+            None
         },
-    });
-    println!("DB connection successful");
+    };
+    if let Some(b) = &bridge {
+        b.register_commands(&mut commands);
+    }
+    let commands = Arc::new(commands);
+    let limiter = match ratelimit::Limiter::load(db.clone()).await {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Unable to read the rate limits, using the defaults: {}", e);
+            ratelimit::Limiter::new(ratelimit::Limits{user: None, room: None, window_secs: None}, vec![])
+        },
+    };
 
     client.register_event_handler({
             let dbd = db.clone();
+            let shutdown = shutdown.clone();
+            let addr = addr.clone();
+            let commands = commands.clone();
+            let limiter = limiter.clone();
             move |ev: SyncMessageEvent<MessageEventContent>, room: Room, client: Client| {
                 let dbd = dbd.clone();
+                let shutdown = shutdown.clone();
+                let addr = addr.clone();
+                let commands = commands.clone();
+                let limiter = limiter.clone();
                 async move {
-                    handle_message(ev, room, client, dbd).await;
+                    let _guard = match shutdown.begin_command() {
+                        Some(g) => g,
+                        None => return,
+                    };
+                    handle_message(ev, room, client, dbd, addr, commands, limiter).await;
                 }
             }
         }
     ).await;
-    println!("Event registered");
+    info!("Event registered");
+
+    let mut tasks = vec![];
+    tasks.push(supervisor::supervise("room checker", shutdown.clone(), {
+        let client = client.clone();
+        let db = db.clone();
+        move || {
+            let client = client.clone();
+            let db = db.clone();
+            async move {
+                if let Err(e) = do_check_rooms(client, db).await {
+                    error!("Room checker failed: {}", e);
+                }
+            }
+        }
+    }));
+    info!("Room checker is running");
+
+    tasks.push(supervisor::supervise("pantry checker", shutdown.clone(), {
+        let client = client.clone();
+        let db = db.clone();
+        move || do_check_pantry(client.clone(), db.clone())
+    }));
+    info!("Pantry checker is running");
 
-    tokio::spawn(do_check_rooms(client.clone(), db.clone()));
-    println!("Room checker is running");
+    tasks.push(supervisor::supervise("tv scheduler", shutdown.clone(), {
+        let client = client.clone();
+        let db = db.clone();
+        move || do_run_tv_schedule(client.clone(), db.clone())
+    }));
+    info!("TV scheduler is running");
+
+    if let Some(b) = bridge {
+        tasks.push(supervisor::supervise("mqtt bridge", shutdown.clone(), {
+            let client = client.clone();
+            move || do_relay_mqtt(client.clone(), b.clone())
+        }));
+        info!("MQTT bridge is running");
+    }
+
+    tasks.push(supervisor::supervise("event delivery", shutdown.clone(), {
+        let db = db.clone();
+        move || webhook::outbound::run(db.clone())
+    }));
+
+    tasks.push(supervisor::supervise("nightly backup", shutdown.clone(), {
+        let db = db.clone();
+        move || do_run_nightly_backup(db.clone())
+    }));
+
+    tasks.push(supervisor::supervise("database watchdog", shutdown.clone(), {
+        let db = db.clone();
+        move || do_watch_db(db.clone())
+    }));
+
+    match env::var(ENV_VAR_HOMECHATBOT_METRICS_ADDRESS) {
+        Ok(addr) if addr != "" => {
+            match addr.parse::<SocketAddr>() {
+                Ok(a) => {
+                    tasks.push(supervisor::supervise("metrics server", shutdown.clone(), {
+                        let db = db.clone();
+                        move || metrics::serve(a, db.clone())
+                    }));
+                },
+                Err(e) => {
+                    error!("Invalid value for environment variable {}: {}", ENV_VAR_HOMECHATBOT_METRICS_ADDRESS, e);
+                    process::exit(-1);
+                },
+            };
+        },
+        _ => {},
+    };
+
+    match env::var(ENV_VAR_HOMECHATBOT_WEBHOOK_ADDRESS) {
+        Ok(addr) if addr != "" => {
+            match addr.parse::<SocketAddr>() {
+                Ok(a) => {
+                    tasks.push(supervisor::supervise("webhook listener", shutdown.clone(), {
+                        let client = client.clone();
+                        let db = db.clone();
+                        move || webhook::serve(a, (*client).clone(), db.clone())
+                    }));
+                },
+                Err(e) => {
+                    error!("Invalid value for environment variable {}: {}", ENV_VAR_HOMECHATBOT_WEBHOOK_ADDRESS, e);
+                    process::exit(-1);
+                },
+            };
+        },
+        _ => {},
+    };
 
     // Syncing is important to synchronize the client state with the server.
-    // This method will never return.
-    client.clone().sync(SyncSettings::default()).await;
+    // It only stops once a shutdown was requested.
+    tokio::select! {
+        _ = client.sync_with_callback(SyncSettings::default(), |_| async {
+            metrics::record_sync();
+            LoopCtrl::Continue
+        }) => {},
+        _ = shutdown.wait() => {},
+    };
+
+    info!("Shutting down, waiting for commands in progress");
+    let left = shutdown.drain(time::Duration::from_secs(SHUTDOWN_TIMEOUT_SECS)).await;
+    if left > 0 {
+        warn!(commands = left, "Commands still in progress at shutdown");
+    }
+    for t in tasks {
+        let _ = t.await;
+    }
+    logout(&client).await;
+    info!("Shutdown complete");
 
     Ok(())
 }
@@ -0,0 +1,241 @@
+use crate::db;
+use crate::error::{self, Error};
+use crate::grocery;
+use crate::migrate;
+use crate::recipe;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use mongodb::bson::doc;
+
+const MEAL_COLLECTION_NAME : &str = "meals";
+pub const COLLECTIONS : &[migrate::Collection] = &[
+    migrate::Collection{name: MEAL_COLLECTION_NAME, index: Some("day")},
+];
+const MEAL_HELP : &str = "Meal planner allowed commands:
+    list
+    plan {day} {recipe} [servings]
+    clear [day]
+    shop {day|week}
+Days are: mon, tue, wed, thu, fri, sat, sun";
+const WEEK_DAYS : [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Meal {
+    day: String,
+    recipe: String,
+    servings: u32,
+}
+
+pub async fn handle_meal_command(cmd: String, db: Box<db::Homechatbotdb>) -> String {
+    let re = match Regex::new(r"^(?s)(\w+)(?:\s+(.*))?$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)),
+    };
+    let caps = match re.captures(cmd.as_str()) {
+        Some(c) => c,
+        None => return String::from(MEAL_HELP),
+    };
+    let cmd = match caps.get(1) {
+        Some(c) => c.as_str().to_lowercase(),
+        None => return String::from(MEAL_HELP),
+    };
+    if cmd == "list" {
+        return handle_list_request(db).await;
+    } else if cmd == "plan" {
+        match caps.get(2) {
+            Some(c) => return handle_plan_request(c.as_str(), db).await,
+            None => return String::from(MEAL_HELP),
+        };
+    } else if cmd == "clear" {
+        return handle_clear_request(caps.get(2).map(|c| c.as_str()), db).await;
+    } else if cmd == "shop" {
+        match caps.get(2) {
+            Some(c) => return handle_shop_request(c.as_str(), db).await,
+            None => return String::from(MEAL_HELP),
+        };
+    }
+    return String::from(MEAL_HELP);
+}
+
+fn normalize_day(day: &str) -> Option<String> {
+    let day = day.trim().to_lowercase();
+    for wd in WEEK_DAYS.iter() {
+        if day.starts_with(wd) {
+            return Some(wd.to_string());
+        }
+    }
+    return None;
+}
+
+async fn get_meals(fltr: mongodb::bson::Document, db: Box<db::Homechatbotdb>) -> Result<Vec<Meal>, Error> {
+    let mut items = db.get_generic_data_collection::<Meal>(MEAL_COLLECTION_NAME, fltr, doc!{}).await?;
+    items.sort_by_key(|m| WEEK_DAYS.iter().position(|d| *d == m.day).unwrap_or(WEEK_DAYS.len()));
+    return Ok(items);
+}
+
+async fn handle_list_request(db: Box<db::Homechatbotdb>) -> String {
+    let items = match get_meals(doc!{}, db).await {
+        Ok(i) => i,
+        Err(e) => return error::report(&e),
+    };
+    if items.len() == 0 {
+        return "No meals planned".to_string();
+    }
+    let mut msg = "".to_string();
+    for ml in items {
+        msg = format!("{}{}: {} ({} servings)\n", msg, ml.day, ml.recipe, ml.servings);
+    }
+    return msg;
+}
+
+async fn handle_plan_request(cmd_rest: &str, db: Box<db::Homechatbotdb>) -> String {
+    let re = match Regex::new(r"^(\w+)\s+(\S+)(?:\s+(\d+))?\s*$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)),
+    };
+    let caps = match re.captures(cmd_rest.trim()) {
+        Some(c) => c,
+        None => return String::from(MEAL_HELP),
+    };
+    let day = match caps.get(1).and_then(|c| normalize_day(c.as_str())) {
+        Some(d) => d,
+        None => return format!("Unknown day\n{}", MEAL_HELP),
+    };
+    let rname = caps.get(2).map_or("", |c| c.as_str());
+    let rcp = match recipe::get_recipe(rname, db.clone()).await {
+        Ok(Some(r)) => r,
+        Ok(None) => return format!("Recipe \"{}\" not found", rname),
+        Err(e) => return error::report(&e),
+    };
+    let servings = match caps.get(3) {
+        Some(s) => match s.as_str().parse::<u32>() {
+            Ok(n) if n > 0 => n,
+            _ => return format!("Servings must be a positive number\n{}", MEAL_HELP),
+        },
+        None => rcp.servings,
+    };
+    match db.remove_data(MEAL_COLLECTION_NAME, doc!{"day": day.as_str()}).await {
+        Ok(_) => {},
+        Err(e) => return error::report(&e),
+    };
+    match db.insert_data_to_collection(MEAL_COLLECTION_NAME, vec![doc!{"day": day.as_str(), "recipe": rcp.name.as_str(), "servings": servings}]).await {
+        Ok(_) => return format!("Planned {} for {}", rcp.name, day),
+        Err(e) => return error::report(&e),
+    };
+}
+
+async fn handle_clear_request(day: Option<&str>, db: Box<db::Homechatbotdb>) -> String {
+    let fltr = match day {
+        Some(d) => match normalize_day(d) {
+            Some(nd) => doc!{"day": nd},
+            None => return format!("Unknown day\n{}", MEAL_HELP),
+        },
+        None => doc!{},
+    };
+    match db.remove_data(MEAL_COLLECTION_NAME, fltr).await {
+        Ok(_) => return "Meal plan cleared".to_string(),
+        Err(e) => return error::report(&e),
+    };
+}
+
+// Scales a quantity like "250g" or "1.5 l" by the given factor. Quantities
+// without a leading number (e.g. "a pinch") are returned unchanged.
+fn scale_quantity(quantity: &str, factor: f64) -> String {
+    let re = match Regex::new(r"^(\d+(?:[.,]\d+)?)(.*)$") {
+        Ok(r) => r,
+        Err(_) => return quantity.to_string(),
+    };
+    let caps = match re.captures(quantity.trim()) {
+        Some(c) => c,
+        None => return quantity.to_string(),
+    };
+    let num = match caps.get(1).map_or("", |c| c.as_str()).replace(",", ".").parse::<f64>() {
+        Ok(n) => n * factor,
+        Err(_) => return quantity.to_string(),
+    };
+    let unit = caps.get(2).map_or("", |c| c.as_str());
+    let num = (num * 100.0).round() / 100.0;
+    return format!("{}{}", num, unit);
+}
+
+fn is_on_list(product: &str, listed: &Vec<String>) -> bool {
+    let product = product.to_lowercase();
+    for l in listed {
+        let l = l.to_lowercase();
+        if l == product || l.starts_with(&format!("{} (", product)) {
+            return true;
+        }
+    }
+    return false;
+}
+
+async fn handle_shop_request(period: &str, db: Box<db::Homechatbotdb>) -> String {
+    let fltr = if period.trim().to_lowercase() == "week" {
+        doc!{}
+    } else {
+        match normalize_day(period) {
+            Some(d) => doc!{"day": d},
+            None => return format!("Unknown day\n{}", MEAL_HELP),
+        }
+    };
+    let meals = match get_meals(fltr, db.clone()).await {
+        Ok(m) => m,
+        Err(e) => return error::report(&e),
+    };
+    if meals.len() == 0 {
+        return "No meals planned".to_string();
+    }
+    let listed = match grocery::get_listed_products(db.clone()).await {
+        Ok(l) => l,
+        Err(e) => return error::report(&e),
+    };
+    // (category, product) -> quantities, in the order first needed
+    let mut needed : Vec<(String, String, Vec<String>)> = vec![];
+    for ml in meals {
+        let rcp = match recipe::get_recipe(ml.recipe.as_str(), db.clone()).await {
+            Ok(Some(r)) => r,
+            Ok(None) => continue,
+            Err(e) => return error::report(&e),
+        };
+        let factor = ml.servings as f64 / rcp.servings as f64;
+        for ing in rcp.ingredients {
+            if is_on_list(ing.product.as_str(), &listed) {
+                continue;
+            }
+            let qty = if ing.quantity == "" { "".to_string() } else { scale_quantity(ing.quantity.as_str(), factor) };
+            match needed.iter_mut().find(|n| n.1.to_lowercase() == ing.product.to_lowercase()) {
+                Some(n) => {
+                    if qty != "" {
+                        n.2.push(qty);
+                    }
+                },
+                None => needed.push((ing.category, ing.product, if qty == "" { vec![] } else { vec![qty] })),
+            };
+        }
+    }
+    if needed.len() == 0 {
+        return "Everything needed is already on the grocery list".to_string();
+    }
+    let mut categories : Vec<String> = vec![];
+    for n in needed.iter() {
+        if !categories.contains(&n.0) {
+            categories.push(n.0.clone());
+        }
+    }
+    let mut added = 0;
+    for cat in categories {
+        let products : Vec<String> = needed.iter().filter(|n| n.0 == cat).map(|n| {
+            if n.2.len() == 0 {
+                n.1.clone()
+            } else {
+                format!("{} ({})", n.1, n.2.join(" + "))
+            }
+        }).collect();
+        added += products.len();
+        match grocery::add_products(cat.as_str(), products, db.clone()).await {
+            Ok(_) => {},
+            Err(e) => return error::report(&e),
+        };
+    }
+    return format!("{} items added to the grocery list!", added);
+}
@@ -0,0 +1,151 @@
+use crate::db;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+use chrono::Utc;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use tracing::{error, info};
+
+// The sync loop long-polls for 30 seconds, so anything well above that means
+// the bot is no longer talking to the homeserver
+const MAX_SYNC_LAG_SECS : i64 = 120;
+
+static COMMANDS : Mutex<Vec<(String, u64)>> = Mutex::new(Vec::new());
+static ERRORS : Mutex<Vec<(String, u64)>> = Mutex::new(Vec::new());
+static INVITES_ACCEPTED : AtomicU64 = AtomicU64::new(0);
+static INVITES_REJECTED : AtomicU64 = AtomicU64::new(0);
+static DB_OPERATIONS : AtomicU64 = AtomicU64::new(0);
+static DB_LATENCY_MICROS : AtomicU64 = AtomicU64::new(0);
+static LAST_SYNC : AtomicI64 = AtomicI64::new(0);
+
+fn increment(counters: &Mutex<Vec<(String, u64)>>, label: &str) {
+    let mut counters = match counters.lock() {
+        Ok(c) => c,
+        Err(p) => p.into_inner(),
+    };
+    match counters.iter_mut().find(|c| c.0 == label) {
+        Some(c) => c.1 += 1,
+        None => counters.push((label.to_string(), 1)),
+    };
+}
+
+pub fn record_command(module: &str) {
+    increment(&COMMANDS, module);
+}
+
+pub fn record_error(kind: &str) {
+    increment(&ERRORS, kind);
+}
+
+pub fn record_invite(accepted: bool) {
+    if accepted {
+        INVITES_ACCEPTED.fetch_add(1, Ordering::Relaxed);
+    } else {
+        INVITES_REJECTED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn record_db_operation(duration: Duration, success: bool) {
+    DB_OPERATIONS.fetch_add(1, Ordering::Relaxed);
+    DB_LATENCY_MICROS.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    if !success {
+        record_error("db");
+    }
+}
+
+pub fn record_sync() {
+    LAST_SYNC.store(Utc::now().timestamp(), Ordering::Relaxed);
+}
+
+// Seconds since the last successful sync, None if there was none yet
+pub fn sync_lag_secs() -> Option<i64> {
+    let last = LAST_SYNC.load(Ordering::Relaxed);
+    if last == 0 {
+        return None;
+    }
+    return Some(Utc::now().timestamp() - last);
+}
+
+fn render_labeled(out: &mut String, name: &str, help: &str, label: &str, counters: &Mutex<Vec<(String, u64)>>) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n", name, help, name));
+    let counters = match counters.lock() {
+        Ok(c) => c,
+        Err(p) => p.into_inner(),
+    };
+    for (l, v) in counters.iter() {
+        out.push_str(&format!("{}{{{}=\"{}\"}} {}\n", name, label, l.replace("\\", "\\\\").replace("\"", "\\\""), v));
+    }
+}
+
+pub fn render() -> String {
+    let mut out = String::new();
+    render_labeled(&mut out, "homechatbot_commands_total", "Commands handled per module", "module", &COMMANDS);
+    render_labeled(&mut out, "homechatbot_errors_total", "Errors per kind", "kind", &ERRORS);
+    out.push_str("# HELP homechatbot_invites_total Room invitations handled\n# TYPE homechatbot_invites_total counter\n");
+    out.push_str(&format!("homechatbot_invites_total{{result=\"accepted\"}} {}\n", INVITES_ACCEPTED.load(Ordering::Relaxed)));
+    out.push_str(&format!("homechatbot_invites_total{{result=\"rejected\"}} {}\n", INVITES_REJECTED.load(Ordering::Relaxed)));
+    out.push_str("# HELP homechatbot_db_operation_seconds Duration of database operations\n# TYPE homechatbot_db_operation_seconds summary\n");
+    out.push_str(&format!("homechatbot_db_operation_seconds_sum {}\n", DB_LATENCY_MICROS.load(Ordering::Relaxed) as f64 / 1_000_000.0));
+    out.push_str(&format!("homechatbot_db_operation_seconds_count {}\n", DB_OPERATIONS.load(Ordering::Relaxed)));
+    out.push_str("# HELP homechatbot_sync_lag_seconds Seconds since the last successful Matrix sync\n# TYPE homechatbot_sync_lag_seconds gauge\n");
+    out.push_str(&format!("homechatbot_sync_lag_seconds {}\n", sync_lag_secs().unwrap_or(-1)));
+    return out;
+}
+
+async fn health(db: Box<db::Homechatbotdb>) -> (bool, String) {
+    let sync = match sync_lag_secs() {
+        Some(lag) if lag <= MAX_SYNC_LAG_SECS => (true, format!("sync: ok ({}s ago)", lag)),
+        Some(lag) => (false, format!("sync: stale ({}s ago)", lag)),
+        None => (false, "sync: not synced yet".to_string()),
+    };
+    let dbh = match db.ping().await {
+        Ok(_) => (true, "database: ok".to_string()),
+        Err(e) => (false, format!("database: {}", e)),
+    };
+    return (sync.0 && dbh.0, format!("{}\n{}\n", sync.1, dbh.1));
+}
+
+async fn route(req: Request<Body>, db: Box<db::Homechatbotdb>) -> Result<Response<Body>, Infallible> {
+    let resp = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/healthz") => {
+            let (ok, body) = health(db).await;
+            Response::builder()
+                .status(if ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE })
+                .body(Body::from(body))
+        },
+        (&Method::GET, "/metrics") => {
+            Response::builder()
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .body(Body::from(render()))
+        },
+        _ => {
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("Not found\n"))
+        },
+    };
+    return Ok(resp.unwrap_or_else(|_| Response::new(Body::from("Internal error\n"))));
+}
+
+pub async fn serve(address: SocketAddr, db: Box<db::Homechatbotdb>) {
+    let make_svc = make_service_fn(move |_conn| {
+        let db = db.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| route(req, db.clone())))
+        }
+    });
+    let server = match Server::try_bind(&address) {
+        Ok(s) => s.serve(make_svc),
+        Err(e) => {
+            error!(address = %address, "Unable to start the metrics server: {}", e);
+            return;
+        },
+    };
+    info!(address = %address, "Metrics server is running");
+    if let Err(e) = server.await {
+        error!("Metrics server stopped: {}", e);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::db;
+use crate::error::Error;
+use crate::{backup, bgchan, expense, grocery, ha, meal, pantry, recipe};
+use serde::{Deserialize, Serialize};
+use mongodb::bson::doc;
+use std::future::Future;
+use std::pin::Pin;
+use tracing::info;
+
+const SCHEMA_VERSION_KEY : &str = "schema_version";
+
+// A collection a module keeps its documents in, with the field which has to
+// be unique in it if any
+pub struct Collection {
+    pub name: &'static str,
+    pub index: Option<&'static str>,
+}
+
+type MigrationFn = fn(Box<db::Homechatbotdb>) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+
+// A change to existing documents. Each runs once, in version order, and the
+// version reached is kept as {schema_version} in the config collection.
+struct Migration {
+    version: u32,
+    description: &'static str,
+    run: MigrationFn,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SchemaVersion {
+    schema_version: u32,
+}
+
+// Everything else is configured in here, so a new database needs it first
+const CORE_COLLECTIONS : &[Collection] = &[
+    Collection{name: db::CONFIG_COLLECTION_NAME, index: None},
+];
+
+fn collections() -> Vec<&'static Collection> {
+    let modules : [&'static [Collection]; 9] = [
+        CORE_COLLECTIONS,
+        grocery::COLLECTIONS,
+        pantry::COLLECTIONS,
+        recipe::COLLECTIONS,
+        meal::COLLECTIONS,
+        expense::COLLECTIONS,
+        bgchan::COLLECTIONS,
+        ha::COLLECTIONS,
+        backup::COLLECTIONS,
+    ];
+    return modules.iter().flat_map(|m| m.iter()).collect();
+}
+
+// Append only: a released migration never changes, fixes get a new version
+fn migrations() -> Vec<Migration> {
+    return vec![
+        Migration{version: 1, description: "Record when grocery items were added", run: |db| Box::pin(grocery::migrate_added_dates(db))},
+        Migration{version: 2, description: "Move the channels of the unnamed TV to the first named one", run: |db| Box::pin(bgchan::migrate_default_lineup(db))},
+    ];
+}
+
+// The names of every collection the bot keeps documents in
+pub fn collection_names() -> Vec<&'static str> {
+    return collections().iter().map(|c| c.name).collect();
+}
+
+// The schema version the database is brought to at startup
+pub fn latest_version() -> u32 {
+    return migrations().iter().map(|m| m.version).max().unwrap_or(0);
+}
+
+pub async fn get_schema_version(db: Box<db::Homechatbotdb>) -> Result<u32, Error> {
+    let items = db.get_generic_data_collection::<SchemaVersion>(db::CONFIG_COLLECTION_NAME, doc!{SCHEMA_VERSION_KEY: {"$exists": true}}, doc!{}).await?;
+    return Ok(items.iter().map(|v| v.schema_version).max().unwrap_or(0));
+}
+
+async fn set_schema_version(version: u32, db: Box<db::Homechatbotdb>) -> Result<(), Error> {
+    db.remove_data(db::CONFIG_COLLECTION_NAME, doc!{SCHEMA_VERSION_KEY: {"$exists": true}}).await?;
+    return db.insert_data_to_collection(db::CONFIG_COLLECTION_NAME, vec![doc!{SCHEMA_VERSION_KEY: version}]).await;
+}
+
+// Creates the missing collections and indexes, then runs the migrations the
+// database has not seen yet. Returns what was done, or with dry_run what
+// would be done without changing anything.
+pub async fn run(db: Box<db::Homechatbotdb>, dry_run: bool) -> Result<Vec<String>, Error> {
+    let mut steps : Vec<String> = vec![];
+    for coll in collections() {
+        if !db.check_collection_exists(coll.name).await? {
+            steps.push(format!("Create collection {}", coll.name));
+            if !dry_run {
+                db.create_collection(coll.name).await?;
+            }
+        }
+        let field = match coll.index {
+            Some(f) => f,
+            None => continue,
+        };
+        // Without the collection there are no indexes to list yet
+        let indexes = if dry_run && !db.check_collection_exists(coll.name).await? { vec![] } else { db.get_collection_index(coll.name).await? };
+        if !indexes.iter().any(|i| i.starts_with(field)) {
+            steps.push(format!("Create unique index on {}.{}", coll.name, field));
+            if !dry_run {
+                db.create_collection_index(coll.name, field).await?;
+            }
+        }
+    }
+    let current = get_schema_version(db.clone()).await?;
+    let latest = latest_version();
+    if current > latest {
+        return Err(Error::InvalidInput(format!("The database schema version {} is newer than this bot knows ({})", current, latest)));
+    }
+    for m in migrations().into_iter().filter(|m| m.version > current) {
+        steps.push(format!("Migration {}: {}", m.version, m.description));
+        if dry_run {
+            continue;
+        }
+        info!(version = m.version, "Running migration: {}", m.description);
+        (m.run)(db.clone()).await?;
+        set_schema_version(m.version, db.clone()).await?;
+    }
+    return Ok(steps);
+}
@@ -0,0 +1,240 @@
+use crate::db;
+use crate::error::{self, Error};
+use crate::registry::{self, Registry};
+use crate::reply::Reply;
+use serde::{Deserialize, Serialize};
+use mongodb::bson::doc;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS, SubscribeFilter};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+const DEFAULT_PORT : u16 = 1883;
+const DEFAULT_CLIENT_ID : &str = "home-chatbot";
+const KEEP_ALIVE_SECS : u64 = 30;
+const REQUEST_CAPACITY : usize = 32;
+
+// Broker settings, a {mqtt_host, ...} document in the config collection
+#[derive(Debug, Serialize, Deserialize)]
+struct MqttConfig {
+    mqtt_host: String,
+    mqtt_port: Option<u16>,
+    mqtt_client_id: Option<String>,
+    mqtt_username: Option<String>,
+    mqtt_password: Option<String>,
+}
+
+// "light {room} {state}" publishing {state} to "home/{room}/light/set". The
+// first word of the command becomes a chat command, the other words are
+// either literal or a {placeholder} taking one word.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CommandMapping {
+    pub command: String,
+    pub topic: String,
+    pub payload: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CommandMappings {
+    mqtt_commands: Vec<CommandMapping>,
+}
+
+// Messages on topic (MQTT wildcards allowed) are posted to room. message
+// may use {topic} and {payload}; with payload set only that payload counts.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Subscription {
+    pub topic: String,
+    pub room: String,
+    pub message: String,
+    pub payload: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Subscriptions {
+    mqtt_subscriptions: Vec<Subscription>,
+}
+
+#[derive(Clone)]
+pub struct Bridge {
+    client: AsyncClient,
+    eventloop: Arc<Mutex<EventLoop>>,
+    commands: Vec<CommandMapping>,
+    subscriptions: Vec<Subscription>,
+}
+
+// Reads the MQTT configuration. Returns None when no broker is configured.
+// Mappings are read once, so changing them needs a restart.
+pub async fn connect(db: Box<db::Homechatbotdb>) -> Result<Option<Bridge>, Error> {
+    let mut cfgs = db.get_generic_data_collection::<MqttConfig>(db::CONFIG_COLLECTION_NAME, doc!{"mqtt_host": {"$exists": true}}, doc!{}).await?;
+    let cfg = match cfgs.pop() {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+    let commands : Vec<CommandMapping> = db.get_generic_data_collection::<CommandMappings>(db::CONFIG_COLLECTION_NAME, doc!{"mqtt_commands": {"$exists": true}}, doc!{}).await?
+        .into_iter().flat_map(|c| c.mqtt_commands).collect();
+    let subscriptions : Vec<Subscription> = db.get_generic_data_collection::<Subscriptions>(db::CONFIG_COLLECTION_NAME, doc!{"mqtt_subscriptions": {"$exists": true}}, doc!{}).await?
+        .into_iter().flat_map(|s| s.mqtt_subscriptions).collect();
+    let mut opts = MqttOptions::new(
+        cfg.mqtt_client_id.unwrap_or(DEFAULT_CLIENT_ID.to_string()),
+        cfg.mqtt_host.as_str(),
+        cfg.mqtt_port.unwrap_or(DEFAULT_PORT),
+    );
+    opts.set_keep_alive(Duration::from_secs(KEEP_ALIVE_SECS));
+    if let Some(u) = cfg.mqtt_username {
+        opts.set_credentials(u, cfg.mqtt_password.unwrap_or_default());
+    }
+    let (client, eventloop) = AsyncClient::new(opts, REQUEST_CAPACITY);
+    info!(host = cfg.mqtt_host.as_str(), commands = commands.len(), subscriptions = subscriptions.len(), "MQTT bridge configured");
+    return Ok(Some(Bridge{
+        client: client,
+        eventloop: Arc::new(Mutex::new(eventloop)),
+        commands: commands,
+        subscriptions: subscriptions,
+    }));
+}
+
+// MQTT topic filter matching: "+" is one level, a trailing "#" any number
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut fl = filter.split('/');
+    let mut tl = topic.split('/');
+    loop {
+        match (fl.next(), tl.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => continue,
+            (Some(f), Some(t)) if f == t => continue,
+            (None, None) => return true,
+            _ => return false,
+        };
+    }
+}
+
+pub(crate) fn fill(template: &str, values: &[(String, String)]) -> String {
+    let mut out = template.to_string();
+    for (k, v) in values {
+        out = out.replace(format!("{{{}}}", k).as_str(), v.as_str());
+    }
+    return out;
+}
+
+// Matches the words after the command against a mapping, returning the
+// placeholder values
+pub(crate) fn match_mapping(mapping: &CommandMapping, args: &str) -> Option<Vec<(String, String)>> {
+    let pattern : Vec<&str> = mapping.command.split_whitespace().skip(1).collect();
+    let words : Vec<&str> = args.split_whitespace().collect();
+    if pattern.len() != words.len() {
+        return None;
+    }
+    let mut values = vec![];
+    for (p, w) in pattern.iter().zip(words.iter()) {
+        if p.starts_with('{') && p.ends_with('}') && p.len() > 2 {
+            values.push((p[1..p.len() - 1].to_string(), w.to_string()));
+        } else if p.to_lowercase() != w.to_lowercase() {
+            return None;
+        }
+    }
+    return Some(values);
+}
+
+impl Bridge {
+    // Adds one chat command per distinct first word of the mappings. Words
+    // which already are commands are skipped, the configuration must not be
+    // able to replace "admin" or "gro".
+    pub fn register_commands(&self, reg: &mut Registry) {
+        let mut words : Vec<String> = vec![];
+        for m in self.commands.iter() {
+            let w = match m.command.split_whitespace().next() {
+                Some(w) => w.to_lowercase(),
+                None => continue,
+            };
+            if words.contains(&w) {
+                continue;
+            }
+            if reg.find(w.as_str()).is_some() {
+                warn!(command = w.as_str(), "Skipping the MQTT command, the name is already taken");
+                continue;
+            }
+            words.push(w);
+        }
+        for word in words {
+            let mappings : Vec<CommandMapping> = self.commands.iter()
+                .filter(|m| m.command.split_whitespace().next().map(|w| w.to_lowercase()) == Some(word.clone()))
+                .cloned().collect();
+            let usage : Vec<String> = mappings.iter().map(|m| m.command.clone()).collect();
+            let help = format!("{} (MQTT)", usage.join(" | "));
+            let client = self.client.clone();
+            reg.register(&[word.as_str()], "mqtt", help.as_str(), move |r: registry::Request| {
+                let client = client.clone();
+                let mappings = mappings.clone();
+                let usage = usage.clone();
+                async move {
+                    match publish_command(&client, &mappings, r.args.as_str()).await {
+                        Ok(msg) => msg.into(),
+                        Err(Error::InvalidInput(_)) => format!("Usage:\n    {}", usage.join("\n    ")).into(),
+                        Err(e) => Reply::from(error::report(&e)),
+                    }
+                }
+            });
+        }
+    }
+
+    // Drives the connection until something worth a notification arrives.
+    // Returns the rooms and messages to post; an error means the broker
+    // connection broke and the caller should back off before polling again.
+    pub async fn next_notifications(&self) -> Result<Vec<(String, String)>, Error> {
+        let mut eventloop = self.eventloop.lock().await;
+        loop {
+            let event = match eventloop.poll().await {
+                Ok(e) => e,
+                Err(e) => return Err(Error::ConnectionLost(format!("MQTT connection failed: {}", e))),
+            };
+            match event {
+                Event::Incoming(Packet::ConnAck(_)) => {
+                    info!("Connected to the MQTT broker");
+                    // Subscriptions do not survive a reconnect with a clean session
+                    let mut filters : Vec<SubscribeFilter> = vec![];
+                    for s in self.subscriptions.iter() {
+                        if !filters.iter().any(|f| f.path == s.topic) {
+                            filters.push(SubscribeFilter::new(s.topic.clone(), QoS::AtLeastOnce));
+                        }
+                    }
+                    if filters.len() > 0 {
+                        if let Err(e) = self.client.try_subscribe_many(filters) {
+                            return Err(Error::Internal(format!("Unable to subscribe to MQTT topics: {}", e)));
+                        }
+                    }
+                },
+                Event::Incoming(Packet::Publish(p)) => {
+                    let payload = String::from_utf8_lossy(&p.payload).trim().to_string();
+                    debug!(topic = p.topic.as_str(), "Got MQTT message");
+                    let values = vec![("topic".to_string(), p.topic.clone()), ("payload".to_string(), payload.clone())];
+                    let notes : Vec<(String, String)> = self.subscriptions.iter()
+                        .filter(|s| topic_matches(s.topic.as_str(), p.topic.as_str()))
+                        .filter(|s| s.payload.as_ref().map_or(true, |w| w.eq_ignore_ascii_case(payload.as_str())))
+                        .map(|s| (s.room.clone(), fill(s.message.as_str(), &values)))
+                        .collect();
+                    if notes.len() > 0 {
+                        return Ok(notes);
+                    }
+                },
+                _ => {},
+            };
+        }
+    }
+}
+
+async fn publish_command(client: &AsyncClient, mappings: &[CommandMapping], args: &str) -> Result<String, Error> {
+    for m in mappings {
+        let values = match match_mapping(m, args) {
+            Some(v) => v,
+            None => continue,
+        };
+        let topic = fill(m.topic.as_str(), &values);
+        let payload = fill(m.payload.as_str(), &values);
+        match client.publish(topic.as_str(), QoS::AtLeastOnce, false, payload.clone()).await {
+            Ok(_) => return Ok(format!("Sent \"{}\" to {}", payload, topic)),
+            Err(e) => return Err(Error::ConnectionLost(format!("Unable to publish to {}: {}", topic, e))),
+        };
+    }
+    return Err(Error::InvalidInput(format!("No MQTT command matches \"{}\"", args.trim())));
+}
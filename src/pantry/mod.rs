@@ -0,0 +1,402 @@
+use crate::db;
+use crate::error::{self, Error};
+use crate::events;
+use crate::grocery;
+use crate::migrate;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use mongodb::bson::doc;
+use chrono::{Duration, Local, NaiveDate};
+
+const PANTRY_COLLECTION_NAME : &str = "pantry";
+pub const COLLECTIONS : &[migrate::Collection] = &[
+    migrate::Collection{name: PANTRY_COLLECTION_NAME, index: Some("pantryid")},
+];
+const PANTRY_HELP : &str = "Pantry allowed commands:
+    list
+    add {category}
+        product1[, quantity][, YYYY-MM-DD]
+        product2[, quantity][, YYYY-MM-DD]
+        ...
+    in {grocery_id}[, quantity][, YYYY-MM-DD]
+    use {pantry_id} [quantity]
+    track {pantry_id} {minimum_quantity}
+    expiring [days]
+    rem {pantry_id}";
+const MAX_ITEMS_IN_DB : u32 = 10000;
+const DEFAULT_EXPIRY_DAYS : u32 = 3;
+const DATE_FORMAT : &str = "%Y-%m-%d";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PantryItem {
+    pantryid: u32,
+    category: String,
+    product: String,
+    quantity: f64,
+    unit: String,
+    best_before: Option<String>,
+    min_quantity: Option<f64>,
+    warned_on: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PantryConfig {
+    pantry_notify_rooms: Vec<String>,
+    pantry_expiry_days: Option<u32>,
+}
+
+pub async fn handle_pantry_command(cmd: String, db: Box<db::Homechatbotdb>) -> String {
+    let re = match Regex::new(r"^(?s)(\w+)(?:\s+(.*))?$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)),
+    };
+    let caps = match re.captures(cmd.as_str()) {
+        Some(c) => c,
+        None => return String::from(PANTRY_HELP),
+    };
+    let cmd = match caps.get(1) {
+        Some(c) => c.as_str().to_lowercase(),
+        None => return String::from(PANTRY_HELP),
+    };
+    if cmd == "list" {
+        return handle_list_request(db).await;
+    } else if cmd == "expiring" {
+        let days = match caps.get(2) {
+            Some(d) => match d.as_str().trim().parse::<u32>() {
+                Ok(n) => n,
+                Err(e) => return format!("Only numbers are allowed: {}\n{}", e, PANTRY_HELP),
+            },
+            None => DEFAULT_EXPIRY_DAYS,
+        };
+        return match get_expiring_items(days, db).await {
+            Ok(msg) if msg == "" => format!("Nothing expires within {} days", days),
+            Ok(msg) => msg,
+            Err(e) => error::report(&e),
+        };
+    }
+    let rest = match caps.get(2) {
+        Some(c) => c.as_str(),
+        None => return String::from(PANTRY_HELP),
+    };
+    if cmd == "add" {
+        return handle_add_request(rest, db).await;
+    } else if cmd == "in" {
+        return handle_in_request(rest, db).await;
+    } else if cmd == "use" {
+        return handle_use_request(rest, db).await;
+    } else if cmd == "track" {
+        return handle_track_request(rest, db).await;
+    } else if cmd == "rem" {
+        return handle_remove_request(rest, db).await;
+    }
+    return String::from(PANTRY_HELP);
+}
+
+// Parses "2", "500g" or "1.5 l" into a number and a unit
+fn parse_quantity(qty: &str) -> Result<(f64, String), Error> {
+    let re = match Regex::new(r"^(\d+(?:[.,]\d+)?)\s*(\D*)$") {
+        Ok(r) => r,
+        Err(e) => return Err(Error::Internal(format!("ERROR: {}", e))),
+    };
+    let caps = match re.captures(qty.trim()) {
+        Some(c) => c,
+        None => return Err(Error::InvalidInput(format!("Invalid quantity \"{}\"", qty.trim()))),
+    };
+    let num = match caps.get(1).map_or("", |c| c.as_str()).replace(",", ".").parse::<f64>() {
+        Ok(n) => n,
+        Err(e) => return Err(Error::InvalidInput(format!("Invalid quantity \"{}\": {}", qty.trim(), e))),
+    };
+    return Ok((num, caps.get(2).map_or("", |c| c.as_str()).trim().to_string()));
+}
+
+fn parse_date(dt: &str) -> Result<String, Error> {
+    match NaiveDate::parse_from_str(dt.trim(), DATE_FORMAT) {
+        Ok(d) => return Ok(d.format(DATE_FORMAT).to_string()),
+        Err(e) => return Err(Error::InvalidInput(format!("Invalid date \"{}\", expected YYYY-MM-DD: {}", dt.trim(), e))),
+    };
+}
+
+// Splits "product, quantity, date" where quantity and date are optional and
+// may come in any order
+fn parse_item_spec(spec: &str) -> Result<(String, f64, String, Option<String>), Error> {
+    let mut parts = spec.split(",").map(|p| p.trim());
+    let first = parts.next().unwrap_or("").to_string();
+    let mut quantity = (1.0, "".to_string());
+    let mut best_before = None;
+    for p in parts {
+        if p == "" {
+            continue;
+        }
+        if p.len() == 10 && p.chars().nth(4) == Some('-') {
+            best_before = Some(parse_date(p)?);
+        } else {
+            quantity = parse_quantity(p)?;
+        }
+    }
+    return Ok((first, quantity.0, quantity.1, best_before));
+}
+
+fn format_item(item: &PantryItem) -> String {
+    let mut msg = format!("({}) {} {}{}", item.pantryid, item.product, item.quantity, if item.unit == "" { "".to_string() } else { format!(" {}", item.unit) });
+    if let Some(bb) = &item.best_before {
+        msg = format!("{}, best before {}", msg, bb);
+    }
+    if let Some(mq) = item.min_quantity {
+        msg = format!("{}, keep at least {}", msg, mq);
+    }
+    return msg;
+}
+
+async fn get_item(pantryid: u32, db: Box<db::Homechatbotdb>) -> Result<Option<PantryItem>, Error> {
+    let mut items = db.get_generic_data_collection::<PantryItem>(PANTRY_COLLECTION_NAME, doc!{"pantryid": pantryid}, doc!{}).await?;
+    return Ok(items.pop());
+}
+
+async fn handle_list_request(db: Box<db::Homechatbotdb>) -> String {
+    let items = match db.get_generic_data_collection::<PantryItem>(PANTRY_COLLECTION_NAME, doc!{}, doc!{"category":1}).await {
+        Ok(i) => i,
+        Err(e) => return error::report(&e),
+    };
+    if items.len() == 0 {
+        return "Pantry is empty".to_string();
+    }
+    let mut msg = "".to_string();
+    let mut prev_cat = "".to_string();
+    for item in items {
+        if item.category != prev_cat {
+            msg = format!("{}{}:\n", msg, item.category);
+            prev_cat = item.category.clone();
+        }
+        msg = format!("{}{}\n", msg, format_item(&item));
+    }
+    return msg;
+}
+
+async fn insert_item(category: &str, product: &str, quantity: f64, unit: &str, best_before: Option<String>, db: Box<db::Homechatbotdb>) -> Result<(), Error> {
+    loop {
+        let id = get_smallest_available_id(db.clone()).await?;
+        let d = doc!{
+            "pantryid": id,
+            "category": category,
+            "product": product,
+            "quantity": quantity,
+            "unit": unit,
+            "best_before": best_before.clone(),
+            "min_quantity": mongodb::bson::Bson::Null,
+            "warned_on": mongodb::bson::Bson::Null,
+        };
+        match db.insert_data_to_collection(PANTRY_COLLECTION_NAME, vec![d]).await {
+            Ok(_) => return Ok(()),
+            Err(Error::Duplicate(_)) => continue,
+            Err(e) => return Err(e),
+        };
+    }
+}
+
+async fn handle_add_request(cmd_rest: &str, db: Box<db::Homechatbotdb>) -> String {
+    let re = match Regex::new(r"^(?s)(.*?)\n(.*)$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)),
+    };
+    let caps = match re.captures(cmd_rest) {
+        Some(c) => c,
+        None => return String::from(PANTRY_HELP),
+    };
+    let category = caps.get(1).map_or("", |c| c.as_str()).trim();
+    let products = caps.get(2).map_or("", |c| c.as_str());
+    for line in products.split("\n") {
+        if line.trim() == "" {
+            continue;
+        }
+        let (product, quantity, unit, best_before) = match parse_item_spec(line) {
+            Ok(s) => s,
+            Err(e) => return format!("{}\n{}", e.user_message(), PANTRY_HELP),
+        };
+        match insert_item(category, product.as_str(), quantity, unit.as_str(), best_before, db.clone()).await {
+            Ok(_) => {},
+            Err(e) => return error::report(&e),
+        };
+    }
+    return "Items successfully added to the pantry!".to_string();
+}
+
+async fn handle_in_request(cmd_rest: &str, db: Box<db::Homechatbotdb>) -> String {
+    let (groid, quantity, unit, best_before) = match parse_item_spec(cmd_rest) {
+        Ok(s) => s,
+        Err(e) => return format!("{}\n{}", e.user_message(), PANTRY_HELP),
+    };
+    let groid = match groid.parse::<u32>() {
+        Ok(i) => i,
+        Err(e) => return format!("Only numbers are allowed: {}\n{}", e, PANTRY_HELP),
+    };
+    let (category, product) = match grocery::get_product(groid, db.clone()).await {
+        Ok(Some(p)) => p,
+        Ok(None) => return format!("Grocery item {} not found", groid),
+        Err(e) => return error::report(&e),
+    };
+    match insert_item(category.as_str(), product.as_str(), quantity, unit.as_str(), best_before, db.clone()).await {
+        Ok(_) => {},
+        Err(e) => return error::report(&e),
+    };
+    match grocery::remove_product(groid, db).await {
+        Ok(_) => return format!("{} moved into the pantry", product),
+        Err(e) => return error::report(&e),
+    };
+}
+
+async fn handle_use_request(cmd_rest: &str, db: Box<db::Homechatbotdb>) -> String {
+    let mut parts = cmd_rest.trim().splitn(2, char::is_whitespace);
+    let pantryid = match parts.next().unwrap_or("").parse::<u32>() {
+        Ok(i) => i,
+        Err(e) => return format!("Only numbers are allowed: {}\n{}", e, PANTRY_HELP),
+    };
+    let used = match parts.next() {
+        Some(q) => match parse_quantity(q) {
+            Ok(q) => q.0,
+            Err(e) => return format!("{}\n{}", e.user_message(), PANTRY_HELP),
+        },
+        None => 1.0,
+    };
+    let item = match get_item(pantryid, db.clone()).await {
+        Ok(Some(i)) => i,
+        Ok(None) => return format!("Pantry item {} not found", pantryid),
+        Err(e) => return error::report(&e),
+    };
+    let left = if item.quantity > used { item.quantity - used } else { 0.0 };
+    let mut msg = if left > 0.0 {
+        match db.update_data(PANTRY_COLLECTION_NAME, doc!{"pantryid": pantryid}, doc!{"quantity": left}).await {
+            Ok(_) => format!("{} {}{} left", item.product, left, if item.unit == "" { "".to_string() } else { format!(" {}", item.unit) }),
+            Err(e) => return error::report(&e),
+        }
+    } else if item.min_quantity.is_some() {
+        // Tracked items stay in the pantry so the minimum is remembered
+        match db.update_data(PANTRY_COLLECTION_NAME, doc!{"pantryid": pantryid}, doc!{"quantity": 0.0, "best_before": mongodb::bson::Bson::Null}).await {
+            Ok(_) => format!("{} used up", item.product),
+            Err(e) => return error::report(&e),
+        }
+    } else {
+        match db.remove_data(PANTRY_COLLECTION_NAME, doc!{"pantryid": pantryid}).await {
+            Ok(_) => format!("{} used up and removed from the pantry", item.product),
+            Err(e) => return error::report(&e),
+        }
+    };
+    if let Some(mq) = item.min_quantity {
+        if left <= mq {
+            match add_to_grocery_list(&item, db).await {
+                Ok(true) => msg = format!("{}, added to the grocery list", msg),
+                Ok(false) => {},
+                Err(e) => return format!("{}, but unable to add it to the grocery list: {}", msg, error::report(&e)),
+            };
+        }
+    }
+    return msg;
+}
+
+async fn add_to_grocery_list(item: &PantryItem, db: Box<db::Homechatbotdb>) -> Result<bool, Error> {
+    let listed = grocery::get_listed_products(db.clone()).await?;
+    let product = item.product.to_lowercase();
+    for l in listed {
+        if l.to_lowercase() == product {
+            return Ok(false);
+        }
+    }
+    grocery::add_products(item.category.as_str(), vec![item.product.clone()], db).await?;
+    return Ok(true);
+}
+
+async fn handle_track_request(cmd_rest: &str, db: Box<db::Homechatbotdb>) -> String {
+    let re = match Regex::new(r"^(\d+)\s+(\S+)\s*$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)),
+    };
+    let caps = match re.captures(cmd_rest.trim()) {
+        Some(c) => c,
+        None => return String::from(PANTRY_HELP),
+    };
+    let pantryid = match caps.get(1).map_or("", |c| c.as_str()).parse::<u32>() {
+        Ok(i) => i,
+        Err(e) => return format!("Only numbers are allowed: {}\n{}", e, PANTRY_HELP),
+    };
+    let minq = match parse_quantity(caps.get(2).map_or("", |c| c.as_str())) {
+        Ok(q) => q.0,
+        Err(e) => return format!("{}\n{}", e.user_message(), PANTRY_HELP),
+    };
+    match get_item(pantryid, db.clone()).await {
+        Ok(Some(_)) => {},
+        Ok(None) => return format!("Pantry item {} not found", pantryid),
+        Err(e) => return error::report(&e),
+    };
+    match db.update_data(PANTRY_COLLECTION_NAME, doc!{"pantryid": pantryid}, doc!{"min_quantity": minq}).await {
+        Ok(_) => return format!("Pantry item {} is now tracked", pantryid),
+        Err(e) => return error::report(&e),
+    };
+}
+
+async fn handle_remove_request(cmd_rest: &str, db: Box<db::Homechatbotdb>) -> String {
+    for itm in cmd_rest.split(",") {
+        let id = match itm.trim().parse::<u32>() {
+            Ok(i) => i,
+            Err(e) => return format!("Only numbers are allowed: {}\n{}", e, PANTRY_HELP),
+        };
+        match db.remove_data(PANTRY_COLLECTION_NAME, doc!{"pantryid":id}).await {
+            Ok(_) => {},
+            Err(e) => return error::report(&e),
+        };
+    }
+    return "Items successfully removed from the pantry".to_string();
+}
+
+async fn get_expiring_items(days: u32, db: Box<db::Homechatbotdb>) -> Result<String, Error> {
+    let limit = (Local::today().naive_local() + Duration::days(days as i64)).format(DATE_FORMAT).to_string();
+    let items = db.get_generic_data_collection::<PantryItem>(PANTRY_COLLECTION_NAME, doc!{"best_before": {"$ne": null, "$lte": limit}}, doc!{"best_before":1}).await?;
+    let mut msg = "".to_string();
+    for item in items {
+        msg = format!("{}{}\n", msg, format_item(&item));
+    }
+    return Ok(msg);
+}
+
+// Returns the rooms to notify and the warning text for items expiring soon
+// which have not been warned about today yet. Returns None if there is
+// nothing to warn about or no notification room is configured.
+pub async fn get_expiry_warnings(db: Box<db::Homechatbotdb>) -> Result<Option<(Vec<String>, String)>, Error> {
+    let mut cfgs = db.get_generic_data_collection::<PantryConfig>(db::CONFIG_COLLECTION_NAME, doc!{"pantry_notify_rooms": {"$exists": true}}, doc!{}).await?;
+    let cfg = match cfgs.pop() {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+    if cfg.pantry_notify_rooms.len() == 0 {
+        return Ok(None);
+    }
+    let today = Local::today().naive_local().format(DATE_FORMAT).to_string();
+    let days = cfg.pantry_expiry_days.unwrap_or(DEFAULT_EXPIRY_DAYS);
+    let limit = (Local::today().naive_local() + Duration::days(days as i64)).format(DATE_FORMAT).to_string();
+    let items = db.get_generic_data_collection::<PantryItem>(PANTRY_COLLECTION_NAME, doc!{
+        "best_before": {"$ne": null, "$lte": limit},
+        "quantity": {"$gt": 0.0},
+        "warned_on": {"$ne": today.as_str()},
+    }, doc!{"best_before":1}).await?;
+    if items.len() == 0 {
+        return Ok(None);
+    }
+    let mut msg = format!("The following pantry items expire within {} days:\n", days);
+    for item in items.iter() {
+        msg = format!("{}{}\n", msg, format_item(item));
+    }
+    let ids : Vec<u32> = items.iter().map(|i| i.pantryid).collect();
+    db.update_data(PANTRY_COLLECTION_NAME, doc!{"pantryid": {"$in": ids}}, doc!{"warned_on": today.as_str()}).await?;
+    let expiring : Vec<serde_json::Value> = items.iter().map(|i| serde_json::json!({"id": i.pantryid, "product": i.product, "best_before": i.best_before})).collect();
+    events::publish("pantry.expiry_warning", serde_json::json!({"days": days, "items": expiring}));
+    return Ok(Some((cfg.pantry_notify_rooms, msg)));
+}
+
+async fn get_smallest_available_id(db: Box<db::Homechatbotdb>) -> Result<u32, Error> {
+    let items = db.get_generic_data_collection::<PantryItem>(PANTRY_COLLECTION_NAME, doc!{}, doc!{}).await?;
+    let bufv : Vec<u32> = items.iter().map(|i| i.pantryid).collect();
+    for n in 1..MAX_ITEMS_IN_DB {
+        if !bufv.contains(&n) {
+            return Ok(n);
+        }
+    }
+    return Err(Error::InvalidInput(String::from("Too many products in the pantry")))
+}
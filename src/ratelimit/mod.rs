@@ -0,0 +1,169 @@
+use crate::db;
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use mongodb::bson::doc;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_USER_LIMIT : u32 = 10;
+const DEFAULT_ROOM_LIMIT : u32 = 30;
+const DEFAULT_WINDOW_SECS : u64 = 60;
+// A message repeating one of the bot's own replies from this recently is
+// most likely another bot echoing it back
+const ECHO_WINDOW_SECS : u64 = 60;
+const RECENT_REPLIES_PER_ROOM : usize = 5;
+
+// {rate_limits: {user, room, window_secs}} in the config collection: at most
+// user commands per sender and room commands per room within window_secs.
+// 0 turns a limit off.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Limits {
+    pub user: Option<u32>,
+    pub room: Option<u32>,
+    pub window_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RateLimits {
+    rate_limits: Limits,
+}
+
+// {ignored_users: [...]} in the config collection, e.g. other bots
+#[derive(Debug, Serialize, Deserialize)]
+struct IgnoredUsers {
+    ignored_users: Vec<String>,
+}
+
+pub enum Verdict {
+    Allow,
+    // Over the limit; answer once with the seconds until the next command
+    // is accepted
+    CoolDown(u64),
+    // Still over the limit and already told so
+    Drop,
+}
+
+#[derive(Default)]
+struct Window {
+    hits: VecDeque<Instant>,
+    warned: bool,
+}
+
+#[derive(Default)]
+struct State {
+    windows: HashMap<String, Window>,
+    replies: HashMap<String, VecDeque<(Instant, String)>>,
+    // "{room} {sender}" of who repeated a reply, and when they last did
+    echoers: HashMap<String, Instant>,
+}
+
+// Shared by every message handler, so cheap to clone
+#[derive(Clone)]
+pub struct Limiter {
+    user_limit: u32,
+    room_limit: u32,
+    window: Duration,
+    ignored: Arc<Vec<String>>,
+    state: Arc<Mutex<State>>,
+}
+
+// Counts a hit in the window of key, returning how long until there is room
+// again if the limit was already reached
+fn hit(state: &mut State, key: String, limit: u32, window: Duration, now: Instant) -> Option<(Duration, bool)> {
+    if limit == 0 {
+        return None;
+    }
+    // Senders and rooms quiet for a whole window are forgotten
+    state.windows.retain(|_, w| w.hits.back().map_or(false, |t| now.duration_since(*t) < window));
+    let w = state.windows.entry(key).or_default();
+    while w.hits.front().map_or(false, |t| now.duration_since(*t) >= window) {
+        w.hits.pop_front();
+    }
+    if w.hits.len() < limit as usize {
+        w.hits.push_back(now);
+        w.warned = false;
+        return None;
+    }
+    let wait = w.hits.front().map_or(window, |t| window - now.duration_since(*t));
+    let first = !w.warned;
+    w.warned = true;
+    return Some((wait, first));
+}
+
+impl Limiter {
+    pub fn new(limits: Limits, ignored: Vec<String>) -> Limiter {
+        return Limiter{
+            user_limit: limits.user.unwrap_or(DEFAULT_USER_LIMIT),
+            room_limit: limits.room.unwrap_or(DEFAULT_ROOM_LIMIT),
+            window: Duration::from_secs(limits.window_secs.unwrap_or(DEFAULT_WINDOW_SECS).max(1)),
+            ignored: Arc::new(ignored),
+            state: Arc::new(Mutex::new(State::default())),
+        };
+    }
+
+    // Reads the limits once at startup, the defaults apply without config
+    pub async fn load(db: Box<db::Homechatbotdb>) -> Result<Limiter, Error> {
+        let limits = db.get_generic_data_collection::<RateLimits>(db::CONFIG_COLLECTION_NAME, doc!{"rate_limits": {"$exists": true}}, doc!{}).await?
+            .pop()
+            .map_or(Limits{user: None, room: None, window_secs: None}, |l| l.rate_limits);
+        let ignored = db.get_generic_data_collection::<IgnoredUsers>(db::CONFIG_COLLECTION_NAME, doc!{"ignored_users": {"$exists": true}}, doc!{}).await?
+            .into_iter()
+            .flat_map(|i| i.ignored_users)
+            .collect();
+        return Ok(Limiter::new(limits, ignored));
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        return self.state.lock().unwrap_or_else(|e| e.into_inner());
+    }
+
+    // True for messages which must not be answered at all: from ignored
+    // users, or repeating what the bot just said in the room. A person may
+    // well say the same once, so only the second repeat from the same
+    // sender is taken for a bot echoing the replies.
+    pub fn should_ignore(&self, sender: &str, room: &str, body: &str) -> bool {
+        if self.ignored.iter().any(|u| u == sender) {
+            return true;
+        }
+        let body = body.trim();
+        let now = Instant::now();
+        let echo_window = Duration::from_secs(ECHO_WINDOW_SECS);
+        let mut state = self.lock();
+        let echo = state.replies.get(room).map_or(false, |r| r.iter().any(|(t, msg)| {
+            now.duration_since(*t) < echo_window && msg.trim() == body
+        }));
+        state.echoers.retain(|_, t| now.duration_since(*t) < echo_window);
+        if !echo {
+            return false;
+        }
+        return state.echoers.insert(format!("{} {}", room, sender), now).is_some();
+    }
+
+    // Counts a command against both the sender's and the room's limit
+    pub fn acquire(&self, sender: &str, room: &str) -> Verdict {
+        let now = Instant::now();
+        let mut state = self.lock();
+        let user = hit(&mut state, format!("user:{}", sender), self.user_limit, self.window, now);
+        let room = match user {
+            // Commands over the user limit do not count against the room
+            Some(_) => None,
+            None => hit(&mut state, format!("room:{}", room), self.room_limit, self.window, now),
+        };
+        match user.or(room) {
+            None => return Verdict::Allow,
+            Some((wait, true)) => return Verdict::CoolDown(wait.as_secs().max(1)),
+            Some((_, false)) => return Verdict::Drop,
+        };
+    }
+
+    // Remembers what the bot said, to recognize it when echoed back
+    pub fn record_reply(&self, room: &str, msg: &str) {
+        let mut state = self.lock();
+        let replies = state.replies.entry(room.to_string()).or_default();
+        replies.push_back((Instant::now(), msg.to_string()));
+        while replies.len() > RECENT_REPLIES_PER_ROOM {
+            replies.pop_front();
+        }
+    }
+}
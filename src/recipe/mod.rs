@@ -0,0 +1,172 @@
+use crate::db;
+use crate::error::{self, Error};
+use crate::migrate;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use mongodb::bson::{doc, to_document};
+
+const RECIPE_COLLECTION_NAME : &str = "recipes";
+pub const COLLECTIONS : &[migrate::Collection] = &[
+    migrate::Collection{name: RECIPE_COLLECTION_NAME, index: Some("name")},
+];
+const RECIPE_HELP : &str = "Recipe allowed commands:
+    list
+    show {name}
+    add {name} {servings}
+        {category}: {product}[, {quantity}]
+        {category}: {product}[, {quantity}]
+        ...
+    rem {name}";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Ingredient {
+    pub category: String,
+    pub product: String,
+    pub quantity: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Recipe {
+    pub name: String,
+    pub servings: u32,
+    pub ingredients: Vec<Ingredient>,
+}
+
+pub async fn handle_recipe_command(cmd: String, db: Box<db::Homechatbotdb>) -> String {
+    let re = match Regex::new(r"^(?s)(\w+)(?:\s+(.*))?$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)),
+    };
+    let caps = match re.captures(cmd.as_str()) {
+        Some(c) => c,
+        None => return String::from(RECIPE_HELP),
+    };
+    let cmd = match caps.get(1) {
+        Some(c) => c.as_str().to_lowercase(),
+        None => return String::from(RECIPE_HELP),
+    };
+    if cmd == "list" {
+        return handle_list_request(db).await;
+    } else if cmd == "show" {
+        match caps.get(2) {
+            Some(c) => return handle_show_request(c.as_str(), db).await,
+            None => return String::from(RECIPE_HELP),
+        };
+    } else if cmd == "add" {
+        match caps.get(2) {
+            Some(c) => return handle_add_request(c.as_str(), db).await,
+            None => return String::from(RECIPE_HELP),
+        };
+    } else if cmd == "rem" {
+        match caps.get(2) {
+            Some(c) => return handle_remove_request(c.as_str(), db).await,
+            None => return String::from(RECIPE_HELP),
+        };
+    }
+    return String::from(RECIPE_HELP);
+}
+
+pub async fn get_recipe(name: &str, db: Box<db::Homechatbotdb>) -> Result<Option<Recipe>, Error> {
+    let mut items = db.get_generic_data_collection::<Recipe>(RECIPE_COLLECTION_NAME, doc!{"name": name.trim().to_lowercase()}, doc!{}).await?;
+    return Ok(items.pop());
+}
+
+async fn handle_list_request(db: Box<db::Homechatbotdb>) -> String {
+    let items = match db.get_generic_data_collection::<Recipe>(RECIPE_COLLECTION_NAME, doc!{}, doc!{"name":1}).await {
+        Ok(i) => i,
+        Err(e) => return error::report(&e),
+    };
+    if items.len() == 0 {
+        return "No recipes stored".to_string();
+    }
+    let mut msg = "".to_string();
+    for rcp in items {
+        msg = format!("{}{} ({} servings, {} ingredients)\n", msg, rcp.name, rcp.servings, rcp.ingredients.len());
+    }
+    return msg;
+}
+
+async fn handle_show_request(name: &str, db: Box<db::Homechatbotdb>) -> String {
+    let rcp = match get_recipe(name, db).await {
+        Ok(Some(r)) => r,
+        Ok(None) => return format!("Recipe \"{}\" not found", name.trim()),
+        Err(e) => return error::report(&e),
+    };
+    let mut msg = format!("{} ({} servings):\n", rcp.name, rcp.servings);
+    for ing in rcp.ingredients {
+        if ing.quantity == "" {
+            msg = format!("{}{}: {}\n", msg, ing.category, ing.product);
+        } else {
+            msg = format!("{}{}: {}, {}\n", msg, ing.category, ing.product, ing.quantity);
+        }
+    }
+    return msg;
+}
+
+async fn handle_add_request(cmd_rest: &str, db: Box<db::Homechatbotdb>) -> String {
+    let re = match Regex::new(r"^(?s)(\S+)\s+(\d+)\s*\n(.*)$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)),
+    };
+    let caps = match re.captures(cmd_rest) {
+        Some(c) => c,
+        None => return String::from(RECIPE_HELP),
+    };
+    let name = match caps.get(1) {
+        Some(c) => c.as_str().to_lowercase(),
+        None => return String::from(RECIPE_HELP),
+    };
+    let servings = match caps.get(2) {
+        Some(c) => match c.as_str().parse::<u32>() {
+            Ok(s) if s > 0 => s,
+            _ => return format!("Servings must be a positive number\n{}", RECIPE_HELP),
+        },
+        None => return String::from(RECIPE_HELP),
+    };
+    let lines = match caps.get(3) {
+        Some(l) => l.as_str(),
+        None => return String::from(RECIPE_HELP),
+    };
+    let ingre = match Regex::new(r"^([^:]+):\s*([^,]+?)\s*(?:,\s*(.*?))?\s*$") {
+        Ok(r) => r,
+        Err(e) => return String::from(format!("ERROR: {}", e)),
+    };
+    let mut ingredients : Vec<Ingredient> = vec![];
+    for line in lines.split("\n") {
+        if line.trim() == "" {
+            continue;
+        }
+        let icaps = match ingre.captures(line.trim()) {
+            Some(c) => c,
+            None => return format!("Invalid ingredient \"{}\"\n{}", line.trim(), RECIPE_HELP),
+        };
+        ingredients.push(Ingredient{
+            category: icaps.get(1).map_or("", |c| c.as_str()).trim().to_string(),
+            product: icaps.get(2).map_or("", |c| c.as_str()).to_string(),
+            quantity: icaps.get(3).map_or("", |c| c.as_str()).to_string(),
+        });
+    }
+    if ingredients.len() == 0 {
+        return format!("A recipe needs at least one ingredient\n{}", RECIPE_HELP);
+    }
+    let rcp = Recipe{name: name.clone(), servings: servings, ingredients: ingredients};
+    let rdoc = match to_document(&rcp) {
+        Ok(d) => d,
+        Err(e) => return format!("Unable to serialize recipe: {}", e),
+    };
+    match db.remove_data(RECIPE_COLLECTION_NAME, doc!{"name": name.as_str()}).await {
+        Ok(_) => {},
+        Err(e) => return error::report(&e),
+    };
+    match db.insert_data_to_collection(RECIPE_COLLECTION_NAME, vec![rdoc]).await {
+        Ok(_) => return format!("Recipe \"{}\" saved!", name),
+        Err(e) => return error::report(&e),
+    };
+}
+
+async fn handle_remove_request(name: &str, db: Box<db::Homechatbotdb>) -> String {
+    match db.remove_data(RECIPE_COLLECTION_NAME, doc!{"name": name.trim().to_lowercase()}).await {
+        Ok(_) => return "Recipe successfully removed".to_string(),
+        Err(e) => return error::report(&e),
+    };
+}
@@ -0,0 +1,110 @@
+use crate::addressing;
+use crate::admin;
+use crate::bgchan;
+use crate::db;
+use crate::expense;
+use crate::grocery;
+use crate::ha;
+use crate::meal;
+use crate::pantry;
+use crate::recipe;
+use crate::reply::Reply;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+// Everything a command handler gets to know about the message
+pub struct Request {
+    pub args: String,
+    pub sender: String,
+    pub room: String,
+    // Whether only the sender and the bot are in the room
+    pub is_dm: bool,
+    pub db: Box<db::Homechatbotdb>,
+}
+
+pub type Handler = Arc<dyn Fn(Request) -> Pin<Box<dyn Future<Output = Reply> + Send>> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct Command {
+    pub names: Vec<String>,
+    // Used as the metrics label, so it has to come from a small fixed set
+    pub label: String,
+    pub help: String,
+    pub handler: Handler,
+}
+
+// The chat commands the bot knows, looked up by their first word. Modules
+// add their commands at startup instead of growing message_triage.
+#[derive(Clone, Default)]
+pub struct Registry {
+    commands: Vec<Command>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        return Registry::default();
+    }
+
+    pub fn register<F, Fut>(&mut self, names: &[&str], label: &str, help: &str, handler: F)
+    where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Reply> + Send + 'static {
+        let names : Vec<String> = names.iter().map(|n| n.to_lowercase()).collect();
+        // A later registration wins. Commands from the configuration check
+        // find() first, so they can not replace the built-in ones.
+        self.commands.retain(|c| !c.names.iter().any(|n| names.contains(n)));
+        self.commands.push(Command{
+            names: names,
+            label: label.to_string(),
+            help: help.to_string(),
+            handler: Arc::new(move |req| Box::pin(handler(req))),
+        });
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Command> {
+        let name = name.to_lowercase();
+        return self.commands.iter().find(|c| c.names.contains(&name));
+    }
+
+    pub fn help(&self) -> String {
+        let mut msg = String::from("The following commands are currently supported:");
+        for c in self.commands.iter() {
+            msg = format!("{}\n    {}", msg, c.help);
+        }
+        return msg;
+    }
+}
+
+// The commands built into the bot
+pub fn builtin() -> Registry {
+    let mut reg = Registry::new();
+    reg.register(&["bgchan", "tv"], "bgchan", "tv (or bgchan)", |r: Request| async move {
+        bgchan::handle_bgchan_command(r.args, r.sender.as_str(), r.room.as_str(), r.is_dm, r.db).await.into()
+    });
+    reg.register(&["gro", "grocery"], "gro", "gro / grocery", |r: Request| async move {
+        grocery::handle_grocery_command(r.args, r.sender.as_str(), r.room.as_str(), r.db).await
+    });
+    reg.register(&["recipe"], "recipe", "recipe", |r: Request| async move {
+        recipe::handle_recipe_command(r.args, r.db).await.into()
+    });
+    reg.register(&["meal"], "meal", "meal", |r: Request| async move {
+        meal::handle_meal_command(r.args, r.db).await.into()
+    });
+    reg.register(&["pantry"], "pantry", "pantry", |r: Request| async move {
+        pantry::handle_pantry_command(r.args, r.db).await.into()
+    });
+    reg.register(&["expense"], "expense", "expense", |r: Request| async move {
+        expense::handle_expense_command(r.args, r.sender.as_str(), r.db).await
+    });
+    reg.register(&["ha"], "ha", "ha (Home Assistant)", |r: Request| async move {
+        ha::handle_ha_command(r.args, r.sender.as_str(), r.db).await.into()
+    });
+    reg.register(&["quiet"], "quiet", "quiet on|off (ignore unknown commands in this room)", |r: Request| async move {
+        addressing::handle_quiet_command(r.args.as_str(), r.room.as_str(), r.db).await.into()
+    });
+    reg.register(&["admin"], "admin", "admin (for the users in \"admin_users\")", |r: Request| async move {
+        admin::handle_admin_command(r.args, r.sender.as_str(), r.room.as_str(), r.is_dm, r.db).await
+    });
+    return reg;
+}
@@ -0,0 +1,46 @@
+use crate::metrics;
+use matrix_sdk::{
+    Client,
+    ruma::{RoomId, events::{AnyMessageEventContent, room::message::MessageEventContent}},
+};
+use matrix_sdk_common::uuid::Uuid;
+use mime::Mime;
+use std::convert::TryFrom;
+use tracing::error;
+
+pub enum Reply {
+    Text(String),
+    // The message was not a known command
+    Unknown,
+    File {
+        name: String,
+        content_type: Mime,
+        data: Vec<u8>,
+    },
+}
+
+impl From<String> for Reply {
+    fn from(msg: String) -> Reply {
+        return Reply::Text(msg);
+    }
+}
+
+// Posts a message to a room the bot is in, outside of any conversation
+pub async fn send_notification(client: &Client, room: &str, msg: &str) {
+    let room_id = match RoomId::try_from(room) {
+        Ok(r) => r,
+        Err(e) => {
+            error!(room = %room, "Invalid room ID: {}", e);
+            return;
+        },
+    };
+    let txt_msg = AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(msg));
+    let txn_id = Uuid::new_v4();
+    match client.room_send(&room_id, txt_msg, Some(txn_id)).await {
+        Ok(_) => {},
+        Err(e) => {
+            metrics::record_error("send");
+            error!(room = %room, "Unable to send notification: {:?}", e);
+        },
+    };
+}
@@ -0,0 +1,174 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+use crate::metrics;
+
+const MIN_BACKOFF : Duration = Duration::from_secs(1);
+const MAX_BACKOFF : Duration = Duration::from_secs(60);
+// A task which ran at least this long before failing starts over with the
+// minimal backoff
+const HEALTHY_RUN : Duration = Duration::from_secs(60);
+
+// Shared shutdown state: a flag telling new work to stay away, a counter of
+// commands still being handled and a channel waking up waiting tasks
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    in_flight: Arc<AtomicUsize>,
+    notify: watch::Receiver<bool>,
+    trigger: Arc<watch::Sender<bool>>,
+}
+
+// Decrements the in-flight counter when the command is finished, even if
+// its handler panicked
+pub struct InFlightGuard {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (tx, rx) = watch::channel(false);
+        return Shutdown{
+            requested: Arc::new(AtomicBool::new(false)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            notify: rx,
+            trigger: Arc::new(tx),
+        };
+    }
+
+    pub fn is_requested(&self) -> bool {
+        return self.requested.load(Ordering::SeqCst);
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        let _ = self.trigger.send(true);
+    }
+
+    // Resolves once a shutdown was requested
+    pub async fn wait(&self) {
+        let mut rx = self.notify.clone();
+        while !*rx.borrow() {
+            if rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // Registers a command being handled. Returns None if the bot is
+    // shutting down and the command should not be started.
+    pub fn begin_command(&self) -> Option<InFlightGuard> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlightGuard{in_flight: self.in_flight.clone()};
+        if self.is_requested() {
+            return None;
+        }
+        return Some(guard);
+    }
+
+    // Waits for the commands being handled to finish, at most for the given
+    // time. Returns the number of commands which did not finish.
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        let start = Instant::now();
+        loop {
+            let left = self.in_flight.load(Ordering::SeqCst);
+            if left == 0 || start.elapsed() >= timeout {
+                return left;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+// Resolves on SIGINT or SIGTERM
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        let mut term = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Unable to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            },
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+            _ = term.recv() => info!("Received SIGTERM"),
+        };
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("Received Ctrl-C");
+    }
+}
+
+// Runs a background task and restarts it with exponential backoff whenever
+// it returns or panics, until a shutdown is requested
+pub fn supervise<F, Fut>(name: &'static str, shutdown: Shutdown, task: F) -> tokio::task::JoinHandle<()>
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    return tokio::spawn(async move {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let started = Instant::now();
+            let mut handle = tokio::spawn(task());
+            tokio::select! {
+                res = &mut handle => {
+                    match res {
+                        Ok(_) => warn!(task = name, "Background task stopped"),
+                        Err(e) => error!(task = name, "Background task failed: {}", e),
+                    };
+                    metrics::record_error("task");
+                },
+                _ = shutdown.wait() => {
+                    handle.abort();
+                    info!(task = name, "Background task stopped for shutdown");
+                    return;
+                },
+            };
+            if started.elapsed() >= HEALTHY_RUN {
+                backoff = MIN_BACKOFF;
+            }
+            info!(task = name, "Restarting background task in {}s", backoff.as_secs());
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {},
+                _ = shutdown.wait() => return,
+            };
+            backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+        }
+    });
+}
+
+// Retries an operation with exponential backoff until it succeeds or a
+// shutdown is requested
+pub async fn retry<T, F, Fut>(name: &str, shutdown: &Shutdown, op: F) -> Option<T>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, String>>,
+{
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match op().await {
+            Ok(v) => return Some(v),
+            Err(e) => warn!(operation = name, "Failed, retrying in {}s: {}", backoff.as_secs(), e),
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {},
+            _ = shutdown.wait() => return None,
+        };
+        backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,77 @@
+use crate::bgchan::epg::{self, Guide};
+use crate::bgchan::lineup::Channel;
+use chrono::{DateTime, FixedOffset};
+use std::sync::Arc;
+
+const GUIDE : &str = include_str!("fixtures/guide.xml");
+
+fn guide() -> Guide {
+    return epg::parse_xmltv(GUIDE).expect("guide");
+}
+
+// During the weather on BNT 1 and the quiz on bTV
+fn evening() -> DateTime<FixedOffset> {
+    return DateTime::parse_from_rfc3339("2021-10-15T19:15:00+03:00").expect("time");
+}
+
+fn channel(number: u32, name: &str) -> Channel {
+    return Channel{chanid: format!("living/{}", number), tv: "living".to_string(), number: number, name: name.to_string(), aliases: vec![], language: "bg".to_string(), favourite: false};
+}
+
+#[test]
+fn guide_is_parsed_without_the_broken_programmes() {
+    let g = guide();
+    assert_eq!(g.channels.len(), 3);
+    assert_eq!(g.channels[0].names, vec!["BNT 1"]);
+    let titles : Vec<&str> = g.programmes.iter().map(|p| p.title.as_str()).collect();
+    assert_eq!(titles.len(), 8);
+    assert!(!titles.contains(&"Broken Start") && !titles.contains(&"Broken Stop"));
+    assert!(g.programmes.windows(2).all(|w| w[0].start <= w[1].start));
+    assert!(epg::parse_xmltv("<channels/>").is_err());
+}
+
+#[test]
+fn missing_stop_times_are_taken_from_the_next_programme() {
+    let g = guide();
+    let weather = g.programmes.iter().find(|p| p.title == "Weather").expect("weather");
+    assert_eq!(weather.stop, Some(DateTime::parse_from_rfc3339("2021-10-15T19:30:00+03:00").expect("time")));
+    let late = g.programmes.iter().find(|p| p.title == "Late Movie").expect("late movie");
+    assert_eq!(late.stop, None);
+}
+
+#[test]
+fn now_next_tonight_and_find() {
+    let g = guide();
+    assert_eq!(epg::now_request(&g, &[], evening()), "BNT 1: Weather (until 19:30)\nbTV: Quiz Show (until 20:30)\n");
+    assert_eq!(epg::next_request("bnt1", &g, &[], evening()).expect("next"),
+        "BNT 1:\n19:00 Weather\n19:30 Film: The Long Way\n21:00 Documentary\n09:00 Morning Football Review\n");
+    assert_eq!(epg::tonight_request(&g, &[], evening()),
+        "BNT 1:\n    19:30 Film: The Long Way\n    21:00 Documentary\nbTV:\n    19:00 Quiz Show\n    20:30 Football: Levski v CSKA\nNova:\n    20:00 Late Movie\n");
+    assert_eq!(epg::find_request("football", &g, &[], evening()),
+        "20:30 bTV: Football: Levski v CSKA\nSat 09:00 BNT 1: Morning Football Review\n");
+}
+
+#[test]
+fn lineup_names_the_channels() {
+    let g = guide();
+    let lineup = vec![channel(1, "BNT1"), channel(2, "BTV")];
+    assert_eq!(epg::now_request(&g, &lineup, evening()), "BNT1: Weather (until 19:30)\nBTV: Quiz Show (until 20:30)\n");
+    let (chan, title) = epg::channel_showing("quiz", &g, &lineup, evening()).expect("airing");
+    assert_eq!((chan.number, title.as_str()), (2, "Quiz Show"));
+    assert!(epg::channel_showing("late movie", &g, &lineup, evening()).is_err());
+}
+
+#[tokio::test]
+async fn guide_is_cached_until_it_expires() {
+    let path = std::env::temp_dir().join(format!("homechatbot-guide-{}.xml", std::process::id()));
+    std::fs::write(&path, GUIDE).expect("guide file");
+    let source = path.to_string_lossy().to_string();
+    let first = epg::load_guide(source.as_str()).await.expect("guide");
+    std::fs::write(&path, "<tv></tv>").expect("new guide file");
+    let cached = epg::load_guide(source.as_str()).await.expect("cached guide");
+    assert!(Arc::ptr_eq(&first, &cached));
+    epg::expire_cache();
+    let reloaded = epg::load_guide(source.as_str()).await.expect("reloaded guide");
+    assert_eq!(reloaded.programmes.len(), 0);
+    std::fs::remove_file(&path).expect("cleanup");
+}
@@ -0,0 +1,32 @@
+use super::{Harness, ALICE, BOT, ROOM};
+use super::homeserver::joined_sync;
+use crate::expense::{format_amount, parse_amount};
+
+#[test]
+fn amounts_are_parsed_to_cents() {
+    assert_eq!(parse_amount("12").expect("amount"), 1200);
+    assert_eq!(parse_amount("12.5").expect("amount"), 1250);
+    assert_eq!(parse_amount("12,05").expect("amount"), 1205);
+    assert_eq!(parse_amount("1000000").expect("amount"), 100_000_000);
+    assert!(parse_amount("1000000.01").is_err());
+    assert!(parse_amount("-3").is_err());
+    assert!(parse_amount("1.234").is_err());
+    assert_eq!(format_amount(-1205), "-12.05");
+}
+
+#[test]
+fn huge_amounts_do_not_overflow() {
+    // Fits in an i64, but not once turned into cents
+    assert!(parse_amount("99999999999999999").is_err());
+    assert!(parse_amount("92233720368547758.07").is_err());
+    assert!(parse_amount("999999999999999999999").is_err());
+}
+
+#[tokio::test]
+async fn huge_expenses_are_refused() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOT])).await;
+    let refused = h.say(ALICE, "expense 99999999999999999 groceries").await;
+    assert!(refused[0].starts_with("Invalid amount \"99999999999999999\""), "unexpected reply: {}", refused[0]);
+    assert_eq!(h.say(ALICE, "expense balance").await, vec!["No expenses recorded"]);
+}
@@ -0,0 +1,38 @@
+use super::{Harness, ALICE, BOT, ROOM};
+use super::homeserver::joined_sync;
+use crate::csv;
+use crate::grocery::price_jump;
+
+#[test]
+fn price_jumps_are_measured_against_the_average() {
+    assert_eq!(price_jump(250, vec![]), None);
+    assert_eq!(price_jump(120, vec![100, 100]), None);
+    assert_eq!(price_jump(121, vec![100, 100]), Some((21, 100)));
+    assert_eq!(price_jump(300, vec![100, 200]), Some((100, 150)));
+    // Broken records are left out
+    assert_eq!(price_jump(300, vec![0, -50, 100]), Some((200, 100)));
+}
+
+#[test]
+fn huge_prices_do_not_overflow() {
+    assert_eq!(price_jump(i64::MAX, vec![i64::MAX, i64::MAX]), None);
+    assert_eq!(price_jump(i64::MAX, vec![1]), Some((i64::MAX, 1)));
+    assert_eq!(price_jump(i64::MAX, vec![i64::MAX / 2, i64::MAX / 2]), Some((100, i64::MAX / 2)));
+}
+
+#[tokio::test]
+async fn unusual_prices_are_reported() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOT])).await;
+    assert_eq!(h.say(ALICE, "gro add dairy\nmilk\nmilk").await, vec!["Items successfully added!"]);
+    assert_eq!(h.say(ALICE, "gro bought 1 1.00 lidl").await, vec!["milk bought for 1.00"]);
+    assert_eq!(h.say(ALICE, "gro bought 2 1.50 lidl").await, vec!["milk bought for 1.50\nWARNING: this is 50% above the usual price of 1.00"]);
+}
+
+#[test]
+fn csv_fields_survive_a_round_trip() {
+    let fields = ["milk", "cheese, grated", "\"fresh\" bread"];
+    let line : Vec<String> = fields.iter().map(|f| csv::field(f)).collect();
+    assert_eq!(line.join(","), "milk,\"cheese, grated\",\"\"\"fresh\"\" bread\"");
+    assert_eq!(csv::parse_line(line.join(",").as_str()), fields);
+}
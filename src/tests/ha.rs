@@ -0,0 +1,73 @@
+use super::{Harness, ALICE, BOB, BOT, ROOM};
+use super::homeserver::joined_sync;
+use super::stub::StubServer;
+use crate::db;
+use hyper::{Method, StatusCode};
+use mongodb::bson::doc;
+use serde_json::json;
+
+// Home Assistant behind a stub, with BOB only allowed the kids' lights
+async fn setup() -> (Harness, StubServer) {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOB, BOT])).await;
+    let ha = StubServer::start().await;
+    h.db.insert_data_to_collection(db::CONFIG_COLLECTION_NAME, vec![
+        doc!{"ha_url": ha.url.as_str(), "ha_token": "ha_secret"},
+        doc!{"ha_roles": [
+            {"role": "parents", "users": [ALICE], "entities": ["*"]},
+            {"role": "kids", "users": [BOB], "entities": ["light.kids_*"]},
+        ]},
+    ]).await.expect("config");
+    return (h, ha);
+}
+
+#[tokio::test]
+async fn state_is_read_from_home_assistant() {
+    let (h, ha) = setup().await;
+    ha.respond(Method::GET, "/api/states/light.kids_lamp", StatusCode::OK, json!({
+        "entity_id": "light.kids_lamp", "state": "on", "attributes": {"friendly_name": "Kids lamp"},
+    }));
+    assert_eq!(h.say(BOB, "ha state light.kids_lamp").await, vec!["Kids lamp: on"]);
+    assert_eq!(h.say(BOB, "ha state light.kids_gone").await, vec!["Home Assistant has no entity light.kids_gone"]);
+    let reqs = ha.requests();
+    assert_eq!(reqs.len(), 2);
+    assert_eq!((&reqs[0].method, reqs[0].path.as_str()), (&Method::GET, "/api/states/light.kids_lamp"));
+    assert_eq!(reqs[0].headers.get("authorization").and_then(|v| v.to_str().ok()), Some("Bearer ha_secret"));
+}
+
+#[tokio::test]
+async fn services_are_called_with_their_data() {
+    let (h, ha) = setup().await;
+    ha.respond(Method::POST, "/api/services/light/turn_on", StatusCode::OK, json!([]));
+    ha.respond(Method::POST, "/api/services/light/frobnicate", StatusCode::BAD_REQUEST, json!({"message": "Service not found"}));
+    assert_eq!(h.say(BOB, "ha call light.turn_on light.kids_lamp brightness=120").await, vec!["Called light.turn_on on light.kids_lamp"]);
+    assert_eq!(h.say(BOB, "ha call light.frobnicate light.kids_lamp").await, vec!["Home Assistant does not know the service light.frobnicate or its data"]);
+    let reqs = ha.requests();
+    assert_eq!(reqs[0].body, json!({"entity_id": "light.kids_lamp", "brightness": 120}));
+}
+
+#[tokio::test]
+async fn roles_limit_the_entities() {
+    let (h, ha) = setup().await;
+    ha.respond(Method::GET, "/api/states/light.hall", StatusCode::OK, json!({"entity_id": "light.hall", "state": "off"}));
+    assert_eq!(h.say(BOB, "ha state light.hall").await, vec!["You are not allowed to use light.hall"]);
+    assert_eq!(h.say(ALICE, "ha state light.hall").await, vec!["light.hall: off"]);
+    // Matches light.kids_*, but must not reach another entity
+    let sneaky = h.say(BOB, "ha state light.kids_/../../states/lock.front_door").await;
+    assert!(sneaky[0].contains("neither an alias nor an entity ID"), "unexpected reply: {}", sneaky[0]);
+    let alias = h.say(ALICE, "ha alias add door light.kids_/../../states/lock.front_door").await;
+    assert!(alias[0].contains("is not an entity ID"), "unexpected reply: {}", alias[0]);
+    assert_eq!(ha.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn aliases_are_changed_by_admins_only() {
+    let (h, _ha) = setup().await;
+    let denied = h.say(BOB, "ha alias add kitchen light.kids_lamp").await;
+    assert!(denied[0].starts_with("Aliases can only be changed by"), "unexpected reply: {}", denied[0]);
+    assert_eq!(h.say(ALICE, "ha alias add kitchen light.kitchen").await, vec!["Alias \"kitchen\" now points to light.kitchen"]);
+    let denied = h.say(BOB, "ha alias rem kitchen").await;
+    assert!(denied[0].starts_with("Aliases can only be changed by"), "unexpected reply: {}", denied[0]);
+    let aliases = h.say(BOB, "ha aliases").await;
+    assert!(aliases[0].contains("kitchen") && aliases[0].contains("light.kitchen"), "unexpected aliases: {}", aliases[0]);
+}
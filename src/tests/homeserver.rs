@@ -0,0 +1,166 @@
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+const CLIENT_API_PREFIX : &str = "/_matrix/client/r0";
+
+// A request the bot made, with the room IDs in the path decoded
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub method: Method,
+    pub path: String,
+    pub body: Value,
+}
+
+#[derive(Default)]
+struct State {
+    syncs: VecDeque<Value>,
+    requests: Vec<Recorded>,
+    next_event: u32,
+}
+
+// Just enough of a Matrix homeserver for the bot to log in, sync, join,
+// leave and send messages. Sync responses are queued by the test; every
+// request is recorded so the test can check what the bot did.
+pub struct MockHomeserver {
+    pub url: String,
+    state: Arc<Mutex<State>>,
+}
+
+// Undoes the percent encoding of room IDs, event types and transaction IDs
+fn decode_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out : Vec<u8> = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = path.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            },
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            },
+        };
+    }
+    return String::from_utf8_lossy(&out).to_string();
+}
+
+fn respond(status: StatusCode, body: Value) -> Response<Body> {
+    return Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap_or_else(|_| Response::new(Body::empty()));
+}
+
+fn route(method: &Method, path: &str, state: &mut State, user_id: &str) -> Response<Body> {
+    let parts : Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    match (method, parts.as_slice()) {
+        (&Method::POST, ["login"]) => return respond(StatusCode::OK, json!({"user_id": user_id, "access_token": "mock_token", "device_id": "MOCKDEVICE"})),
+        (&Method::GET, ["sync"]) => {
+            let next = state.syncs.pop_front().unwrap_or(json!({}));
+            let mut sync = json!({"next_batch": format!("batch{}", state.requests.len())});
+            if let (Some(s), Some(n)) = (sync.as_object_mut(), next.as_object()) {
+                s.extend(n.clone());
+            }
+            return respond(StatusCode::OK, sync);
+        },
+        (&Method::PUT, ["rooms", _, "send", _, _]) => {
+            state.next_event += 1;
+            return respond(StatusCode::OK, json!({"event_id": format!("$event{}:localhost", state.next_event)}));
+        },
+        (&Method::POST, ["rooms", room, "join"]) | (&Method::POST, ["join", room]) => return respond(StatusCode::OK, json!({"room_id": room})),
+        (&Method::POST, ["rooms", _, "leave"]) => return respond(StatusCode::OK, json!({})),
+        (&Method::GET, ["rooms", _, "members"]) => return respond(StatusCode::OK, json!({"chunk": []})),
+        (&Method::GET, ["profile", ..]) => return respond(StatusCode::OK, json!({})),
+        (&Method::POST, ["keys", "upload"]) => return respond(StatusCode::OK, json!({"one_time_key_counts": {}})),
+        (&Method::POST, ["keys", "query"]) => return respond(StatusCode::OK, json!({"device_keys": {}})),
+        _ => return respond(StatusCode::NOT_FOUND, json!({"errcode": "M_UNRECOGNIZED", "error": "Unrecognized request"})),
+    };
+}
+
+async fn handle(req: Request<Body>, state: Arc<Mutex<State>>, user_id: String) -> Result<Response<Body>, Infallible> {
+    let method = req.method().clone();
+    let path = decode_path(req.uri().path());
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(b) => serde_json::from_slice::<Value>(&b).unwrap_or(Value::Null),
+        Err(_) => Value::Null,
+    };
+    let path = path.strip_prefix(CLIENT_API_PREFIX).unwrap_or(path.as_str()).to_string();
+    let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+    state.requests.push(Recorded{method: method.clone(), path: path.clone(), body: body});
+    return Ok(route(&method, path.as_str(), &mut state, user_id.as_str()));
+}
+
+impl MockHomeserver {
+    // Listens on a free port on the loopback interface until the test ends.
+    // Logging in gives the bot user_id.
+    pub async fn start(user_id: &str) -> MockHomeserver {
+        let state = Arc::new(Mutex::new(State::default()));
+        let make_svc = make_service_fn({
+            let state = state.clone();
+            let user_id = user_id.to_string();
+            move |_conn| {
+                let state = state.clone();
+                let user_id = user_id.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| handle(req, state.clone(), user_id.clone())))
+                }
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        return MockHomeserver{url: url, state: state};
+    }
+
+    // The next /sync returns this, merged into an otherwise empty response
+    pub fn queue_sync(&self, sync: Value) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).syncs.push_back(sync);
+    }
+
+    pub fn requests(&self, method: Method, path: &str) -> Vec<Recorded> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        return state.requests.iter().filter(|r| r.method == method && r.path == path).cloned().collect();
+    }
+
+    // The bodies of the text messages sent to a room, oldest first
+    pub fn sent_messages(&self, room: &str) -> Vec<String> {
+        let prefix = format!("/rooms/{}/send/m.room.message/", room);
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        return state.requests.iter()
+            .filter(|r| r.method == Method::PUT && r.path.starts_with(prefix.as_str()))
+            .filter_map(|r| r.body.get("body").and_then(|b| b.as_str()).map(|b| b.to_string()))
+            .collect();
+    }
+}
+
+// A sync with an invitation into room, sent by inviter who created it
+pub fn invite_sync(room: &str, inviter: &str, invitee: &str) -> Value {
+    return json!({"rooms": {"invite": {room: {"invite_state": {"events": [
+        {"type": "m.room.create", "state_key": "", "sender": inviter, "content": {"creator": inviter}},
+        {"type": "m.room.member", "state_key": invitee, "sender": inviter, "content": {"membership": "invite"}},
+    ]}}}}});
+}
+
+// A sync with room joined by all of members
+pub fn joined_sync(room: &str, members: &[&str]) -> Value {
+    let mut events = vec![json!({
+        "type": "m.room.create", "state_key": "", "event_id": "$create:localhost",
+        "sender": members[0], "origin_server_ts": 0, "content": {"creator": members[0]},
+    })];
+    for (i, m) in members.iter().enumerate() {
+        events.push(json!({
+            "type": "m.room.member", "state_key": m, "event_id": format!("$member{}:localhost", i),
+            "sender": m, "origin_server_ts": 0, "content": {"membership": "join"},
+        }));
+    }
+    return json!({"rooms": {"join": {room: {"state": {"events": events}, "timeline": {"events": []}}}}});
+}
@@ -0,0 +1,316 @@
+// Drives the bot end to end against a mock homeserver and the in-memory
+// database, so "cargo test" needs neither Matrix nor MongoDB
+use crate::{addressing, backup, check_invites, db, handle_message, migrate, ratelimit, registry};
+use homeserver::{invite_sync, joined_sync, MockHomeserver};
+use hyper::Method;
+use matrix_sdk::{Client, SyncSettings, reqwest::Url, room::Room};
+use matrix_sdk::ruma::{RoomId, events::{SyncMessageEvent, room::message::MessageEventContent}};
+use mongodb::bson::doc;
+use serde_json::json;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+mod epg;
+mod expense;
+mod grocery;
+mod ha;
+mod homeserver;
+mod mqtt;
+mod settings;
+mod stub;
+mod tv;
+mod webhook;
+
+const BOT : &str = "@bot:localhost";
+// Allowed to invite the bot and an admin
+const ALICE : &str = "@alice:localhost";
+// Allowed to invite the bot, but not an admin
+const BOB : &str = "@bob:localhost";
+const MALLORY : &str = "@mallory:localhost";
+// Another bot in the room, in "ignored_users"
+const ROBOT : &str = "@robot:localhost";
+// A bot nobody told the bot about
+const ECHOBOT : &str = "@echobot:localhost";
+const ROOM : &str = "!kitchen:localhost";
+// Commands per user and minute, the room allows twice as many
+const USER_LIMIT : usize = 5;
+
+struct Harness {
+    server: MockHomeserver,
+    client: Client,
+    db: Box<db::Homechatbotdb>,
+    commands: Arc<registry::Registry>,
+    limiter: ratelimit::Limiter,
+}
+
+impl Harness {
+    async fn new() -> Harness {
+        let server = MockHomeserver::start(BOT).await;
+        let client = Client::new(Url::parse(server.url.as_str()).expect("mock URL")).expect("client");
+        client.login("bot", "secret", None, None).await.expect("login");
+        let db = Box::new(db::Homechatbotdb::in_memory().await.expect("database"));
+        migrate::run(db.clone(), false).await.expect("migrations");
+        db.insert_data_to_collection(db::CONFIG_COLLECTION_NAME, vec![
+            doc!{"allowed_users": [ALICE, BOB]},
+            doc!{"admin_users": [ALICE]},
+            doc!{"ignored_users": [ROBOT]},
+            doc!{"rate_limits": {"user": USER_LIMIT as u32, "room": 2 * USER_LIMIT as u32, "window_secs": 60}},
+        ]).await.expect("config");
+        let limiter = ratelimit::Limiter::load(db.clone()).await.expect("rate limits");
+        return Harness{server: server, client: client, db: db, commands: Arc::new(registry::builtin()), limiter: limiter};
+    }
+
+    async fn sync(&self, sync: serde_json::Value) {
+        self.server.queue_sync(sync);
+        self.client.sync_once(SyncSettings::default()).await.expect("sync");
+    }
+
+    // Sends body to ROOM as sender and returns what the bot answered
+    async fn say(&self, sender: &str, body: &str) -> Vec<String> {
+        let before = self.server.sent_messages(ROOM).len();
+        let room_id = RoomId::try_from(ROOM).expect("room ID");
+        let room = self.client.get_joined_room(&room_id).expect("joined room");
+        let ev : SyncMessageEvent<MessageEventContent> = serde_json::from_value(json!({
+            "type": "m.room.message", "event_id": "$message:localhost", "sender": sender,
+            "origin_server_ts": 0, "content": {"msgtype": "m.text", "body": body},
+        })).expect("message event");
+        let addr = addressing::Addressing::new(None, vec![BOT.to_string()]);
+        handle_message(ev, Room::Joined(room), self.client.clone(), self.db.clone(), addr, self.commands.clone(), self.limiter.clone()).await;
+        return self.server.sent_messages(ROOM).split_off(before);
+    }
+}
+
+#[tokio::test]
+async fn invite_from_allowed_user_is_accepted() {
+    let h = Harness::new().await;
+    h.sync(invite_sync(ROOM, ALICE, BOT)).await;
+    check_invites(&h.client, h.db.clone()).await;
+    assert_eq!(h.server.requests(Method::POST, format!("/rooms/{}/join", ROOM).as_str()).len(), 1);
+    assert_eq!(h.server.requests(Method::POST, format!("/rooms/{}/leave", ROOM).as_str()).len(), 0);
+}
+
+#[tokio::test]
+async fn invite_from_unknown_user_is_rejected() {
+    let h = Harness::new().await;
+    h.sync(invite_sync(ROOM, MALLORY, BOT)).await;
+    check_invites(&h.client, h.db.clone()).await;
+    assert_eq!(h.server.requests(Method::POST, format!("/rooms/{}/join", ROOM).as_str()).len(), 0);
+    assert_eq!(h.server.requests(Method::POST, format!("/rooms/{}/leave", ROOM).as_str()).len(), 1);
+}
+
+#[tokio::test]
+async fn grocery_items_added_are_listed() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOT])).await;
+    assert_eq!(h.say(ALICE, "gro add dairy\nmilk\ncheese").await, vec!["Items successfully added!"]);
+    let list = h.say(ALICE, "gro list").await;
+    assert_eq!(list.len(), 1);
+    assert!(list[0].contains("milk") && list[0].contains("cheese"), "unexpected list: {}", list[0]);
+    assert_eq!(h.say(ALICE, "gro rem 1").await, vec!["Items successfully removed"]);
+    let list = h.say(ALICE, "gro list").await;
+    assert!(!list[0].contains("milk") && list[0].contains("cheese"), "unexpected list: {}", list[0]);
+}
+
+#[tokio::test]
+async fn admin_commands_need_an_admin() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOB, BOT])).await;
+    let denied = h.say(BOB, "admin webhook list").await;
+    assert!(denied[0].starts_with("Admin commands are only for"), "unexpected reply: {}", denied[0]);
+    assert_eq!(h.say(ALICE, "admin webhook list").await, vec!["No webhooks"]);
+}
+
+#[tokio::test]
+async fn tv_devices_are_changed_by_admins_only() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOB, BOT])).await;
+    let denied = h.say(BOB, "tv device add living http://127.0.0.1:1/").await;
+    assert!(denied[0].starts_with("TV devices can only be changed by"), "unexpected reply: {}", denied[0]);
+    let denied = h.say(BOB, "tv device rem living").await;
+    assert!(denied[0].starts_with("TV devices can only be changed by"), "unexpected reply: {}", denied[0]);
+    let refused = h.say(ALICE, "tv device add living http://127.0.0.1:1/ secret").await;
+    assert!(refused[0].starts_with("A TV token is only accepted in a direct chat"), "unexpected reply: {}", refused[0]);
+    assert_eq!(h.say(ALICE, "tv device add living http://127.0.0.1:1/").await, vec!["TV \"living\" saved"]);
+    assert_eq!(h.say(BOB, "tv devices").await, vec!["living http://127.0.0.1:1/ (used here)\n"]);
+}
+
+#[tokio::test]
+async fn unknown_commands_are_answered() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOT])).await;
+    assert_eq!(h.say(ALICE, "frobnicate the toaster").await, vec!["UNKNOWN"]);
+    assert_eq!(h.say(ALICE, "test").await, vec!["running"]);
+}
+
+#[tokio::test]
+async fn own_messages_are_ignored() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOT])).await;
+    assert_eq!(h.say(BOT, "gro list").await.len(), 0);
+}
+
+#[tokio::test]
+async fn flooding_gets_one_cool_down_reply() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOB, BOT])).await;
+    for _ in 0..USER_LIMIT {
+        assert_eq!(h.say(BOB, "test").await, vec!["running"]);
+    }
+    let cool_down = h.say(BOB, "test").await;
+    assert!(cool_down[0].starts_with("Too many commands"), "unexpected reply: {}", cool_down[0]);
+    assert_eq!(h.say(BOB, "test").await.len(), 0);
+    // Only the sender is limited, not the room
+    assert_eq!(h.say(ALICE, "test").await, vec!["running"]);
+}
+
+#[tokio::test]
+async fn bots_and_echoes_are_ignored() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOB, ROBOT, ECHOBOT, BOT])).await;
+    assert_eq!(h.say(ROBOT, "test").await.len(), 0);
+    assert_eq!(h.say(ALICE, "frobnicate").await, vec!["UNKNOWN"]);
+    // Anyone may repeat a reply once, a person included
+    assert_eq!(h.say(BOB, "UNKNOWN").await, vec!["UNKNOWN"]);
+    // Another bot repeating the replies must not keep a conversation going
+    assert_eq!(h.say(ECHOBOT, "UNKNOWN").await, vec!["UNKNOWN"]);
+    assert_eq!(h.say(ECHOBOT, "UNKNOWN").await.len(), 0);
+}
+
+#[tokio::test]
+async fn migrations_run_once_and_dry_run_changes_nothing() {
+    let db = Box::new(db::Homechatbotdb::in_memory().await.expect("database"));
+    // A grocery item from before the added date was recorded
+    db.create_collection("groceries").await.expect("collection");
+    db.insert_data_to_collection("groceries", vec![doc!{"groid": 1, "category": "dairy", "product": "milk"}]).await.expect("item");
+    let planned = migrate::run(db.clone(), true).await.expect("dry run");
+    assert!(planned.iter().any(|s| s.starts_with("Migration 1:")), "unexpected plan: {:?}", planned);
+    assert!(!db.check_collection_exists("pantry").await.expect("collections"));
+    let done = migrate::run(db.clone(), false).await.expect("migrations");
+    assert_eq!(done, planned);
+    assert!(db.check_collection_exists("pantry").await.expect("collections"));
+    let items = db.get_generic_data_collection::<mongodb::bson::Document>("groceries", doc!{"added": {"$exists": true}}, doc!{}).await.expect("items");
+    assert_eq!(items.len(), 1);
+    assert_eq!(migrate::run(db.clone(), false).await.expect("migrations").len(), 0);
+}
+
+#[tokio::test]
+async fn backup_restores_what_was_backed_up() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOT])).await;
+    assert_eq!(h.say(ALICE, "gro add dairy\nmilk").await, vec!["Items successfully added!"]);
+    let archive = backup::create_archive(h.db.clone()).await.expect("backup");
+    assert_eq!(h.say(ALICE, "gro rem 1").await, vec!["Items successfully removed"]);
+    assert!(backup::restore_archive(b"{\"groceries\": []}", h.db.clone()).await.is_err());
+    backup::restore_archive(&archive, h.db.clone()).await.expect("restore");
+    let list = h.say(ALICE, "gro list").await;
+    assert!(list[0].contains("milk"), "unexpected list: {}", list[0]);
+    // The config came back too, so the admin is still an admin
+    assert_eq!(h.say(ALICE, "admin webhook list").await, vec!["No webhooks"]);
+}
+
+#[tokio::test]
+async fn backup_is_only_uploaded_in_a_direct_chat() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOB, BOT])).await;
+    let refused = h.say(ALICE, "admin backup").await;
+    assert!(refused[0].starts_with("The backup holds every password"), "unexpected reply: {}", refused[0]);
+}
+
+#[tokio::test]
+async fn failed_restore_puts_the_data_back() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOT])).await;
+    assert_eq!(h.say(ALICE, "gro add dairy\nmilk").await, vec!["Items successfully added!"]);
+    let mut archive : serde_json::Value = serde_json::from_slice(&backup::create_archive(h.db.clone()).await.expect("backup")).expect("JSON");
+    // config is restored first, without any admin, then the duplicate IDs
+    // make the groceries fail
+    archive["collections"]["config"] = json!([]);
+    archive["collections"]["groceries"] = json!([
+        {"groid": 7, "category": "bakery", "product": "bread", "added": "2021-01-01"},
+        {"groid": 7, "category": "bakery", "product": "rolls", "added": "2021-01-01"},
+    ]);
+    let msg = backup::restore_archive(archive.to_string().as_bytes(), h.db.clone()).await.expect("restore");
+    assert!(msg.starts_with("Restoring groceries failed"), "unexpected reply: {}", msg);
+    let list = h.say(ALICE, "gro list").await;
+    assert!(list[0].contains("milk") && !list[0].contains("bread"), "unexpected list: {}", list[0]);
+    assert_eq!(h.say(ALICE, "admin webhook list").await, vec!["No webhooks"]);
+}
+
+#[tokio::test]
+async fn nightly_backup_skips_the_day_it_started_late() {
+    let h = Harness::new().await;
+    let dir = std::env::temp_dir().join(format!("homechatbot-backup-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("backup directory");
+    h.db.insert_data_to_collection(db::CONFIG_COLLECTION_NAME, vec![
+        doc!{"backup": {"dir": dir.to_string_lossy().to_string(), "at": "00:00", "keep": 1}},
+    ]).await.expect("config");
+    let mut last = None;
+    assert_eq!(backup::run_scheduled(&mut last, h.db.clone()).await.expect("first run"), None);
+    // As if the bot had been running since yesterday
+    last = last.map(|d| d.pred());
+    let old = dir.join("home-chatbot-backup-2000-01-01-0300.json");
+    std::fs::write(&old, b"{}").expect("old backup");
+    let written = backup::run_scheduled(&mut last, h.db.clone()).await.expect("nightly run");
+    assert!(written.is_some());
+    assert_eq!(backup::run_scheduled(&mut last, h.db.clone()).await.expect("second run"), None);
+    // Only the newest one is kept
+    assert!(!old.exists());
+    assert_eq!(std::fs::read_dir(&dir).expect("backups").count(), 1);
+    std::fs::remove_dir_all(&dir).expect("cleanup");
+}
+
+#[tokio::test]
+async fn grocery_items_move_into_the_pantry() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOT])).await;
+    assert_eq!(h.say(ALICE, "gro add dairy\nmilk").await, vec!["Items successfully added!"]);
+    assert_eq!(h.say(ALICE, "pantry in 1, 2 l").await, vec!["milk moved into the pantry"]);
+    assert_eq!(h.say(ALICE, "pantry in 1").await, vec!["Grocery item 1 not found"]);
+    let pantry = h.say(ALICE, "pantry list").await;
+    assert!(pantry[0].contains("milk"), "unexpected pantry: {}", pantry[0]);
+}
+
+#[tokio::test]
+async fn bought_items_leave_the_list_with_their_price() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOT])).await;
+    assert_eq!(h.say(ALICE, "gro add dairy\nmilk").await, vec!["Items successfully added!"]);
+    let bought = h.say(ALICE, "gro bought 1 2.40 lidl").await;
+    assert!(bought[0].starts_with("milk bought for"), "unexpected reply: {}", bought[0]);
+    assert_eq!(h.say(ALICE, "gro bought 1 2.40").await, vec!["Grocery item 1 not found"]);
+    let prices = h.db.get_generic_data_collection::<mongodb::bson::Document>("grocery_prices", doc!{"product": "milk"}, doc!{}).await.expect("prices");
+    assert_eq!(prices.len(), 1);
+}
+
+#[tokio::test]
+async fn done_asks_for_the_total_before_clearing_the_list() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOT])).await;
+    assert_eq!(h.say(ALICE, "gro add dairy\nmilk\ncheese").await, vec!["Items successfully added!"]);
+    let asked = h.say(ALICE, "gro done").await;
+    assert!(asked[0].starts_with("This clears the 2 items"), "unexpected reply: {}", asked[0]);
+    // Added after the question, so it stays
+    assert_eq!(h.say(ALICE, "gro add bakery\nbread").await, vec!["Items successfully added!"]);
+    assert_eq!(h.say(ALICE, "42.50").await, vec!["Grocery list cleared and 42.50 logged as a shared expense"]);
+    let list = h.say(ALICE, "gro list").await;
+    assert!(!list[0].contains("milk") && list[0].contains("bread"), "unexpected list: {}", list[0]);
+    let expenses = h.db.get_generic_data_collection::<mongodb::bson::Document>("expenses", doc!{"amount_cents": 4250i64, "category": "groceries"}, doc!{}).await.expect("expenses");
+    assert_eq!(expenses.len(), 1);
+}
+
+#[tokio::test]
+async fn done_keeps_the_list_unless_answered() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOB, BOT])).await;
+    assert_eq!(h.say(ALICE, "gro add dairy\nmilk").await, vec!["Items successfully added!"]);
+    h.say(ALICE, "gro done").await;
+    // Commands still work while the question is open
+    let list = h.say(ALICE, "gro list").await;
+    assert!(list[0].contains("milk"), "unexpected list: {}", list[0]);
+    assert_eq!(h.say(ALICE, "cancel").await, vec!["The grocery list was kept"]);
+    // Only the one who asked answers
+    h.say(BOB, "gro done").await;
+    assert_eq!(h.say(ALICE, "skip").await, vec!["UNKNOWN"]);
+    assert_eq!(h.say(BOB, "skip").await, vec!["Grocery list cleared"]);
+    assert_eq!(h.say(BOB, "gro list").await, vec!["List is empty"]);
+}
@@ -0,0 +1,84 @@
+use super::ROOM;
+use crate::{db, mqtt, registry};
+use mongodb::bson::doc;
+use std::time::Duration;
+
+fn mapping(command: &str, topic: &str, payload: &str) -> mqtt::CommandMapping {
+    return mqtt::CommandMapping{command: command.to_string(), topic: topic.to_string(), payload: payload.to_string()};
+}
+
+// A bridge configured for the broker in HOMECHATBOT_TEST_MQTT_HOST, or
+// localhost. Nothing connects until the bridge is polled.
+async fn bridge(commands: &[&str]) -> mqtt::Bridge {
+    let db = Box::new(db::Homechatbotdb::in_memory().await.expect("database"));
+    let host = std::env::var("HOMECHATBOT_TEST_MQTT_HOST").unwrap_or("localhost".to_string());
+    let commands : Vec<mongodb::bson::Document> = commands.iter()
+        .map(|c| doc!{"command": format!("{} {{state}}", c), "topic": format!("homechatbot-test/{}/set", c), "payload": "{state}"})
+        .collect();
+    db.insert_data_to_collection(db::CONFIG_COLLECTION_NAME, vec![
+        doc!{"mqtt_host": host, "mqtt_client_id": format!("homechatbot-test-{}", std::process::id())},
+        doc!{"mqtt_commands": commands},
+        doc!{"mqtt_subscriptions": [{"topic": "homechatbot-test/+/set", "room": ROOM, "message": "{topic} is {payload}"}]},
+    ]).await.expect("config");
+    return mqtt::connect(db).await.expect("bridge").expect("configured bridge");
+}
+
+#[test]
+fn topic_filters_match_like_a_broker() {
+    assert!(mqtt::topic_matches("home/kitchen/temp", "home/kitchen/temp"));
+    assert!(mqtt::topic_matches("home/+/temp", "home/kitchen/temp"));
+    assert!(!mqtt::topic_matches("home/+/temp", "home/kitchen/fridge/temp"));
+    assert!(mqtt::topic_matches("home/#", "home/kitchen/fridge/temp"));
+    assert!(mqtt::topic_matches("#", "home"));
+    assert!(!mqtt::topic_matches("home/kitchen", "home/kitchen/temp"));
+    assert!(!mqtt::topic_matches("home/kitchen/temp", "home/kitchen"));
+}
+
+#[test]
+fn commands_fill_their_placeholders() {
+    let m = mapping("light {room} {state}", "home/{room}/light/set", "{state}");
+    let values = mqtt::match_mapping(&m, "kitchen ON").expect("match");
+    assert_eq!(values, vec![("room".to_string(), "kitchen".to_string()), ("state".to_string(), "ON".to_string())]);
+    assert_eq!(mqtt::fill(m.topic.as_str(), &values), "home/kitchen/light/set");
+    assert_eq!(mqtt::fill(m.payload.as_str(), &values), "ON");
+    assert_eq!(mqtt::match_mapping(&m, "kitchen"), None);
+    let fixed = mapping("heating boost {minutes}", "home/heating/boost", "{minutes}");
+    assert!(mqtt::match_mapping(&fixed, "BOOST 30").is_some());
+    assert_eq!(mqtt::match_mapping(&fixed, "off 30"), None);
+}
+
+#[tokio::test]
+async fn mqtt_commands_can_not_replace_builtin_ones() {
+    let b = bridge(&["lamp", "admin", "gro"]).await;
+    let mut reg = registry::builtin();
+    b.register_commands(&mut reg);
+    assert_eq!(reg.find("lamp").map(|c| c.label.as_str()), Some("mqtt"));
+    assert_eq!(reg.find("admin").map(|c| c.label.as_str()), Some("admin"));
+    assert_eq!(reg.find("gro").map(|c| c.label.as_str()), Some("gro"));
+}
+
+// Needs a broker, e.g. "mosquitto -p 1883": cargo test -- --ignored
+#[tokio::test]
+#[ignore]
+async fn commands_are_published_and_subscriptions_posted() {
+    let b = bridge(&["lamp"]).await;
+    let mut reg = registry::Registry::new();
+    b.register_commands(&mut reg);
+    let listener = tokio::spawn({
+        let b = b.clone();
+        async move { tokio::time::timeout(Duration::from_secs(10), b.next_notifications()).await }
+    });
+    // Give the bridge time to connect and subscribe
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let lamp = reg.find("lamp").expect("lamp command");
+    let req = registry::Request{
+        args: "on".to_string(), sender: "@alice:localhost".to_string(), room: ROOM.to_string(), is_dm: false,
+        db: Box::new(db::Homechatbotdb::in_memory().await.expect("database")),
+    };
+    match (lamp.handler)(req).await {
+        crate::reply::Reply::Text(msg) => assert_eq!(msg, "Sent \"on\" to homechatbot-test/lamp/set"),
+        _ => panic!("unexpected reply"),
+    };
+    let notes = listener.await.expect("listener").expect("notification in time").expect("notifications");
+    assert_eq!(notes, vec![(ROOM.to_string(), "homechatbot-test/lamp/set is on".to_string())]);
+}
@@ -0,0 +1,76 @@
+use hyper::{Body, HeaderMap, Method, Request, Response, Server, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+// A request made to the stub, with the path exactly as it arrived
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub method: Method,
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: Value,
+}
+
+#[derive(Default)]
+struct State {
+    routes: Vec<(Method, String, StatusCode, Value)>,
+    requests: Vec<Recorded>,
+}
+
+// A JSON HTTP service answering with what the test set up, for the modules
+// talking to Home Assistant, TVs and the like. Anything else gets a 404.
+pub struct StubServer {
+    pub url: String,
+    state: Arc<Mutex<State>>,
+}
+
+async fn handle(req: Request<Body>, state: Arc<Mutex<State>>) -> Result<Response<Body>, Infallible> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let headers = req.headers().clone();
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(b) => serde_json::from_slice::<Value>(&b).unwrap_or(Value::Null),
+        Err(_) => Value::Null,
+    };
+    let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+    state.requests.push(Recorded{method: method.clone(), path: path.clone(), headers: headers, body: body});
+    let (status, body) = state.routes.iter()
+        .find(|(m, p, _, _)| *m == method && *p == path)
+        .map_or((StatusCode::NOT_FOUND, json!({"message": "Not found"})), |(_, _, s, b)| (*s, b.clone()));
+    let resp = Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap_or_else(|_| Response::new(Body::empty()));
+    return Ok(resp);
+}
+
+impl StubServer {
+    pub async fn start() -> StubServer {
+        let state = Arc::new(Mutex::new(State::default()));
+        let make_svc = make_service_fn({
+            let state = state.clone();
+            move |_conn| {
+                let state = state.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| handle(req, state.clone())))
+                }
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        return StubServer{url: url, state: state};
+    }
+
+    pub fn respond(&self, method: Method, path: &str, status: StatusCode, body: Value) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).routes.push((method, path.to_string(), status, body));
+    }
+
+    pub fn requests(&self) -> Vec<Recorded> {
+        return self.state.lock().unwrap_or_else(|e| e.into_inner()).requests.clone();
+    }
+}
@@ -0,0 +1,96 @@
+use super::{Harness, ALICE, BOT, ROOM};
+use super::homeserver::joined_sync;
+use super::stub::StubServer;
+use crate::bgchan::TvBackend;
+use crate::bgchan::http::HttpBackend;
+use crate::bgchan::lineup;
+use crate::error::Error;
+use crate::{db, migrate};
+use hyper::{Method, StatusCode};
+use mongodb::bson::doc;
+use serde_json::{json, Value};
+use std::net::TcpListener;
+
+#[tokio::test]
+async fn http_backend_posts_the_documented_requests() {
+    let tv = StubServer::start().await;
+    for path in ["/power", "/channel", "/channel/up", "/volume", "/volume/down", "/input"] {
+        tv.respond(Method::POST, path, StatusCode::OK, json!({}));
+    }
+    let backend = HttpBackend::new(format!("{}/", tv.url).as_str(), Some("tv_secret".to_string())).expect("backend");
+    backend.power(true).await.expect("power");
+    backend.set_channel(5).await.expect("channel");
+    backend.channel_up().await.expect("channel up");
+    backend.set_volume(20).await.expect("volume");
+    backend.volume_down().await.expect("volume down");
+    backend.set_input("hdmi1").await.expect("input");
+    let reqs = tv.requests();
+    let sent : Vec<(&str, &Value)> = reqs.iter().map(|r| (r.path.as_str(), &r.body)).collect();
+    assert_eq!(sent, vec![
+        ("/power", &json!({"on": true})),
+        ("/channel", &json!({"number": 5})),
+        ("/channel/up", &Value::Null),
+        ("/volume", &json!({"level": 20})),
+        ("/volume/down", &Value::Null),
+        ("/input", &json!({"source": "hdmi1"})),
+    ]);
+    assert!(reqs.iter().all(|r| r.method == Method::POST && r.headers.get("authorization").and_then(|v| v.to_str().ok()) == Some("Bearer tv_secret")));
+}
+
+#[tokio::test]
+async fn http_backend_errors_are_classified() {
+    let tv = StubServer::start().await;
+    tv.respond(Method::POST, "/power", StatusCode::UNAUTHORIZED, json!({}));
+    tv.respond(Method::POST, "/channel", StatusCode::BAD_REQUEST, json!({}));
+    tv.respond(Method::POST, "/volume", StatusCode::INTERNAL_SERVER_ERROR, json!({}));
+    let backend = HttpBackend::new(tv.url.as_str(), None).expect("backend");
+    assert!(matches!(backend.power(false).await, Err(Error::PermissionDenied(_))));
+    match backend.set_channel(999).await {
+        Err(Error::InvalidInput(m)) => assert_eq!(m, "The TV rejected /channel (HTTP 400)"),
+        r => panic!("unexpected result: {:?}", r),
+    };
+    assert!(matches!(backend.set_volume(10).await, Err(Error::Internal(_))));
+    assert!(tv.requests().iter().all(|r| r.headers.get("authorization").is_none()));
+    // Nothing listens on a port which was just given back
+    let port = TcpListener::bind("127.0.0.1:0").and_then(|l| l.local_addr()).expect("free port").port();
+    let gone = HttpBackend::new(format!("http://127.0.0.1:{}", port).as_str(), None).expect("backend");
+    assert!(matches!(gone.power(true).await, Err(Error::ConnectionLost(_))));
+}
+
+#[tokio::test]
+async fn lineup_set_up_before_the_tv_moves_to_it() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOT])).await;
+    let tv = StubServer::start().await;
+    tv.respond(Method::POST, "/channel", StatusCode::OK, json!({}));
+    let added = h.say(ALICE, "tv channel add 1 BNT1 aka bnt").await;
+    assert!(added[0].starts_with("Channel 1 added"), "unexpected reply: {}", added[0]);
+    assert_eq!(h.say(ALICE, format!("tv device add living {}", tv.url).as_str()).await, vec!["TV \"living\" saved"]);
+    h.say(ALICE, "tv bnt").await;
+    let reqs = tv.requests();
+    assert_eq!((reqs.len(), &reqs[0].body), (1, &json!({"number": 1})));
+    assert_eq!(lineup::get_lineup("default", h.db.clone()).await.expect("lineup").len(), 0);
+}
+
+#[tokio::test]
+async fn upgrade_moves_the_unnamed_lineup_to_the_first_named_tv() {
+    let db = Box::new(db::Homechatbotdb::in_memory().await.expect("database"));
+    migrate::run(db.clone(), false).await.expect("migrations");
+    // As left behind by a version with migration 1 only
+    db.update_data(db::CONFIG_COLLECTION_NAME, doc!{"schema_version": {"$exists": true}}, doc!{"schema_version": 1}).await.expect("schema version");
+    db.insert_data_to_collection(db::CONFIG_COLLECTION_NAME, vec![
+        doc!{"tv_device": "living", "tv_url": "http://127.0.0.1:1/"},
+        doc!{"tv_device": "kids", "tv_url": "http://127.0.0.1:2/"},
+    ]).await.expect("devices");
+    db.insert_data_to_collection(lineup::CHANNEL_COLLECTION_NAME, vec![
+        doc!{"chanid": "default/1", "tv": "default", "number": 1, "name": "BNT 1", "aliases": [], "language": "bg", "favourite": true},
+        doc!{"chanid": "default/2", "tv": "default", "number": 2, "name": "Nova", "aliases": [], "language": "bg", "favourite": false},
+    ]).await.expect("channels");
+    let done = migrate::run(db.clone(), false).await.expect("migrations");
+    assert!(done.iter().any(|s| s.starts_with("Migration 2:")), "unexpected steps: {:?}", done);
+    assert_eq!(lineup::get_lineup("default", db.clone()).await.expect("lineup").len(), 0);
+    assert_eq!(lineup::get_lineup("living", db.clone()).await.expect("lineup").len(), 0);
+    let kids = lineup::get_lineup("kids", db.clone()).await.expect("lineup");
+    let moved : Vec<(&str, bool)> = kids.iter().map(|c| (c.chanid.as_str(), c.favourite)).collect();
+    assert_eq!(moved, vec![("kids/1", true), ("kids/2", false)]);
+}
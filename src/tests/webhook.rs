@@ -0,0 +1,51 @@
+use super::{Harness, ALICE, BOB, BOT, ROOM};
+use super::homeserver::joined_sync;
+use crate::webhook;
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
+
+// Adds a webhook posting to ROOM from a direct chat and returns its token
+async fn add_webhook(h: &Harness) -> String {
+    h.sync(joined_sync(ROOM, &[ALICE, BOT])).await;
+    let added = h.say(ALICE, format!("admin webhook add door {}", ROOM).as_str()).await;
+    assert!(added[0].starts_with("Webhook \"door\" posts to"), "unexpected reply: {}", added[0]);
+    return added[0].lines().last().expect("token").to_string();
+}
+
+// Starts the listener on a free port and waits until it accepts connections
+async fn start_listener(h: &Harness) -> String {
+    let addr : SocketAddr = TcpListener::bind("127.0.0.1:0").and_then(|l| l.local_addr()).expect("free port");
+    tokio::spawn(webhook::serve(addr, h.client.clone(), h.db.clone()));
+    for _ in 0..50 {
+        if tokio::net::TcpStream::connect(addr).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    return format!("http://{}/webhook", addr);
+}
+
+#[tokio::test]
+async fn webhook_tokens_are_only_shown_in_direct_chats() {
+    let h = Harness::new().await;
+    h.sync(joined_sync(ROOM, &[ALICE, BOB, BOT])).await;
+    let refused = h.say(ALICE, "admin webhook add door").await;
+    assert!(refused[0].starts_with("The token of a new webhook is only shown in a direct chat"), "unexpected reply: {}", refused[0]);
+    assert_eq!(h.say(ALICE, "admin webhook list").await, vec!["No webhooks"]);
+}
+
+#[tokio::test]
+async fn webhook_posts_are_checked_before_the_body_is_read() {
+    let h = Harness::new().await;
+    let token = add_webhook(&h).await;
+    let url = start_listener(&h).await;
+    let http = reqwest::Client::new();
+    let post = |token: &str, body: String| http.post(url.as_str()).header("X-Webhook-Token", token).body(body).send();
+    let resp = post("wrong", "{\"text\": \"Opened\"}".to_string()).await.expect("unknown token");
+    assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+    let resp = post(token.as_str(), format!("{{\"text\": \"{}\"}}", "x".repeat(100 * 1024))).await.expect("big body");
+    assert_eq!(resp.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+    let resp = post(token.as_str(), "{\"text\": \"Opened\"}".to_string()).await.expect("post");
+    assert_eq!(resp.status(), reqwest::StatusCode::ACCEPTED);
+    assert_eq!(h.server.sent_messages(ROOM).last().map(|m| m.as_str()), Some("[door] Opened"));
+}
@@ -0,0 +1,230 @@
+use crate::db;
+use crate::error::{self, Error};
+use crate::metrics;
+use crate::reply;
+use serde::{Deserialize, Serialize};
+use mongodb::bson::{doc, to_document};
+use matrix_sdk::Client;
+use matrix_sdk_common::uuid::Uuid;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use tracing::{error, info, warn};
+
+pub mod outbound;
+
+// Local services send small status updates, anything bigger is a mistake
+const MAX_BODY_BYTES : usize = 64 * 1024;
+const TOKEN_HEADER : &str = "X-Webhook-Token";
+
+// One inbound webhook, a {webhook_name, webhook_token, webhook_room}
+// document in the config collection
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Webhook {
+    webhook_name: String,
+    webhook_token: String,
+    webhook_room: String,
+}
+
+// What a service posts. Only text is required; any other field is listed
+// below it.
+#[derive(Debug, Deserialize)]
+struct Payload {
+    token: Option<String>,
+    title: Option<String>,
+    text: String,
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+async fn get_webhooks(db: Box<db::Homechatbotdb>) -> Result<Vec<Webhook>, Error> {
+    return db.get_generic_data_collection::<Webhook>(db::CONFIG_COLLECTION_NAME, doc!{"webhook_name": {"$exists": true}}, doc!{"webhook_name": 1}).await;
+}
+
+// Compares without stopping at the first difference, so the time taken
+// does not tell how much of a token was right
+fn same_token(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    return a.bytes().zip(b.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0;
+}
+
+fn new_token() -> String {
+    return format!("{}{}", Uuid::new_v4().to_simple(), Uuid::new_v4().to_simple());
+}
+
+fn format_message(name: &str, payload: &Payload) -> String {
+    let mut msg = match &payload.title {
+        Some(t) => format!("[{}] {}\n{}", name, t, payload.text),
+        None => format!("[{}] {}", name, payload.text),
+    };
+    for (k, v) in payload.extra.iter() {
+        let v = match v {
+            serde_json::Value::String(s) => s.clone(),
+            v => v.to_string(),
+        };
+        msg = format!("{}\n{}: {}", msg, k, v);
+    }
+    return msg;
+}
+
+fn respond(status: StatusCode, body: &str) -> Response<Body> {
+    return Response::builder()
+        .status(status)
+        .body(Body::from(format!("{}\n", body)))
+        .unwrap_or_else(|_| Response::new(Body::from("Internal error\n")));
+}
+
+// Reads at most limit bytes, stopping as soon as the body turns out to be
+// bigger. None if it is.
+async fn read_body(mut body: Body, limit: usize) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let mut data : Vec<u8> = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if data.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    return Ok(Some(data));
+}
+
+fn find_hook<'a>(hooks: &'a [Webhook], token: &str) -> Option<&'a Webhook> {
+    let hook = hooks.iter().find(|h| same_token(h.webhook_token.as_str(), token));
+    if hook.is_none() {
+        warn!("Webhook called with an unknown token");
+    }
+    return hook;
+}
+
+// Anyone who can reach the listener can call it, so nothing is buffered
+// before it is known to be small, and a token in the headers is checked
+// before the body is read at all
+async fn handle_post(req: Request<Body>, client: Client, db: Box<db::Homechatbotdb>) -> Response<Body> {
+    let header_token = req.headers().get(TOKEN_HEADER)
+        .or(req.headers().get(hyper::header::AUTHORIZATION))
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.trim_start_matches("Bearer ").trim().to_string());
+    let length = req.headers().get(hyper::header::CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<usize>().ok());
+    if length.map_or(false, |l| l > MAX_BODY_BYTES) {
+        return respond(StatusCode::PAYLOAD_TOO_LARGE, "Payload too large");
+    }
+    let hooks = match get_webhooks(db).await {
+        Ok(h) => h,
+        Err(e) => {
+            error!("Unable to read webhooks: {}", e);
+            return respond(StatusCode::SERVICE_UNAVAILABLE, "Try again later");
+        },
+    };
+    let header_hook = match &header_token {
+        Some(t) => match find_hook(&hooks, t.as_str()) {
+            Some(h) => Some(h),
+            None => return respond(StatusCode::UNAUTHORIZED, "Unknown token"),
+        },
+        None => None,
+    };
+    let body = match read_body(req.into_body(), MAX_BODY_BYTES).await {
+        Ok(Some(b)) => b,
+        Ok(None) => return respond(StatusCode::PAYLOAD_TOO_LARGE, "Payload too large"),
+        Err(e) => return respond(StatusCode::BAD_REQUEST, format!("Unable to read the body: {}", e).as_str()),
+    };
+    let payload = match serde_json::from_slice::<Payload>(&body) {
+        Ok(p) => p,
+        Err(e) => return respond(StatusCode::BAD_REQUEST, format!("Invalid payload: {}", e).as_str()),
+    };
+    let hook = match (header_hook, &payload.token) {
+        (Some(h), _) => h,
+        (None, Some(t)) => match find_hook(&hooks, t.as_str()) {
+            Some(h) => h,
+            None => return respond(StatusCode::UNAUTHORIZED, "Unknown token"),
+        },
+        (None, None) => return respond(StatusCode::UNAUTHORIZED, "Missing token"),
+    };
+    info!(webhook = hook.webhook_name.as_str(), "Webhook called");
+    metrics::record_command("webhook");
+    reply::send_notification(&client, hook.webhook_room.as_str(), format_message(hook.webhook_name.as_str(), &payload).as_str()).await;
+    return respond(StatusCode::ACCEPTED, "Accepted");
+}
+
+async fn route(req: Request<Body>, client: Client, db: Box<db::Homechatbotdb>) -> Result<Response<Body>, Infallible> {
+    let resp = match (req.method(), req.uri().path()) {
+        (&Method::POST, "/webhook") => handle_post(req, client, db).await,
+        (_, "/webhook") => respond(StatusCode::METHOD_NOT_ALLOWED, "Only POST is allowed"),
+        _ => respond(StatusCode::NOT_FOUND, "Not found"),
+    };
+    return Ok(resp);
+}
+
+pub async fn serve(address: SocketAddr, client: Client, db: Box<db::Homechatbotdb>) {
+    let make_svc = make_service_fn(move |_conn| {
+        let client = client.clone();
+        let db = db.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| route(req, client.clone(), db.clone())))
+        }
+    });
+    let server = match Server::try_bind(&address) {
+        Ok(s) => s.serve(make_svc),
+        Err(e) => {
+            error!(address = %address, "Unable to start the webhook listener: {}", e);
+            return;
+        },
+    };
+    info!(address = %address, "Webhook listener is running");
+    if let Err(e) = server.await {
+        error!("Webhook listener stopped: {}", e);
+    }
+}
+
+// Admin commands. The token is only shown once, when the webhook is added,
+// and only in a direct chat so nobody else in the room gets to see it.
+pub async fn handle_webhook_command(cmd: &str, room: &str, is_dm: bool, db: Box<db::Homechatbotdb>) -> String {
+    let parts : Vec<&str> = cmd.split_whitespace().collect();
+    let action = parts.get(0).map_or("".to_string(), |a| a.to_lowercase());
+    if action == "list" || action == "" {
+        let hooks = match get_webhooks(db).await {
+            Ok(h) => h,
+            Err(e) => return error::report(&e),
+        };
+        if hooks.len() == 0 {
+            return String::from("No webhooks");
+        }
+        let mut msg = "".to_string();
+        for h in hooks {
+            msg = format!("{}{} -> {}\n", msg, h.webhook_name, h.webhook_room);
+        }
+        return msg;
+    } else if action == "add" && (parts.len() == 2 || parts.len() == 3) {
+        if !is_dm {
+            return String::from("The token of a new webhook is only shown in a direct chat. Send \"admin webhook add {name} {room_id}\" there.");
+        }
+        let hook = Webhook{
+            webhook_name: parts[1].to_lowercase(),
+            webhook_token: new_token(),
+            webhook_room: parts.get(2).map_or(room.to_string(), |r| r.to_string()),
+        };
+        let hdoc = match to_document(&hook) {
+            Ok(d) => d,
+            Err(e) => return format!("Unable to serialize webhook: {}", e),
+        };
+        match db.remove_data(db::CONFIG_COLLECTION_NAME, doc!{"webhook_name": hook.webhook_name.as_str()}).await {
+            Ok(_) => {},
+            Err(e) => return error::report(&e),
+        };
+        match db.insert_data_to_collection(db::CONFIG_COLLECTION_NAME, vec![hdoc]).await {
+            Ok(_) => return format!("Webhook \"{}\" posts to {}, its token is:\n{}", hook.webhook_name, hook.webhook_room, hook.webhook_token),
+            Err(e) => return error::report(&e),
+        };
+    } else if action == "rem" && parts.len() == 2 {
+        match db.remove_data(db::CONFIG_COLLECTION_NAME, doc!{"webhook_name": parts[1].to_lowercase()}).await {
+            Ok(_) => return format!("Webhook \"{}\" removed", parts[1].to_lowercase()),
+            Err(e) => return error::report(&e),
+        };
+    }
+    return String::from("Usage: webhook list|add {name} [{room_id}]|rem {name}");
+}
@@ -0,0 +1,149 @@
+use crate::db;
+use crate::error::Error;
+use crate::events::{self, Event};
+use crate::metrics;
+use serde::{Deserialize, Serialize};
+use mongodb::bson::doc;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error, warn};
+
+const HTTP_TIMEOUT_SECS : u64 = 10;
+const MAX_ATTEMPTS : u32 = 5;
+const FIRST_RETRY_SECS : u64 = 2;
+const EVENT_HEADER : &str = "X-Homechatbot-Event";
+const SIGNATURE_HEADER : &str = "X-Homechatbot-Signature";
+
+// {outbound_webhooks: [...]} in the config collection. events are event
+// names, a trailing "*" matches a prefix ("grocery.*"); none means all.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Endpoint {
+    url: String,
+    secret: Option<String>,
+    events: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Endpoints {
+    outbound_webhooks: Vec<Endpoint>,
+}
+
+impl Endpoint {
+    fn wants(&self, event: &str) -> bool {
+        let events = match &self.events {
+            Some(e) if e.len() > 0 => e,
+            _ => return true,
+        };
+        return events.iter().any(|e| match e.strip_suffix('*') {
+            Some(prefix) => event.starts_with(prefix),
+            None => e == event,
+        });
+    }
+}
+
+// Hex encoded HMAC-SHA256 of the body, sent as "sha256={hex}"
+pub fn sign(secret: &str, body: &[u8]) -> Result<String, Error> {
+    let mut mac = match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
+        Ok(m) => m,
+        Err(e) => return Err(Error::InvalidInput(format!("Invalid webhook secret: {}", e))),
+    };
+    mac.update(body);
+    return Ok(format!("sha256={}", hex::encode(mac.finalize().into_bytes())));
+}
+
+async fn get_endpoints(db: Box<db::Homechatbotdb>) -> Result<Vec<Endpoint>, Error> {
+    let items = db.get_generic_data_collection::<Endpoints>(db::CONFIG_COLLECTION_NAME, doc!{"outbound_webhooks": {"$exists": true}}, doc!{}).await?;
+    return Ok(items.into_iter().flat_map(|i| i.outbound_webhooks).collect());
+}
+
+// Posts one event, retrying with a doubling delay on network errors and
+// server errors. Client errors are not retried, the receiver said no.
+async fn deliver(client: reqwest::Client, endpoint: Endpoint, event: Event) {
+    let body = match serde_json::to_vec(&event) {
+        Ok(b) => b,
+        Err(e) => {
+            error!("Unable to serialize event: {}", e);
+            return;
+        },
+    };
+    let signature = match &endpoint.secret {
+        Some(s) => match sign(s.as_str(), &body) {
+            Ok(sig) => Some(sig),
+            Err(e) => {
+                error!(url = endpoint.url.as_str(), "{}", e);
+                return;
+            },
+        },
+        None => None,
+    };
+    let mut delay = Duration::from_secs(FIRST_RETRY_SECS);
+    for attempt in 1..=MAX_ATTEMPTS {
+        let mut req = client.post(endpoint.url.as_str())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event.event.as_str())
+            .body(body.clone());
+        if let Some(sig) = &signature {
+            req = req.header(SIGNATURE_HEADER, sig.as_str());
+        }
+        let retry = match req.send().await {
+            Ok(r) if r.status().is_success() => {
+                debug!(url = endpoint.url.as_str(), event = event.event.as_str(), "Event delivered");
+                return;
+            },
+            Ok(r) if r.status().is_client_error() => {
+                warn!(url = endpoint.url.as_str(), event = event.event.as_str(), status = r.status().as_u16(), "Event refused");
+                false
+            },
+            Ok(r) => {
+                warn!(url = endpoint.url.as_str(), attempt = attempt, status = r.status().as_u16(), "Event delivery failed");
+                true
+            },
+            Err(e) => {
+                warn!(url = endpoint.url.as_str(), attempt = attempt, "Event delivery failed: {}", e);
+                true
+            },
+        };
+        if !retry || attempt == MAX_ATTEMPTS {
+            break;
+        }
+        tokio::time::sleep(delay).await;
+        delay = delay * 2;
+    }
+    metrics::record_error("outbound_webhook");
+    error!(url = endpoint.url.as_str(), event = event.event.as_str(), "Giving up on event delivery");
+}
+
+// Forwards events from the bus to the configured endpoints. Each delivery
+// runs on its own, so a dead endpoint does not hold up the others.
+pub async fn run(db: Box<db::Homechatbotdb>) {
+    let client = match reqwest::Client::builder().timeout(Duration::from_secs(HTTP_TIMEOUT_SECS)).build() {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Unable to create HTTP client: {}", e);
+            return;
+        },
+    };
+    let mut rx = events::subscribe();
+    loop {
+        let event = match rx.recv().await {
+            Ok(e) => e,
+            Err(RecvError::Lagged(n)) => {
+                warn!(skipped = n, "Outbound webhooks fell behind, events were skipped");
+                continue;
+            },
+            Err(RecvError::Closed) => return,
+        };
+        let endpoints = match get_endpoints(db.clone()).await {
+            Ok(e) => e,
+            Err(e) => {
+                error!("Unable to read outbound webhooks: {}", e);
+                continue;
+            },
+        };
+        for ep in endpoints.into_iter().filter(|ep| ep.wants(event.event.as_str())) {
+            tokio::spawn(deliver(client.clone(), ep, event.clone()));
+        }
+    }
+}